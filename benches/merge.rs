@@ -0,0 +1,152 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rand::{thread_rng, Rng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::Path;
+use tsk1183::data::*;
+use tsk1183::*;
+
+const FILES: usize = 256;
+const RECORDS_PER_FILE: usize = 200;
+
+fn spill_files(dir: &Path) -> Vec<FileStorage> {
+    let mut rng = thread_rng();
+    (0..FILES)
+        .map(|i| {
+            let mut heap = (0..RECORDS_PER_FILE)
+                .map(|seq| {
+                    Reverse(Sequenced {
+                        seq: seq as u64,
+                        record: Record::D(DataD {
+                            timestamp: Timestamp(rng.gen_range(0..1_000_000)),
+                            abc: (),
+                        }),
+                        source: 0,
+                    })
+                })
+                .collect::<BinaryHeap<_>>();
+            FileStorage::new(
+                &mut heap,
+                dir.join(format!("dump-{i}")),
+                IntEncoding::default(),
+                SerErrorPolicy::default(),
+            )
+            .unwrap()
+            .expect("heap isn't empty")
+        })
+        .collect()
+}
+
+fn read_spill_files(dir: &Path) -> Vec<FileStorageReader> {
+    spill_files(dir)
+        .into_iter()
+        .map(|storage| storage.read(1_024).unwrap())
+        .collect()
+}
+
+fn merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("merge {FILES} files"));
+    group.sample_size(20);
+    for strategy in [MergeStrategy::Scan, MergeStrategy::LoserTree] {
+        group.bench_function(format!("{strategy:?}"), |b| {
+            b.iter_batched(
+                || {
+                    // spill files are never overwritten, so each batch gets its own dir
+                    let dir = tempfile::tempdir().unwrap();
+                    let readers = read_spill_files(dir.path());
+                    (dir, readers)
+                },
+                |(_dir, readers)| {
+                    for record in KMerge::new(readers).with_strategy(strategy) {
+                        black_box(record.unwrap());
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Both readers of the spill files, merged the same way, so that only reading differs
+#[cfg(feature = "mmap")]
+trait SpillReader {
+    fn front(&mut self) -> Option<Timestamp>;
+    fn advance(&mut self);
+}
+
+#[cfg(feature = "mmap")]
+impl SpillReader for FileStorageReader {
+    fn front(&mut self) -> Option<Timestamp> {
+        self.last().unwrap().map(|x| x.record.timestamp())
+    }
+
+    fn advance(&mut self) {
+        self.read_next().unwrap();
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl SpillReader for MmapReader {
+    fn front(&mut self) -> Option<Timestamp> {
+        self.last().map(|x| x.record.timestamp())
+    }
+
+    fn advance(&mut self) {
+        self.read_next().unwrap();
+    }
+}
+
+#[cfg(feature = "mmap")]
+fn merge_readers(mut readers: Vec<impl SpillReader>) {
+    let mut heap = readers
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(i, reader)| Some(Reverse((reader.front()?, i))))
+        .collect::<BinaryHeap<_>>();
+    while let Some(Reverse((ts, i))) = heap.pop() {
+        black_box(ts);
+        readers[i].advance();
+        if let Some(ts) = readers[i].front() {
+            heap.push(Reverse((ts, i)));
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+fn merge_mmap(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("read {FILES} files while merging"));
+    group.sample_size(20);
+    group.bench_function("BufReader", |b| {
+        b.iter_batched(
+            || {
+                let dir = tempfile::tempdir().unwrap();
+                let readers = read_spill_files(dir.path());
+                (dir, readers)
+            },
+            |(_dir, readers)| merge_readers(readers),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("mmap", |b| {
+        b.iter_batched(
+            || {
+                let dir = tempfile::tempdir().unwrap();
+                let readers = spill_files(dir.path())
+                    .into_iter()
+                    .map(|storage| storage.read_mmap().unwrap())
+                    .collect::<Vec<_>>();
+                (dir, readers)
+            },
+            |(_dir, readers)| merge_readers(readers),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+#[cfg(feature = "mmap")]
+criterion_group!(benches, merge, merge_mmap);
+#[cfg(not(feature = "mmap"))]
+criterion_group!(benches, merge);
+criterion_main!(benches);
@@ -4,13 +4,14 @@ use std::ops::Range;
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 use tempfile::tempdir;
+use tsk1183::clock::{Clock, SystemClock};
 use tsk1183::{data::*, *};
 
 fn main() {
     std::thread::scope(|scope| {
         let dir = tempdir().unwrap();
         let output_path = dir.path().join("output");
-        let writer = output::Writer::open(&output_path).unwrap();
+        let mut writer = output::Writer::open(&output_path).unwrap();
         let mut reader = output::Reader::open(&output_path).unwrap();
 
         let channels = (
@@ -23,18 +24,17 @@ fn main() {
         let notify_new_records = mpsc::channel();
 
         scope.spawn(move || {
-            produce_loop((
+            produce_loop(Producer::new((
                 channels.0 .0,
                 channels.1 .0,
                 channels.2 .0,
                 channels.3 .0,
                 channels.4 .0,
-            ));
+            )));
         });
 
-        scope.spawn(move || {
-            // moving
-            let mut writer = writer;
+        // the temporary directory outlives both of the loops
+        run_to_completion(
             UnsortedDataSinkLoop {
                 receivers: (
                     channels.0 .1,
@@ -46,49 +46,50 @@ fn main() {
                 writer: &mut writer,
                 notify_new_records: notify_new_records.0,
                 buffer_dir: dir.path(),
-                buffer_config: BufferConfig {
-                    max_in_memory: 1000,
-                    file_read_buf_capacity: 8_192,
-                },
-            }
-            .run()
-        });
-
-        SortedOutputListenLoop {
-            reader: &mut reader,
-            notify_new_records: notify_new_records.1,
-        }
-        .run();
+                buffer_config: BufferConfig::builder()
+                    .max_in_memory(1000)
+                    .max_buffered_records(100_000)
+                    .int_encoding(IntEncoding::Varint)
+                    .flush_interval(Duration::from_millis(100))
+                    .build()
+                    .expect("config is valid"),
+                control: None,
+                watermark: None,
+                normalizers: None,
+                lag_metrics: None,
+                tagged_writer: None,
+            },
+            SortedOutputListenLoop {
+                reader: &mut reader,
+                notify_new_records: notify_new_records.1,
+                last_verified: None,
+            },
+        )
+        .expect("the sink should not fail");
     })
 }
 
-fn produce_loop(
-    senders: (
-        mpsc::Sender<DataA>,
-        mpsc::Sender<DataB>,
-        mpsc::Sender<DataC>,
-        mpsc::Sender<DataD>,
-        mpsc::Sender<DataE>,
-    ),
-) {
+fn produce_loop(producer: Producer) {
     const TIME_ERROR: Duration = Duration::from_secs(10);
     const TICK: Duration = Duration::from_millis(50);
     const DATA_PER_TICK: Range<u128> = 5..20;
 
-    struct TimestampGen {
+    struct TimestampGen<C> {
+        clock: C,
         start: Instant,
     }
-    impl TimestampGen {
+    impl<C: Clock> TimestampGen<C> {
         fn timestamp(&self) -> Timestamp {
             let x = random::<f32>();
             let error =
                 Duration::from_millis(((TIME_ERROR.as_millis() as f32) * (1.0 - x * 2.0)) as u64);
-            Timestamp((self.start.elapsed() + error).as_millis())
+            Timestamp::from_duration(self.clock.now() - self.start + error)
         }
     }
 
     let timestamp_gen = TimestampGen {
-        start: Instant::now(),
+        clock: SystemClock,
+        start: SystemClock.now(),
     };
 
     loop {
@@ -98,38 +99,31 @@ fn produce_loop(
 
         for _ in 0..emit_count {
             let timestamp = timestamp_gen.timestamp();
-            if match (0..5).choose(&mut thread_rng()).expect("there is choice") {
-                0 => senders
-                    .0
-                    .send(DataA {
-                        timestamp,
-                        foo: "foo".to_string(),
-                    })
-                    .is_err(),
-                1 => senders
-                    .1
-                    .send(DataB {
-                        timestamp,
-                        bar: false,
-                    })
-                    .is_err(),
-                2 => senders
-                    .2
-                    .send(DataC {
-                        timestamp,
-                        baz: (0, 512),
-                    })
-                    .is_err(),
-                3 => senders.3.send(DataD { timestamp, abc: () }).is_err(),
-                4 => senders
-                    .4
-                    .send(DataE {
-                        timestamp,
-                        def: vec![5, 1, 2],
-                    })
-                    .is_err(),
+            let record: Record = match (0..5).choose(&mut thread_rng()).expect("there is choice") {
+                0 => DataA {
+                    timestamp,
+                    foo: "foo".into(),
+                }
+                .into(),
+                1 => DataB {
+                    timestamp,
+                    bar: false,
+                }
+                .into(),
+                2 => DataC {
+                    timestamp,
+                    baz: (0, 512),
+                }
+                .into(),
+                3 => DataD { timestamp, abc: () }.into(),
+                4 => DataE {
+                    timestamp,
+                    def: vec![5, 1, 2],
+                }
+                .into(),
                 _ => unreachable!(),
-            } {
+            };
+            if producer.send(record).is_err() {
                 println!("some sender is dropped");
                 break;
             };
@@ -1,6 +1,81 @@
+use crate::clock::{Clock, SystemClock};
+use crate::codec::{self, Format, IntEncoding, SerErrorPolicy, SerializationConfig};
 use crate::data::*;
 use crate::output;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::num::NonZero;
+use std::ops::{Bound, RangeBounds, RangeInclusive};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+pub use merge::KMerge;
+use on_disk::Backing;
+#[cfg(feature = "mmap")]
+pub use on_disk::MmapReader;
+pub use on_disk::{FileStorage, FileStorageReader};
+
+/// A buffered record, tagged with a sequence number when it is pushed into the buffer.
+///
+/// Ordered by `(timestamp, seq)`, so that records with equal timestamps keep the order they were
+/// pushed in, through any number of spills and merges. The sequence number is stored in the
+/// on-disk buffers, but not in the output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sequenced {
+    pub seq: u64,
+    pub record: Record,
+    /// Carried along into the output of [`Buffer::new_tagged`], zero for the records pushed
+    /// untagged, see [`Buffer::push_tagged`]
+    pub source: SourceId,
+}
+
+impl Sequenced {
+    pub fn timestamp(&self) -> Timestamp {
+        self.record.timestamp()
+    }
+
+    /// What the records are ordered by
+    fn key(&self) -> (Timestamp, u64) {
+        (self.timestamp(), self.seq)
+    }
+}
+
+/// Leading fields of a serialised [`Sequenced`]: the sequence number, the variant of the record
+/// and its timestamp, which is the first field of each of them.
+///
+/// It is enough to order the records without deserialising their data, see
+/// [`FileStorageReader::peek_timestamp`].
+#[derive(Deserialize)]
+struct SequencedKey {
+    seq: u64,
+    _variant: u32,
+    timestamp: Timestamp,
+}
+
+/// Same as [`Sequenced`], serialised the same way, but borrowing the record, see
+/// [`Buffer::push_record_ref`].
+#[derive(Serialize)]
+struct SequencedRef<'a> {
+    seq: u64,
+    record: &'a Record,
+    source: SourceId,
+}
+
+impl PartialOrd for Sequenced {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sequenced {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
 
 /// In-memory part of buffering
 mod in_memory {
@@ -11,11 +86,17 @@ mod in_memory {
 
     /// In-memory heap of records.
     ///
-    /// It is a simple wrapper around [`BinaryHeap`] with domain knowledge. Records are pushed into the
-    /// heap, and then popped in a sorted manner onto the disk with [`Buffer::drain_into_file`].
+    /// It is a simple wrapper around [`BinaryHeap`] with domain knowledge. Records are pushed into
+    /// the heap, and then popped in a sorted manner onto the disk with
+    /// [`Buffer::drain_into_file`].
     #[derive(Debug)]
     pub struct Buffer {
-        heap: BinaryHeap<Reverse<Record>>,
+        heap: BinaryHeap<Reverse<Sequenced>>,
+        /// Maximal number of records, regardless of the actual capacity of the heap, which could
+        /// be larger
+        capacity: usize,
+        /// Sequence number of the next pushed record, see [`Sequenced`]
+        next_seq: u64,
     }
 
     impl Buffer {
@@ -23,6 +104,8 @@ mod in_memory {
         pub fn with_capacity(capacity: usize) -> Self {
             Self {
                 heap: BinaryHeap::with_capacity(capacity),
+                capacity,
+                next_seq: 0,
             }
         }
 
@@ -31,16 +114,82 @@ mod in_memory {
             self.heap.len()
         }
 
+        pub fn is_empty(&self) -> bool {
+            self.heap.is_empty()
+        }
+
+        /// Get the number of records the buffer could hold.
+        pub fn capacity(&self) -> usize {
+            self.capacity
+        }
+
+        /// Iterate over the records, in arbitrary order
+        pub fn iter(&self) -> impl Iterator<Item = &Record> {
+            self.heap.iter().map(|Reverse(x)| &x.record)
+        }
+
         pub fn is_full(&self) -> bool {
-            self.heap.len() == self.heap.capacity()
+            self.len() >= self.capacity()
+        }
+
+        /// Clone the records, in arbitrary order
+        #[cfg(not(feature = "minimal-memory"))]
+        pub fn clone_records(&self) -> Vec<Sequenced> {
+            self.heap.iter().map(|Reverse(x)| x.clone()).collect()
+        }
+
+        /// Push a record, tagging it with the next sequence number.
+        pub fn push(&mut self, record: Record, source: SourceId) {
+            debug_assert!(self.len() < self.capacity());
+            let seq = self.take_seq();
+            self.heap.push(Reverse(Sequenced {
+                seq,
+                record,
+                source,
+            }));
         }
 
-        /// Push a record.
-        pub fn push(&mut self, record: Record) {
-            debug_assert!(self.heap.len() < self.heap.capacity());
+        /// Take the next sequence number, e.g. for a record spilled without being pushed here.
+        pub fn take_seq(&mut self) -> u64 {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            seq
+        }
+
+        /// Push a record tagged already, e.g. taken out of the buffer before.
+        pub fn push_sequenced(&mut self, record: Sequenced) {
+            debug_assert!(self.len() < self.capacity());
             self.heap.push(Reverse(record));
         }
 
+        /// Move the earliest records out into another buffer, keeping the `keep` latest ones.
+        pub fn split_off_earliest(&mut self, keep: usize) -> Self {
+            let count = self.len().saturating_sub(keep);
+            let mut earliest = Self::with_capacity(count);
+            // pushed in order, so none of them is sifted
+            earliest
+                .heap
+                .extend((0..count).map_while(|_| self.heap.pop()));
+            earliest
+        }
+
+        /// Put back the records moved out with [`Buffer::split_off_earliest`], e.g. those not
+        /// spilled because of an error.
+        pub fn merge_back(&mut self, mut other: Self) {
+            self.heap.append(&mut other.heap);
+        }
+
+        /// Take all the records out, e.g. to merge them with [`KMerge::with_heap`].
+        pub fn take_heap(&mut self) -> BinaryHeap<Reverse<Sequenced>> {
+            std::mem::take(&mut self.heap)
+        }
+
+        /// Put back the records taken with [`Buffer::take_heap`].
+        pub fn restore_heap(&mut self, heap: BinaryHeap<Reverse<Sequenced>>) {
+            debug_assert!(self.heap.is_empty());
+            self.heap = heap;
+        }
+
         /// Write all records from memory on the disk in sorted order.
         ///
         /// Returns [`None`] if there are no records.
@@ -49,8 +198,87 @@ mod in_memory {
         pub fn drain_into_file(
             &mut self,
             file: impl AsRef<Path>,
+            format: impl Into<Format>,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Option<FileStorage>> {
+            FileStorage::new(&mut self.heap, file, format, policy)
+        }
+
+        /// Same as [`Buffer::drain_into_file`], but into a file opened already, see
+        /// [`FileStorage::from_backing`].
+        pub fn drain_into_opened_file(
+            &mut self,
+            file: Backing,
+            format: impl Into<Format>,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Option<FileStorage>> {
+            FileStorage::from_backing(&mut self.heap, file, format, policy)
+        }
+
+        /// Same as [`Buffer::drain_into_file`], but reuses the file of an empty storage, see
+        /// [`FileStorage::refill`].
+        pub fn drain_into_storage(
+            &mut self,
+            storage: FileStorage,
+            format: impl Into<Format>,
+            policy: SerErrorPolicy,
         ) -> std::io::Result<Option<FileStorage>> {
-            FileStorage::new(&mut self.heap, file)
+            storage.refill(&mut self.heap, format, policy)
+        }
+    }
+
+    /// Sorted window of the latest records, see [`InMemoryStrategy::BoundedWindow`].
+    #[derive(Debug)]
+    pub struct Window {
+        records: VecDeque<SourceTagged<Record>>,
+        k: usize,
+    }
+
+    impl Window {
+        pub fn new(k: usize) -> Self {
+            Self {
+                records: VecDeque::with_capacity(k + 1),
+                k,
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.records.len()
+        }
+
+        pub fn front_timestamp(&self) -> Option<Timestamp> {
+            self.records.front().map(SourceTagged::timestamp)
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = &SourceTagged<Record>> {
+            self.records.iter()
+        }
+
+        /// Insert a record after the ones with the same or earlier timestamps, returning the
+        /// earliest record if the window overflows.
+        ///
+        /// Records are compared from the back, so a nearly sorted input takes a few comparisons
+        /// per record.
+        pub fn insert(&mut self, record: SourceTagged<Record>) -> Option<SourceTagged<Record>> {
+            let ts = record.timestamp();
+            let index = self
+                .records
+                .iter()
+                .rposition(|x| x.timestamp() <= ts)
+                .map_or(0, |x| x + 1);
+            self.records.insert(index, record);
+            (self.records.len() > self.k)
+                .then(|| self.records.pop_front())
+                .flatten()
+        }
+
+        /// Take the earliest record, if it's within the bound
+        pub fn pop_within(&mut self, bound: Bound<Timestamp>) -> Option<SourceTagged<Record>> {
+            let ts = self.front_timestamp()?;
+            (Bound::Unbounded, bound)
+                .contains(&ts)
+                .then(|| self.records.pop_front())
+                .flatten()
         }
     }
 }
@@ -61,22 +289,133 @@ mod on_disk {
     use std::cmp::Reverse;
     use std::collections::BinaryHeap;
     use std::fs::{File, OpenOptions};
-    use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
-    use std::num::NonZero;
+    #[cfg(test)]
+    use std::io::Cursor;
+    use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+    /// What a [`FileStorage`] keeps the records in: a [`File`], or in tests a
+    /// [`std::io::Cursor`] over bytes in memory, to merge and spill without touching the disk.
+    #[derive(Debug)]
+    pub(crate) enum Backing {
+        File(File),
+        #[cfg(test)]
+        Memory(Cursor<Vec<u8>>),
+    }
+
+    impl Backing {
+        /// Empty bytes in memory
+        #[cfg(test)]
+        pub(crate) fn memory() -> Self {
+            Self::Memory(Cursor::new(Vec::new()))
+        }
+
+        /// Total length in bytes, regardless of the current position
+        fn byte_len(&self) -> std::io::Result<u64> {
+            match self {
+                Self::File(file) => Ok(file.metadata()?.len()),
+                #[cfg(test)]
+                Self::Memory(cursor) => Ok(cursor.get_ref().len() as u64),
+            }
+        }
+
+        /// Remove all the bytes, moving back to the start
+        fn clear(&mut self) -> std::io::Result<()> {
+            match self {
+                Self::File(file) => file.set_len(0)?,
+                #[cfg(test)]
+                Self::Memory(cursor) => cursor.get_mut().clear(),
+            }
+            self.rewind()
+        }
+
+        /// Read from the current position through a shared reference, leaving the position as
+        /// is.
+        fn peek<T>(
+            &self,
+            read: impl FnOnce(&mut dyn Read) -> std::io::Result<T>,
+        ) -> std::io::Result<T> {
+            match self {
+                Self::File(file) => {
+                    let mut file: &File = file;
+                    let position = file.stream_position()?;
+                    let result = read(&mut file);
+                    file.seek(SeekFrom::Start(position))?;
+                    result
+                }
+                #[cfg(test)]
+                Self::Memory(cursor) => {
+                    let position = (cursor.position() as usize).min(cursor.get_ref().len());
+                    read(&mut &cursor.get_ref()[position..])
+                }
+            }
+        }
+    }
+
+    impl Read for Backing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self {
+                Self::File(file) => file.read(buf),
+                #[cfg(test)]
+                Self::Memory(cursor) => cursor.read(buf),
+            }
+        }
+    }
+
+    impl Write for Backing {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match self {
+                Self::File(file) => file.write(buf),
+                #[cfg(test)]
+                Self::Memory(cursor) => cursor.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            match self {
+                Self::File(file) => file.flush(),
+                #[cfg(test)]
+                Self::Memory(cursor) => cursor.flush(),
+            }
+        }
+    }
+
+    impl Seek for Backing {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            match self {
+                Self::File(file) => file.seek(pos),
+                #[cfg(test)]
+                Self::Memory(cursor) => cursor.seek(pos),
+            }
+        }
+    }
 
     /// On-disk storage of records.
     ///
-    /// Stored records are sorted (by timestamp). To implement merge-sort using multiple [`FileStorage`]
-    /// buffers, [`FileStorage::earliest`] and [`FileStorage::drop_one`] could be used.
+    /// Stored records are sorted (by timestamp). To implement merge-sort using multiple
+    /// [`FileStorage`] buffers, each of them is read with [`FileStorage::read`], and the readers
+    /// are merged with [`KMerge`] (or by hand, with [`FileStorageReader::last`] and
+    /// [`FileStorageReader::read_next`]).
     ///
-    /// It reads data in predefined small chunks, allowing to have a multitude of [`FileStorage`] buffers and
-    /// to implement merge-sort efficiently in terms of RAM.
+    /// It reads data in predefined small chunks, allowing to have a multitude of [`FileStorage`]
+    /// buffers and to implement merge-sort efficiently in terms of RAM.
     #[derive(Debug)]
     pub struct FileStorage {
-        file: Option<File>,
+        /// [`None`] if the file was handed over, see [`FileStorage::from_file`]
+        path: Option<PathBuf>,
+        file: Option<Backing>,
         // buffer: BufReader<File>,
         // last: Record,
         remaining: usize,
+        /// Number of records the storage was created with, see [`FileStorage::records_consumed`]
+        total: usize,
+        format: Format,
+        /// Number of bytes written into the file, see [`FileStorage::bytes_written`]
+        written: u64,
+        /// Timestamp of the first remaining record, if known, see
+        /// [`FileStorage::front_timestamp`]
+        front: Option<Timestamp>,
+        /// Timestamp of the last record, if known, see [`FileStorage::append`]
+        back: Option<Timestamp>,
     }
 
     impl FileStorage {
@@ -84,68 +423,413 @@ mod on_disk {
         ///
         /// Returns [`None`] if the heap is empty.
         ///
+        /// Records are read back in the same `format`. Records that could not be serialised
+        /// are handled according to the `policy`, so the storage could have less records than the
+        /// heap did.
+        ///
+        /// Fails with [`std::io::ErrorKind::AlreadyExists`] if the file exists already, e.g.
+        /// written by another buffer in the same directory, rather than overwriting it. The heap
+        /// is left intact then.
+        ///
         /// TODO: make non-empty heap newtype?
         pub fn new(
-            heap: &mut BinaryHeap<Reverse<Record>>,
+            heap: &mut BinaryHeap<Reverse<Sequenced>>,
             file: impl AsRef<Path>,
+            format: impl Into<Format>,
+            policy: SerErrorPolicy,
         ) -> std::io::Result<Option<Self>> {
-            let Some(non_zero_len) = NonZero::new(heap.len()) else {
+            if heap.is_empty() {
                 return Ok(None);
             };
 
+            let records = std::iter::from_fn(|| heap.pop().map(|Reverse(record)| Ok(record)));
+            Self::from_sorted(records, file, format.into(), policy).map(Some)
+        }
+
+        /// Create by writing already sorted records into the file at `path`.
+        pub(in crate::buffer) fn from_sorted(
+            records: impl Iterator<Item = std::io::Result<Sequenced>>,
+            path: impl AsRef<Path>,
+            format: Format,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Self> {
+            // never written over, e.g. by another buffer in the same directory
             let file = OpenOptions::new()
-                .create(true)
+                .create_new(true)
                 .write(true)
                 .read(true)
-                .open(file)?;
+                .open(&path)?;
+            let path = Some(path.as_ref().to_path_buf());
+            Self::write_sorted(records, Backing::File(file), path, format, policy)
+        }
+
+        /// Open a file with `len` sorted records, written the same way as [`FileStorage::new`]
+        /// does, e.g. by another buffer, or by a spill before the process restarted.
+        ///
+        /// In debug builds, the whole file is checked to be sorted and to have `len` records, and
+        /// [`Error::NotSorted`] is returned otherwise.
+        pub fn open(
+            path: impl AsRef<Path>,
+            len: usize,
+            format: impl Into<Format>,
+        ) -> std::io::Result<Self> {
+            let format = format.into();
+            let mut file = OpenOptions::new().read(true).open(&path)?;
+            codec::read_format_header(&mut file)?;
+            let file = Backing::File(file);
+
+            if cfg!(debug_assertions) {
+                if let Some(index) = first_unsorted(&file, len, format, 8_192)? {
+                    return Err(Error::NotSorted { index }.into());
+                }
+            }
+
+            Ok(Self {
+                path: Some(path.as_ref().to_path_buf()),
+                file: Some(file),
+                remaining: len,
+                total: len,
+                format,
+                written: 0,
+                front: None,
+                back: None,
+            })
+        }
+
+        /// Merge with another storage into a new one at `out`, deleting both of the input files
+        /// (unless they have no path, see [`FileStorage::from_file`]).
+        ///
+        /// Only the remaining records of the inputs are merged.
+        pub fn merge_with(
+            self,
+            other: FileStorage,
+            out: impl AsRef<Path>,
+            buf_capacity: usize,
+        ) -> std::io::Result<FileStorage> {
+            let paths = [self.path.clone(), other.path.clone()];
+            let format = self.format;
+            let mut merge = KMerge::new(vec![self.read(buf_capacity)?, other.read(buf_capacity)?]);
+            // the records were serialised once already
+            let merged = Self::from_sorted(merge.sequenced(), out, format, SerErrorPolicy::Panic)?;
+            for path in paths.into_iter().flatten() {
+                std::fs::remove_file(path)?;
+            }
+            Ok(merged)
+        }
+
+        /// Create a memory-mapped reader.
+        ///
+        /// Unlike [`FileStorage::read`], it doesn't need a read buffer, and deserializes records
+        /// directly from the mapped file.
+        #[cfg(feature = "mmap")]
+        pub fn read_mmap(self) -> std::io::Result<MmapReader> {
+            MmapReader::new(self)
+        }
+
+        /// Read the remaining records without consuming them, with a buffer of `buf_capacity`
+        /// bytes.
+        ///
+        /// Same as [`FileStorage::verify_sorted`], it reads the whole file, and the storage is
+        /// left at the same position. It's not available with the `minimal-memory` feature.
+        #[cfg(not(feature = "minimal-memory"))]
+        pub fn peek_records(&self, buf_capacity: usize) -> std::io::Result<Vec<Sequenced>> {
+            let file = self
+                .file
+                .as_ref()
+                .expect("file is only taken by the readers");
+            file.peek(|file| {
+                let mut reader = BufReader::with_capacity(buf_capacity, file);
+                (0..self.remaining)
+                    .map(|_| self.format.deserialize_from(&mut reader))
+                    .collect()
+            })
+        }
+
+        /// Check that the remaining records are actually sorted, reading them with a buffer of
+        /// `buf_capacity` bytes.
+        ///
+        /// It reads the whole file, so it's meant for debugging rather than for the hot path. The
+        /// storage is left at the same position.
+        pub fn verify_sorted(&self, buf_capacity: usize) -> std::io::Result<bool> {
+            let file = self
+                .file
+                .as_ref()
+                .expect("file is only taken by the readers");
+            Ok(first_unsorted(file, self.remaining, self.format, buf_capacity)?.is_none())
+        }
+
+        /// Same as [`FileStorage::new`], but drains the heap into a file opened already, e.g. an
+        /// unnamed temporary file, instead of creating one at a path.
+        ///
+        /// The file must be empty, and opened for both writing and reading. The storage has no
+        /// [`FileStorage::path`] then, so the file is never deleted by it: once dropped, it is up
+        /// to whoever created the file.
+        pub fn from_file(
+            heap: &mut BinaryHeap<Reverse<Sequenced>>,
+            file: File,
+            format: impl Into<Format>,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Option<Self>> {
+            Self::from_backing(heap, Backing::File(file), format, policy)
+        }
+
+        /// Same as [`FileStorage::from_file`], but into any [`Backing`], e.g. bytes in memory.
+        pub(crate) fn from_backing(
+            heap: &mut BinaryHeap<Reverse<Sequenced>>,
+            file: Backing,
+            format: impl Into<Format>,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Option<Self>> {
+            if heap.is_empty() {
+                return Ok(None);
+            };
+            let records = std::iter::from_fn(|| heap.pop().map(|Reverse(record)| Ok(record)));
+            Self::from_sorted_file(records, file, format.into(), policy).map(Some)
+        }
+
+        /// Same as [`FileStorage::new`], but reuses the file of this storage, which must be empty.
+        ///
+        /// The file is truncated and rewritten, saving the syscalls of creating a new one.
+        pub fn refill(
+            mut self,
+            heap: &mut BinaryHeap<Reverse<Sequenced>>,
+            format: impl Into<Format>,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Option<Self>> {
+            debug_assert!(self.is_empty());
+            if heap.is_empty() {
+                return Ok(None);
+            };
+
+            let mut file = self.file.take().expect("file is only taken by the readers");
+            file.clear()?;
+            let records = std::iter::from_fn(|| heap.pop().map(|Reverse(record)| Ok(record)));
+            Self::write_sorted(records, file, self.path, format.into(), policy).map(Some)
+        }
+
+        /// Same as [`FileStorage::from_sorted`], but into a file opened already, see
+        /// [`FileStorage::from_file`].
+        pub(in crate::buffer) fn from_sorted_file(
+            records: impl Iterator<Item = std::io::Result<Sequenced>>,
+            file: Backing,
+            format: Format,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Self> {
+            if file.byte_len()? > 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    "file to spill into isn't empty",
+                ));
+            }
+            Self::write_sorted(records, file, None, format, policy)
+        }
 
+        fn write_sorted(
+            records: impl Iterator<Item = std::io::Result<Sequenced>>,
+            file: Backing,
+            path: Option<PathBuf>,
+            format: Format,
+            policy: SerErrorPolicy,
+        ) -> std::io::Result<Self> {
             let mut writer = BufWriter::new(file);
+            codec::write_format_header(&mut writer)?;
+            let mut len = 0;
+            let mut written = codec::FORMAT_HEADER_LEN as u64;
 
-            while let Some(item) = heap.pop() {
-                bincode::serialize_into(&mut writer, &item).map_err(unwrap_bincode_io_error)?;
+            // serialised aside first, so that a skipped record leaves nothing in the file
+            let mut scratch = Vec::new();
+            let mut front = None;
+            let mut back = None;
+            for record in records {
+                scratch.clear();
+                let record = record?;
+                let result = format.serialize_into(&mut scratch, &record);
+                if policy.handle(result)?.is_some() {
+                    writer.write_all(&scratch)?;
+                    front = front.or(Some(record.timestamp()));
+                    back = Some(record.timestamp());
+                    len += 1;
+                    written += scratch.len() as u64;
+                }
             }
 
             let mut file = writer.into_inner().map_err(|err| err.into_error())?;
-            file.seek(SeekFrom::Start(0))?;
+            file.seek(SeekFrom::Start(codec::FORMAT_HEADER_LEN as u64))?;
 
-            Ok(Some(Self {
+            Ok(Self {
+                path,
                 file: Some(file),
-                remaining: non_zero_len.get(),
-            }))
+                remaining: len,
+                total: len,
+                format,
+                written,
+                front,
+                back,
+            })
+        }
+
+        /// Append a serialised record with the `timestamp` to the end of the file, leaving the
+        /// storage at the same position.
+        ///
+        /// Returns `false`, with nothing written, if the record is earlier than the last one, so
+        /// that the file would not be sorted anymore, or if the storage can't tell: it was read
+        /// from already, or opened with [`FileStorage::open`].
+        pub(in crate::buffer) fn append(
+            &mut self,
+            serialized: &[u8],
+            timestamp: Timestamp,
+        ) -> std::io::Result<bool> {
+            let sorted = match self.back {
+                Some(back) => back <= timestamp,
+                None => self.total == 0,
+            };
+            if !sorted || self.records_consumed() > 0 {
+                return Ok(false);
+            }
+            let file = self
+                .file
+                .as_mut()
+                .expect("file is only taken by the readers");
+            let position = file.stream_position()?;
+            file.seek(SeekFrom::End(0))?;
+            file.write_all(serialized)?;
+            file.seek(SeekFrom::Start(position))?;
+            self.front = self.front.or(Some(timestamp));
+            self.back = Some(timestamp);
+            self.remaining += 1;
+            self.total += 1;
+            self.written += serialized.len() as u64;
+            Ok(true)
         }
 
         /// Create a reader
         pub fn read(self, capacity: usize) -> std::io::Result<FileStorageReader> {
+            Ok(self.reader(capacity))
+        }
+
+        /// Same as [`FileStorage::read`], but never fails, as errors of reading the first record
+        /// are returned by the reader then, so the storage is never lost.
+        pub(in crate::buffer) fn reader(self, capacity: usize) -> FileStorageReader {
             FileStorageReader::new(self, capacity)
         }
 
+        /// Path of the underlying file, [`None`] if it was handed over with
+        /// [`FileStorage::from_file`]
+        pub fn path(&self) -> Option<&Path> {
+            self.path.as_deref()
+        }
+
+        /// Number of records remaining in the storage
+        pub fn len(&self) -> usize {
+            self.remaining
+        }
+
         pub fn is_empty(&self) -> bool {
             self.remaining == 0
         }
+
+        /// Number of records read out of the storage so far, zero if it's still at its start.
+        ///
+        /// Records are consumed by the readers (see [`FileStorage::read`]), and the storage they
+        /// are closed into keeps the count.
+        pub fn records_consumed(&self) -> usize {
+            self.total - self.remaining
+        }
+
+        /// Number of bytes written into the file when the storage was created, including the
+        /// format header. Zero if it was opened with [`FileStorage::open`].
+        pub fn bytes_written(&self) -> u64 {
+            self.written
+        }
+
+        /// Timestamp of the first remaining record, i.e. the earliest one, without creating a
+        /// reader.
+        ///
+        /// Unless it is known already (e.g. since the storage was written or its reader closed),
+        /// the record is read with a buffer of `buf_capacity` bytes, leaving the storage at the
+        /// same position, and the timestamp is cached.
+        pub fn front_timestamp(
+            &mut self,
+            buf_capacity: usize,
+        ) -> std::io::Result<Option<Timestamp>> {
+            if self.is_empty() {
+                return Ok(None);
+            }
+            if self.front.is_none() {
+                let file = self
+                    .file
+                    .as_mut()
+                    .expect("file is only taken by the readers");
+                let position = file.stream_position()?;
+                let reader = BufReader::with_capacity(buf_capacity, &mut *file);
+                let record: Sequenced = self.format.deserialize_from(reader)?;
+                file.seek(SeekFrom::Start(position))?;
+                self.front = Some(record.timestamp());
+            }
+            Ok(self.front)
+        }
+    }
+
+    /// Find the index of the first of `len` records which is earlier than the previous one,
+    /// reading from the current position of the `file` and restoring it afterwards.
+    fn first_unsorted(
+        file: &Backing,
+        len: usize,
+        format: Format,
+        buf_capacity: usize,
+    ) -> std::io::Result<Option<usize>> {
+        file.peek(|file| {
+            let mut reader = BufReader::with_capacity(buf_capacity, file);
+            let mut prev: Option<Sequenced> = None;
+            for index in 0..len {
+                let record: Sequenced = format.deserialize_from(&mut reader)?;
+                if prev.is_some_and(|prev| record < prev) {
+                    return Ok(Some(index));
+                }
+                prev = Some(record);
+            }
+            Ok(None)
+        })
     }
 
     /// Performs reading from the file buffer in merge-sort-friendly way.
     #[derive(Debug)]
     pub struct FileStorageReader {
         storage: FileStorage,
-        buffer: WrappedBufReader<File>,
-        last: Option<LastRead>,
+        buffer: WrappedBufReader<Backing>,
+        head: Option<Head>,
+        /// Position in the file the reader has started from
+        start: usize,
+    }
+
+    /// The earliest record of a [`FileStorageReader`]
+    #[derive(Debug)]
+    enum Head {
+        /// Only the key is peeked from the read buffer, none of the bytes are consumed yet
+        Key((Timestamp, u64)),
+        Decoded(LastRead),
+        /// Reading the record at `position` failed. The error is returned by the next access to
+        /// the record, and the access after that reads it again from its start.
+        Failed {
+            error: Option<std::io::Error>,
+            position: usize,
+        },
     }
 
     #[derive(Debug)]
     struct LastRead {
-        record: Record,
+        record: Sequenced,
         bytes_read: usize,
     }
 
     impl FileStorageReader {
-        fn new(mut storage: FileStorage, capacity: usize) -> std::io::Result<Self> {
+        fn new(mut storage: FileStorage, capacity: usize) -> Self {
             let mut file = storage
                 .file
                 .take()
                 .expect("this method is only called when there is some file");
             let bytes_read =
-                file.seek(SeekFrom::Current(0))
+                file.stream_position()
                     .expect("zero seeking couldn't fail, could it?") as usize;
             let buf_reader = BufReader::with_capacity(capacity, file);
             let mut reader = Self {
@@ -154,344 +838,5530 @@ mod on_disk {
                     buf_reader,
                     bytes_read,
                 },
-                last: None,
+                head: None,
+                start: bytes_read,
             };
-            reader.read_next()?;
-            Ok(reader)
+            reader.fill_head();
+            reader
         }
 
         /// Last record in the file, i.e. the earliest in this file so far.
         ///
-        /// [`Self::read_next`] moves to the next one (if there is).
-        pub fn last(&self) -> Option<&Record> {
-            self.last.as_ref().map(|x| &x.record)
+        /// [`Self::read_next`] moves to the next one (if there is). The record is only
+        /// deserialised once it is needed, see [`Self::peek_timestamp`].
+        pub fn last(&mut self) -> std::io::Result<Option<&Sequenced>> {
+            self.check_head()?;
+            if let Some(Head::Key(_)) = self.head {
+                let position = self.buffer.bytes_read;
+                self.head = Some(match self.decode() {
+                    Ok(x) => Head::Decoded(x),
+                    Err(error) => {
+                        self.head = Some(Head::Failed {
+                            error: None,
+                            position,
+                        });
+                        return Err(error);
+                    }
+                });
+            }
+            Ok(match &self.head {
+                Some(Head::Decoded(x)) => Some(&x.record),
+                _ => None,
+            })
+        }
+
+        /// Timestamp of [`Self::last`], without deserialising the whole record.
+        ///
+        /// Only the leading bytes of the record are peeked from the read buffer, so that the merge
+        /// could pick the earliest record among many files without deserialising the data of the
+        /// others. If the record crosses the end of the read buffer, it is deserialised whole
+        /// instead. Errors of reading the record are returned by [`Self::pop`] that reaches it.
+        ///
+        /// A record that failed to be read is considered the earliest one, so that the merge
+        /// reaches its error right away, rather than yielding the later records of other readers.
+        pub fn peek_timestamp(&self) -> Option<Timestamp> {
+            self.key().map(|(ts, _)| ts)
+        }
+
+        /// See [`Sequenced::key`]
+        pub(super) fn key(&self) -> Option<(Timestamp, u64)> {
+            self.head.as_ref().map(|head| match head {
+                Head::Key(key) => *key,
+                Head::Decoded(x) => x.record.key(),
+                Head::Failed { .. } => (Timestamp(0), 0),
+            })
         }
 
         /// Read the next record (if there is), changing the result of [`Self::last`]
         pub fn read_next(&mut self) -> std::io::Result<()> {
-            if self.last.is_some() {
+            self.pop()?;
+            Ok(())
+        }
+
+        /// Number of records not popped yet, including [`Self::last`]
+        pub fn remaining(&self) -> usize {
+            self.storage.remaining
+        }
+
+        /// Number of bytes of the records popped so far.
+        ///
+        /// Doesn't include [`Self::last`] and whatever is read ahead into the buffer, so it stays
+        /// accurate if the reader is closed before reaching the end.
+        pub fn bytes_consumed(&self) -> u64 {
+            self.position() - self.start as u64
+        }
+
+        /// Byte offset in the file of [`Self::last`], i.e. of the next record to pop, or of the
+        /// end of the records.
+        ///
+        /// Whatever is read ahead into the buffer is not counted, so it is where
+        /// [`Self::close`] leaves the file.
+        pub fn position(&self) -> u64 {
+            (match &self.head {
+                Some(Head::Decoded(x)) => self.buffer.bytes_read - x.bytes_read,
+                Some(Head::Failed { position, .. }) => *position,
+                _ => self.buffer.bytes_read,
+            }) as u64
+        }
+
+        /// Same as [`Self::read_next`], but returns the record that was [`Self::last`] before.
+        ///
+        /// If the file ends before the record, returns [`output::Error::Eof`], and
+        /// [`output::Error::TruncatedRecord`] if it ends in the middle of it. Nothing is popped
+        /// then, and the next call reads the record again, e.g. once a transient error is gone.
+        pub fn pop(&mut self) -> std::io::Result<Option<Sequenced>> {
+            self.check_head()?;
+            let popped = match self.head.take() {
+                None => None,
+                Some(Head::Key(_)) => {
+                    let position = self.buffer.bytes_read;
+                    match self.decode() {
+                        Ok(x) => Some(x.record),
+                        Err(error) => {
+                            self.head = Some(Head::Failed {
+                                error: None,
+                                position,
+                            });
+                            return Err(error);
+                        }
+                    }
+                }
+                Some(Head::Decoded(x)) => Some(x.record),
+                Some(Head::Failed { .. }) => unreachable!("checked above"),
+            };
+            if popped.is_some() {
                 self.storage.remaining -= 1;
             }
 
-            self.last = if !self.storage.is_empty() {
-                let bytes_before = self.buffer.bytes_read;
-                let record =
-                    bincode::deserialize_from(&mut self.buffer).map_err(unwrap_bincode_io_error)?;
-                let bytes_read = self.buffer.bytes_read - bytes_before;
-                Some(LastRead { record, bytes_read })
-            } else {
-                None
+            // an error of the next record is kept for the next call, not to lose the popped one
+            self.fill_head();
+            debug_assert!(
+                matches!(self.head, Some(Head::Failed { .. }))
+                    || popped
+                        .as_ref()
+                        .zip(self.key())
+                        .is_none_or(|(popped, key)| popped.key() <= key),
+                "records in {:?} aren't sorted",
+                self.storage.path
+            );
+
+            Ok(popped)
+        }
+
+        /// Read the next record into [`Self::head`], keeping the error of reading it, if any.
+        fn fill_head(&mut self) {
+            let position = self.buffer.bytes_read;
+            self.head = match self.read_head() {
+                Ok(head) => head,
+                Err(error) => Some(Head::Failed {
+                    error: Some(error),
+                    position,
+                }),
             };
+        }
 
-            Ok(())
+        /// Return the error of reading [`Self::head`], if it failed. If the error was returned
+        /// already, the record is read again from its start first.
+        fn check_head(&mut self) -> std::io::Result<()> {
+            let Some(Head::Failed { error, position }) = &mut self.head else {
+                return Ok(());
+            };
+            if let Some(error) = error.take() {
+                return Err(error);
+            }
+            let position = *position;
+            self.buffer
+                .buf_reader
+                .seek(SeekFrom::Start(position as u64))?;
+            self.buffer.bytes_read = position;
+            self.fill_head();
+            match &mut self.head {
+                Some(Head::Failed { error, .. }) => Err(error.take().expect("set by fill_head")),
+                _ => Ok(()),
+            }
+        }
+
+        /// Peek the key of the next record, if there is one.
+        fn read_head(&mut self) -> std::io::Result<Option<Head>> {
+            if self.storage.is_empty() {
+                return Ok(None);
+            }
+            let Format::Bincode(encoding) = self.storage.format else {
+                return Ok(Some(Head::Decoded(self.decode()?)));
+            };
+            let buffered = self.buffer.buf_reader.fill_buf()?;
+            // any errors are reported by deserialising the record as usual
+            Ok(Some(
+                match encoding.deserialize_from::<_, SequencedKey>(buffered) {
+                    Ok(key) => Head::Key((key.timestamp, key.seq)),
+                    Err(_) => Head::Decoded(self.decode()?),
+                },
+            ))
+        }
+
+        /// Deserialise the next record, consuming its bytes
+        fn decode(&mut self) -> std::io::Result<LastRead> {
+            let bytes_before = self.buffer.bytes_read;
+            let record = self
+                .storage
+                .format
+                .deserialize_from(&mut self.buffer)
+                .map_err(|err| {
+                    codec::eof_or_truncated(err, self.buffer.bytes_read - bytes_before)
+                })?;
+            let bytes_read = self.buffer.bytes_read - bytes_before;
+            Ok(LastRead { record, bytes_read })
         }
 
         /// Close the reader. The next call to [`FileStorage::read`] will resume from the same
         /// position.
-        pub fn close(mut self) -> std::io::Result<FileStorage> {
+        pub fn close(self) -> std::io::Result<FileStorage> {
+            let (storage, result) = self.close_keeping();
+            result.map(|()| storage)
+        }
+
+        /// Same as [`Self::close`], but returns the storage even if moving its file back to the
+        /// position fails, so that its records are not dropped along with the error.
+        pub(in crate::buffer) fn close_keeping(mut self) -> (FileStorage, std::io::Result<()>) {
+            let position = self.position();
+            self.storage.front = match self.head {
+                Some(Head::Failed { .. }) => None,
+                _ => self.peek_timestamp(),
+            };
             let mut file = self.buffer.buf_reader.into_inner();
-            file.seek(SeekFrom::Start(
-                self.last.map_or(self.buffer.bytes_read, |x| {
-                    self.buffer.bytes_read - x.bytes_read
-                }) as u64,
-            ))?;
+            let result = file.seek(SeekFrom::Start(position)).map(drop);
             self.storage.file = Some(file);
-            Ok(self.storage)
+            (self.storage, result)
         }
     }
 
-    /// Needed to track the exact number of bytes [`bincode`] reads.
+    /// Performs reading from the memory-mapped file buffer, same as [`FileStorageReader`] does.
+    ///
+    /// Instead of tracking bytes read by a reader, it keeps a byte cursor into the map.
+    #[cfg(feature = "mmap")]
     #[derive(Debug)]
-    struct WrappedBufReader<T> {
-        bytes_read: usize,
-        buf_reader: BufReader<T>,
+    pub struct MmapReader {
+        storage: FileStorage,
+        file: Backing,
+        map: memmap2::Mmap,
+        cursor: usize,
+        last: Option<LastRead>,
     }
 
-    // TODO: implement more methods, forwarding them to the actual `BufReader`, for efficiency
-    impl<T: Read> Read for WrappedBufReader<T> {
-        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-            let bytes = self.buf_reader.read(buf)?;
-            self.bytes_read += bytes;
-            Ok(bytes)
+    #[cfg(feature = "mmap")]
+    impl MmapReader {
+        fn new(mut storage: FileStorage) -> std::io::Result<Self> {
+            let mut file = storage
+                .file
+                .take()
+                .expect("this method is only called when there is some file");
+            let cursor = file.stream_position()? as usize;
+            let map = match &file {
+                // SAFETY: the file is exclusively owned by the storage and is never modified
+                //         while it is mapped
+                Backing::File(file) => unsafe { memmap2::Mmap::map(file)? },
+                #[cfg(test)]
+                Backing::Memory(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "only files could be mapped",
+                    ))
+                }
+            };
+            let mut reader = Self {
+                storage,
+                file,
+                map,
+                cursor,
+                last: None,
+            };
+            reader.read_next()?;
+            Ok(reader)
+        }
+
+        /// See [`FileStorageReader::last`]
+        pub fn last(&self) -> Option<&Sequenced> {
+            self.last.as_ref().map(|x| &x.record)
+        }
+
+        /// See [`FileStorageReader::read_next`]
+        pub fn read_next(&mut self) -> std::io::Result<()> {
+            if self.last.is_some() {
+                self.storage.remaining -= 1;
+            }
+
+            self.last = if !self.storage.is_empty() {
+                let mut slice = &self.map[self.cursor..];
+                let len_before = slice.len();
+                let record = self
+                    .storage
+                    .format
+                    .deserialize_from(&mut slice)
+                    .map_err(|err| codec::eof_or_truncated(err, len_before - slice.len()))?;
+                let bytes_read = len_before - slice.len();
+                self.cursor += bytes_read;
+                Some(LastRead { record, bytes_read })
+            } else {
+                None
+            };
+
+            Ok(())
+        }
+
+        /// See [`FileStorageReader::remaining`]
+        pub fn remaining(&self) -> usize {
+            self.storage.remaining
+        }
+
+        /// See [`FileStorageReader::close`]
+        pub fn close(mut self) -> std::io::Result<FileStorage> {
+            drop(self.map);
+            self.file.seek(SeekFrom::Start(
+                self.last
+                    .as_ref()
+                    .map_or(self.cursor, |x| self.cursor - x.bytes_read) as u64,
+            ))?;
+            self.storage.file = Some(self.file);
+            self.storage.front = self.last.map(|x| x.record.timestamp());
+            Ok(self.storage)
+        }
+    }
+
+    /// Needed to track the exact number of bytes [`bincode`] reads.
+    #[derive(Debug)]
+    struct WrappedBufReader<T> {
+        bytes_read: usize,
+        buf_reader: BufReader<T>,
+    }
+
+    // TODO: implement more methods, forwarding them to the actual `BufReader`, for efficiency
+    impl<T: Read> Read for WrappedBufReader<T> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let bytes = self.buf_reader.read(buf)?;
+            self.bytes_read += bytes;
+            Ok(bytes)
+        }
+    }
+}
+
+/// K-way merge of sorted sources
+mod merge {
+    use super::*;
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    /// Streaming k-way merge of [`FileStorageReader`]s and, optionally, an in-memory heap.
+    ///
+    /// Yields records in the global `(timestamp, seq)` order, see [`Sequenced`]. It could be
+    /// stopped at any point (e.g. with [`KMerge::peek`] reaching some bound), and then
+    /// [`KMerge::close`]d, returning the sources with the records that were not yielded yet.
+    #[derive(Debug)]
+    pub struct KMerge {
+        readers: Vec<FileStorageReader>,
+        heap: BinaryHeap<Reverse<Sequenced>>,
+        /// Only built for [`MergeStrategy::LoserTree`]
+        tree: Option<LoserTree>,
+    }
+
+    /// The file storages and the remaining of the heap, see [`KMerge::close`]
+    type Closed = (Vec<FileStorage>, BinaryHeap<Reverse<Sequenced>>);
+
+    enum Source {
+        Reader(usize),
+        Heap,
+    }
+
+    impl KMerge {
+        /// Merge the readers
+        pub fn new(readers: Vec<FileStorageReader>) -> Self {
+            Self {
+                readers,
+                heap: BinaryHeap::new(),
+                tree: None,
+            }
+        }
+
+        /// Merge the in-memory heap alongside with the readers
+        pub fn with_heap(mut self, heap: BinaryHeap<Reverse<Sequenced>>) -> Self {
+            self.heap = heap;
+            self
+        }
+
+        /// Choose how to find the earliest record among the readers, see [`MergeStrategy`]
+        pub fn with_strategy(mut self, strategy: MergeStrategy) -> Self {
+            self.tree = match strategy {
+                MergeStrategy::Scan => None,
+                MergeStrategy::LoserTree => Some(LoserTree::new(&self.readers)),
+            };
+            self
+        }
+
+        /// Where the next record comes from, along with its key.
+        ///
+        /// Records of the readers are compared by their keys only, so that only the records
+        /// actually yielded are deserialised, see [`FileStorageReader::peek_timestamp`].
+        fn earliest_source(&self) -> Option<(Source, (Timestamp, u64))> {
+            let reader = match &self.tree {
+                None => self
+                    .readers
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, x)| x.key().map(|key| (Source::Reader(i), key)))
+                    .min_by_key(|(_, key)| *key),
+                Some(tree) => tree
+                    .winner()
+                    .and_then(|i| self.readers[i].key().map(|key| (Source::Reader(i), key))),
+            };
+            let heap = self
+                .heap
+                .peek()
+                .map(|Reverse(record)| (Source::Heap, record.key()));
+
+            match (reader, heap) {
+                (Some(reader), Some(heap)) if heap.1 < reader.1 => Some(heap),
+                (Some(reader), _) => Some(reader),
+                (None, heap) => heap,
+            }
+        }
+
+        /// Number of the merged readers, including those that are read to the end already
+        pub fn readers_count(&self) -> usize {
+            self.readers.len()
+        }
+
+        /// Total [`FileStorageReader::bytes_consumed`] of the readers
+        pub fn bytes_consumed(&self) -> u64 {
+            self.readers.iter().map(|x| x.bytes_consumed()).sum()
+        }
+
+        /// The record that will be yielded next
+        pub fn peek(&mut self) -> std::io::Result<Option<&Record>> {
+            let next = match self.earliest_source() {
+                None => None,
+                Some((Source::Reader(i), _)) => self.readers[i].last()?,
+                Some((Source::Heap, _)) => self.heap.peek().map(|Reverse(record)| record),
+            };
+            Ok(next.map(|x| &x.record))
+        }
+
+        /// Timestamp of the record that will be yielded next, without deserialising it
+        pub fn peek_timestamp(&self) -> Option<Timestamp> {
+            self.earliest_source().map(|(_, (ts, _))| ts)
+        }
+
+        /// Close the merge, returning non-empty file storages and the remaining of the heap.
+        ///
+        /// Next [`FileStorage::read`] will resume from the first record not yielded by the merge.
+        pub fn close(self) -> std::io::Result<Closed> {
+            let (mut files, heap) = self.close_all()?;
+            files.retain(|file| !file.is_empty());
+            Ok((files, heap))
+        }
+
+        /// Same as [`KMerge::close`], but returns the empty file storages as well, e.g. to reuse
+        /// their files.
+        pub fn close_all(self) -> std::io::Result<Closed> {
+            let (closed, result) = self.close_keeping();
+            result.map(|()| closed)
+        }
+
+        /// Same as [`KMerge::close_all`], but closes each reader on its own, returning all the
+        /// storages along with the first error, see [`FileStorageReader::close_keeping`].
+        pub(in crate::buffer) fn close_keeping(self) -> (Closed, std::io::Result<()>) {
+            let mut result = Ok(());
+            let files = self
+                .readers
+                .into_iter()
+                .map(|reader| {
+                    let (file, closed) = reader.close_keeping();
+                    result = std::mem::replace(&mut result, Ok(())).and(closed);
+                    file
+                })
+                .collect();
+            ((files, self.heap), result)
+        }
+    }
+
+    impl KMerge {
+        /// Same as [`Iterator::next`], but yields the records along with their sequence numbers,
+        /// e.g. to spill them once again
+        pub(in crate::buffer) fn next_sequenced(&mut self) -> Option<std::io::Result<Sequenced>> {
+            match self.earliest_source()?.0 {
+                Source::Reader(i) => {
+                    let popped = self.readers[i].pop().transpose();
+                    if let Some(tree) = &mut self.tree {
+                        tree.replay(i, &self.readers);
+                    }
+                    popped
+                }
+                Source::Heap => self.heap.pop().map(|Reverse(record)| Ok(record)),
+            }
+        }
+
+        /// Iterate over the records along with their sequence numbers, see
+        /// [`KMerge::next_sequenced`]
+        pub(in crate::buffer) fn sequenced(
+            &mut self,
+        ) -> impl Iterator<Item = std::io::Result<Sequenced>> + '_ {
+            std::iter::from_fn(|| self.next_sequenced())
+        }
+    }
+
+    impl Iterator for KMerge {
+        type Item = std::io::Result<Record>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.next_sequenced().map(|x| x.map(|x| x.record))
+        }
+    }
+
+    /// Tournament tree of the readers, keeping the loser of each match in its node.
+    ///
+    /// Leaf `i` (the reader `i`) is the node `k + i`, and the children of the node `n` are `2n`
+    /// and `2n + 1`, so the tree is shaped like a binary heap of `2k` nodes. Node zero holds the
+    /// overall winner. Once the winner is popped, only the matches on its path to the root are
+    /// replayed: one comparison per level, without sifting anything around.
+    #[derive(Debug)]
+    struct LoserTree {
+        nodes: Vec<usize>,
+    }
+
+    impl LoserTree {
+        fn new(readers: &[FileStorageReader]) -> Self {
+            let k = readers.len();
+            let mut winners = vec![0; 2 * k];
+            let mut nodes = vec![0; k];
+            for (i, winner) in winners.iter_mut().skip(k).enumerate() {
+                *winner = i;
+            }
+            for node in (1..k).rev() {
+                let (left, right) = (winners[2 * node], winners[2 * node + 1]);
+                let (winner, loser) = if Self::beats(readers, right, left) {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+                winners[node] = winner;
+                nodes[node] = loser;
+            }
+            if k > 1 {
+                nodes[0] = winners[1];
+            }
+            Self { nodes }
+        }
+
+        /// Index of the reader with the earliest record, if there are readers
+        fn winner(&self) -> Option<usize> {
+            self.nodes.first().copied()
+        }
+
+        /// Replay the matches of the reader, whose record has changed
+        fn replay(&mut self, reader: usize, readers: &[FileStorageReader]) {
+            let k = readers.len();
+            let mut winner = reader;
+            let mut node = (k + reader) / 2;
+            while node > 0 {
+                if Self::beats(readers, self.nodes[node], winner) {
+                    std::mem::swap(&mut self.nodes[node], &mut winner);
+                }
+                node /= 2;
+            }
+            self.nodes[0] = winner;
+        }
+
+        /// Whether the reader `a` has an earlier record than `b`. Empty readers lose, and ties are
+        /// won by the lower index, same as [`MergeStrategy::Scan`] does.
+        fn beats(readers: &[FileStorageReader], a: usize, b: usize) -> bool {
+            match (readers[a].key(), readers[b].key()) {
+                (Some(x), Some(y)) => x.cmp(&y).then(a.cmp(&b)).is_lt(),
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
         }
     }
 }
 
-fn unwrap_bincode_io_error(err: Box<bincode::ErrorKind>) -> std::io::Error {
-    match *err {
-        bincode::ErrorKind::Io(err) => err,
-        other => panic!("intentionally not covering serialisation errors in this task: {other}"),
+/// [`Buffer`] configuration
+///
+/// Could be constructed directly, or with [`Config::builder`], which validates it. Either way,
+/// [`Buffer::new`] validates it as well.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of records is allowed to store in memory.
+    ///
+    /// With the `minimal-memory` feature, it is capped by [`MINIMAL_MEMORY_MAX_IN_MEMORY`].
+    pub max_in_memory: usize,
+    /// Buffer capacity for reading from each file buffer, i.e. merge-sort buffer capacity
+    pub file_read_buf_capacity: usize,
+    /// Buffer capacity for reading from a file buffer, depending on the number of records
+    /// remaining in it.
+    ///
+    /// Allows giving larger buffers to larger files. [`None`] means using
+    /// [`Config::file_read_buf_capacity`] for all of them.
+    pub file_read_buf_policy: Option<fn(usize) -> usize>,
+    /// Cap on the total capacity of the read buffers of all the files being merged.
+    ///
+    /// Each file gets at most an equal share of the cap, so the peak memory of a merge doesn't
+    /// grow with the number of files. The more files, the smaller the buffers, and the more often
+    /// the merge has to wait for the disk. [`None`] means no cap.
+    pub max_merge_buffer_bytes: Option<usize>,
+    /// Soft cap on the total number of buffered records (in-memory and on-disk).
+    ///
+    /// Used by [`crate::UnsortedDataSinkLoop`] to apply backpressure on producers. [`None`] means
+    /// no cap.
+    pub max_buffered_records: Option<usize>,
+    /// Maximal number of records reported by a single [`crate::NewRecordsAvailable`].
+    ///
+    /// Used by [`crate::UnsortedDataSinkLoop`] to split large dumps into a few smaller ones (see
+    /// [`Buffer::dump_safe_at_most`]), notifying about each of them, so that the consumer could
+    /// start reading before the whole dump is over. [`None`] means a single notification per dump.
+    pub max_notify_batch: Option<NonZero<usize>>,
+    /// How late records are allowed to arrive.
+    ///
+    /// [`Buffer::dump_safe`] shifts the safe-to-dump timestamp earlier by this value, giving
+    /// straggler records time to arrive. Records arriving later than that are counted in
+    /// [`Stats::late_records`].
+    pub lateness_slack: Timestamp,
+    /// Hold back the records with exactly the safe timestamp of [`Buffer::dump_safe`] (shifted by
+    /// [`Config::lateness_slack`]), until it advances past them.
+    ///
+    /// The safe timestamp is usually the last one of some input, which could still produce more
+    /// records with the same timestamp. Dumped right away, the equal records could be followed by
+    /// such a straggler in a later dump (and notification), rather than merged with them. Held
+    /// back, they are dumped a bit later instead, so it is a tradeoff of the latency for keeping
+    /// the records with equal timestamps together.
+    pub exclusive_watermark: bool,
+    /// Integer encoding of the on-disk buffers
+    pub int_encoding: IntEncoding,
+    /// Write the on-disk buffers as [`Format::Ndjson`] instead, ignoring [`Config::int_encoding`].
+    ///
+    /// It is only meant for inspecting the spill files while debugging.
+    pub ndjson_spills: bool,
+    /// Limits of the records allowed in, measured as serialised in the on-disk buffers. It should
+    /// be the same as of the output, see [`output::Writer::with_serialization`].
+    ///
+    /// Larger records are rejected by [`Buffer::push_record`] with [`Error::RecordTooLarge`],
+    /// rather than failing the spill they would end up in. Records aren't measured at all without
+    /// [`SerializationConfig::max_record_size`].
+    pub serialization: SerializationConfig,
+    /// How often to flush the output while dumping.
+    ///
+    /// The output is always flushed at the end of [`Buffer::dump_safe`], but large dumps could
+    /// keep records unflushed for a long time. [`None`] means flushing only at the end.
+    pub flush_interval: Option<Duration>,
+    /// Where the time is read from, i.e. for [`Config::flush_interval`]. [`SystemClock`] by
+    /// default, while tests could use [`crate::clock::MockClock`].
+    ///
+    /// It is the only setting measured in wall-clock time. [`Config::lateness_slack`] and
+    /// [`Config::drop_older_than`] are in record timestamps, as they are compared with the
+    /// timestamps of the pushed records and the safe timestamp of [`Buffer::dump_safe`], so they
+    /// are deterministic already. The same goes for [`crate::UnsortedDataSinkLoop`], which only
+    /// reads the time through its buffer.
+    pub clock: Arc<dyn Clock>,
+    /// Number of merged records to hand to the output at once, flushing it after each chunk.
+    ///
+    /// It batches the writes of [`output::Writer::write_all`] while keeping at most a chunk of
+    /// records in memory. [`None`] means writing records one by one. With the `minimal-memory`
+    /// feature, it is capped by [`MINIMAL_MEMORY_MAX_IN_MEMORY`].
+    pub dump_chunk: Option<NonZero<usize>>,
+    /// Progress reporting while dumping, see [`Progress`]
+    pub progress: Option<Progress>,
+    /// Directories to spread the on-disk buffers across, e.g. on different disks.
+    ///
+    /// New buffer files are assigned to them in turns. If empty, the directory passed to
+    /// [`Buffer::new`] is used.
+    pub spill_dirs: Vec<PathBuf>,
+    /// Creates the file to spill into for the given spill id, instead of `dump-{id}` in
+    /// [`Config::spill_dirs`], e.g. on tmpfs or unnamed with `O_TMPFILE`.
+    ///
+    /// The file must be empty, and opened for both writing and reading. The buffer doesn't know
+    /// where it is, so it never deletes it: the factory should better create unnamed files (see
+    /// `tempfile::tempfile_in`), which are gone once closed. Such files are not reused either
+    /// (see [`Config::spill_file_pool`]). [`None`] means creating files in the directories.
+    pub spill_factory: Option<SpillFactory>,
+    /// Whether to spill records on disk when the in-memory buffer is full
+    pub spill: SpillPolicy,
+    /// How the pushed records are kept in memory, see [`InMemoryStrategy`]
+    pub in_memory_strategy: InMemoryStrategy,
+    /// Number of in-memory records to spill at, not greater than [`Config::max_in_memory`].
+    /// [`None`] means spilling once the in-memory buffer is full.
+    pub spill_high: Option<usize>,
+    /// Number of in-memory records a spill leaves, below [`Config::spill_high`], i.e. the low
+    /// mark the in-memory buffer is drained down to.
+    ///
+    /// Only the earliest records are spilled then, keeping the latest ones in memory, so the
+    /// in-memory buffer doesn't go from full to empty on each spill, and it doesn't spill again
+    /// until it refills up to the high mark. Thus, the higher the low mark, the smaller the spill
+    /// files, and the more of them the merge has to read. Dumps still spill all the in-memory
+    /// records before merging, unless there are too few of them, see
+    /// [`Config::min_spill_records`].
+    pub spill_low: usize,
+    /// Number of in-memory records a dump spills at, merging fewer of them right from memory
+    /// instead.
+    ///
+    /// Frequent dumps would otherwise leave a tiny spill file each, e.g. of a single record, and
+    /// the merges would have to read all of them. The in-memory records are still spilled by the
+    /// pushes once they reach [`Config::spill_high`], so they never exceed
+    /// [`Config::max_in_memory`], and a threshold above the high mark means that dumps never
+    /// spill. Zero (the default) means dumps always spill.
+    pub min_spill_records: usize,
+    /// Drop pushed records that are older than the cutoff, instead of buffering them.
+    ///
+    /// Dropped records are counted in [`Stats::dropped_records`]. Records that are not dropped
+    /// could still be late, i.e. earlier than records already dumped (counted in
+    /// [`Stats::late_records`]): those are only avoided by a cutoff that is not earlier than the
+    /// safe timestamp of [`Buffer::dump_safe`] shifted by [`Config::lateness_slack`].
+    pub drop_older_than: Option<Cutoff>,
+    /// Divert late records into a separate output file at this path, instead of buffering them,
+    /// so that the main output stays ordered and nothing is lost.
+    ///
+    /// A record is late if it is earlier than the last dumped one, see [`Stats::late_records`].
+    /// Late records are written in batches, at the end of each dump and once
+    /// [`Config::max_in_memory`] of them are pending. Each batch is sorted, and
+    /// [`Buffer::finish`] sorts the whole file, see [`Buffer::late_output`]. A batch that fails to
+    /// be written stays pending and is counted in [`Stats::late_write_errors`], without failing
+    /// the push or the dump.
+    pub late_output: Option<PathBuf>,
+    /// Write pushed records straight into the output while they arrive in order and nothing is
+    /// buffered, skipping the in-memory buffer and spilling.
+    ///
+    /// Only records the last dump was safe to write are passed through, i.e. not later than the
+    /// safe timestamp of the last [`Buffer::dump_safe`] (or the like), so the output is the same as
+    /// without it: a record earlier than them pushed afterwards would be late anyway (see
+    /// [`Stats::late_records`]). Such records are reported by the next dump, as if they were
+    /// buffered. Hence, it only pays off if the input is ordered already and the dumps are
+    /// frequent.
+    pub pass_through_ordered: bool,
+    /// Number of spill files to keep open once all their records are dumped, so that next spills
+    /// reuse them instead of creating new files. Zero disables the reuse.
+    pub spill_file_pool: usize,
+    /// Never delete or rewrite the spill files, e.g. to inspect them after a failed run.
+    ///
+    /// The files are left in the buffer directories as they were written, even once all their
+    /// records are dumped. It disables [`Config::spill_file_pool`], as reused files are rewritten,
+    /// and the cleanup of [`Buffer::drain_sorted_vec`].
+    pub keep_spills: bool,
+    /// What to do with records that could not be serialised on spilling.
+    ///
+    /// Skipped records are counted in [`Stats::skipped_records`]. The policy of the output is set
+    /// on the [`output::Writer`] separately.
+    pub ser_error_policy: SerErrorPolicy,
+    /// How to merge the on-disk buffers
+    pub merge_strategy: MergeStrategy,
+}
+
+/// See [`Config::spill_factory`]
+#[derive(Clone)]
+pub struct SpillFactory(Arc<dyn Fn(usize) -> std::io::Result<Backing> + Send + Sync>);
+
+impl SpillFactory {
+    /// Wrap the function creating the file for a spill id
+    pub fn new(f: impl Fn(usize) -> std::io::Result<File> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(move |id| f(id).map(Backing::File)))
+    }
+
+    /// Spill into bytes in memory, e.g. to test the buffer without touching the disk
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> Self {
+        Self(Arc::new(|_| Ok(Backing::memory())))
+    }
+
+    fn create(&self, id: usize) -> std::io::Result<Backing> {
+        (self.0)(id)
+    }
+}
+
+impl std::fmt::Debug for SpillFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpillFactory").finish_non_exhaustive()
+    }
+}
+
+/// See [`Config::drop_older_than`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cutoff {
+    /// Drop records earlier than the timestamp
+    Fixed(Timestamp),
+    /// Drop records that are earlier than the latest pushed one by more than the given lag
+    BehindLatest(Timestamp),
+}
+
+/// How [`KMerge`] finds the earliest record among the on-disk buffers, see
+/// [`Config::merge_strategy`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Compare the next records of all the buffers for each merged record, `O(k)`
+    #[default]
+    Scan,
+    /// Keep a tournament (loser) tree of the buffers, `O(log k)` comparisons per merged record.
+    ///
+    /// Pays off with many buffers, and yields records in exactly the same order.
+    LoserTree,
+}
+
+/// What to do when the in-memory buffer is full, see [`Config::spill`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpillPolicy {
+    /// Spill the in-memory records into a file
+    #[default]
+    Spill,
+    /// Never touch the disk. Pushing into the full buffer fails with
+    /// [`Error::CapacityExceeded`], and the records are dumped right from memory.
+    NeverSpill,
+}
+
+/// How the pushed records are kept in memory, see [`Config::in_memory_strategy`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InMemoryStrategy {
+    /// Heap of up to [`Config::max_in_memory`] records, spilled and merged as needed. Any input
+    /// is sorted, as long as the safe timestamps passed to [`Buffer::dump_safe`] are right.
+    #[default]
+    Heap,
+    /// Sorted window of the `k` latest records, for inputs that are nearly sorted already.
+    ///
+    /// Once the window is full, each pushed record pushes the earliest one out of it, which is
+    /// written into the output right away, regardless of the safe timestamp (and reported by the
+    /// next dump, same as [`Config::pass_through_ordered`]). Dumps write the records of the window
+    /// up to the safe timestamp.
+    ///
+    /// It is cheaper than the heap, but only sorts the records out of order by at most `k`
+    /// positions: a record arriving after `k` later ones is written after the records pushed out
+    /// of the window before it, i.e. the output is **not sorted** then. Such records are counted
+    /// in [`Stats::late_records`].
+    ///
+    /// Records pushed out of the window while other records are buffered (e.g. with
+    /// [`Buffer::import_sorted`]) are buffered as with [`InMemoryStrategy::Heap`] instead.
+    BoundedWindow {
+        /// Number of records in the window. With the `minimal-memory` feature, it is capped by
+        /// [`MINIMAL_MEMORY_MAX_IN_MEMORY`].
+        k: NonZero<usize>,
+    },
+}
+
+/// Errors of the [`Buffer`].
+///
+/// They are returned wrapped into [`std::io::Error`], see [`Error::downcast`].
+#[derive(Debug)]
+pub enum Error {
+    /// The in-memory buffer is full and spilling is disabled, see [`SpillPolicy::NeverSpill`].
+    ///
+    /// The record is not buffered.
+    CapacityExceeded {
+        /// Number of records the in-memory buffer holds
+        capacity: usize,
+    },
+    /// The imported file is not sorted, see [`Buffer::import_sorted`]
+    NotSorted {
+        /// Index of the first record that is earlier than the one before it
+        index: usize,
+    },
+    /// The record is larger than [`Config::serialization`] allows.
+    ///
+    /// The record is not buffered.
+    RecordTooLarge {
+        /// Size of the serialised record, in bytes
+        size: u64,
+        /// See [`SerializationConfig::effective_max_record_size`]
+        limit: usize,
+    },
+    /// Writing into the output failed before, so it could end with a part of a record. Nothing
+    /// is dumped into it anymore, until it is replaced with [`Buffer::swap_writer`].
+    OutputFailed,
+}
+
+impl Error {
+    /// Get the error wrapped into [`std::io::Error`], if it is there.
+    pub fn downcast(err: &std::io::Error) -> Option<&Self> {
+        err.get_ref().and_then(|x| x.downcast_ref())
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CapacityExceeded { capacity } => write!(
+                f,
+                "in-memory buffer is full ({capacity} records) and spilling is disabled"
+            ),
+            Self::NotSorted { index } => {
+                write!(f, "record {index} is earlier than the one before it")
+            }
+            Self::RecordTooLarge { size, limit } => {
+                write!(
+                    f,
+                    "record of {size} bytes is larger than the limit of {limit}"
+                )
+            }
+            Self::OutputFailed => write!(f, "writing into the output failed before"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        let kind = match value {
+            Error::CapacityExceeded { .. } => std::io::ErrorKind::OutOfMemory,
+            Error::NotSorted { .. } => std::io::ErrorKind::InvalidData,
+            Error::RecordTooLarge { .. } => std::io::ErrorKind::InvalidInput,
+            Error::OutputFailed => std::io::ErrorKind::BrokenPipe,
+        };
+        std::io::Error::new(kind, value)
+    }
+}
+
+/// Cap on [`Config::max_in_memory`], [`Config::dump_chunk`] and the window of
+/// [`InMemoryStrategy::BoundedWindow`] with the `minimal-memory` feature, so that the memory
+/// footprint stays small whatever the configuration.
+///
+/// The spill marks are lowered to fit under it, the low one to at most half of the high one, see
+/// [`Config::spill_high`]. The effective cap of a buffer is in [`Stats::max_in_memory`]. Without
+/// the feature, nothing is capped.
+pub const MINIMAL_MEMORY_MAX_IN_MEMORY: usize = 1_024;
+
+/// Keeps up to 100 000 records in memory and reads each on-disk buffer through an 8 KiB buffer,
+/// same as [`std::io::BufReader`] does.
+///
+/// Everything else is opt-in: no cap on buffered records or merge buffers, no lateness slack, no
+/// dropping or passing through of records, no periodic flushing and progress reporting. Records
+/// are spilled into the directory passed to [`Buffer::new`] with [`IntEncoding::Fixint`], and
+/// serialisation errors panic.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_in_memory: 100_000,
+            file_read_buf_capacity: 8 * 1024,
+            file_read_buf_policy: None,
+            max_merge_buffer_bytes: None,
+            max_buffered_records: None,
+            max_notify_batch: None,
+            lateness_slack: Timestamp(0),
+            exclusive_watermark: false,
+            int_encoding: IntEncoding::default(),
+            ndjson_spills: false,
+            serialization: SerializationConfig::default(),
+            flush_interval: None,
+            clock: Arc::new(SystemClock),
+            dump_chunk: None,
+            progress: None,
+            spill_dirs: vec![],
+            spill_factory: None,
+            spill: SpillPolicy::default(),
+            in_memory_strategy: InMemoryStrategy::default(),
+            spill_high: None,
+            spill_low: 0,
+            min_spill_records: 0,
+            drop_older_than: None,
+            late_output: None,
+            pass_through_ordered: false,
+            spill_file_pool: 0,
+            keep_spills: false,
+            ser_error_policy: SerErrorPolicy::default(),
+            merge_strategy: MergeStrategy::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Start building the configuration from the [`Default`] one.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Self::default(),
+        }
+    }
+
+    /// Check that the configuration makes sense.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_in_memory == 0 {
+            return Err(ConfigError::ZeroMaxInMemory);
+        }
+        if self.file_read_buf_capacity == 0 {
+            return Err(ConfigError::ZeroFileReadBufCapacity);
+        }
+        if self.max_merge_buffer_bytes == Some(0) {
+            return Err(ConfigError::ZeroMaxMergeBufferBytes);
+        }
+        let spill_high = self.spill_high.unwrap_or(self.max_in_memory);
+        if spill_high > self.max_in_memory {
+            return Err(ConfigError::SpillHighAboveMaxInMemory);
+        }
+        if self.spill_low >= spill_high {
+            return Err(ConfigError::SpillLowNotBelowHigh);
+        }
+        Ok(())
+    }
+
+    /// Estimate the spills and the merge of sorting `estimated_records` records of
+    /// `avg_record_bytes` bytes each, without any IO.
+    ///
+    /// The size is the one of a record in the spill files, and the output is assumed to take
+    /// about as much.
+    ///
+    /// The records are assumed to be pushed with [`InMemoryStrategy::Heap`] and dumped at once
+    /// in the end, e.g. with [`crate::external_sort`], following the same spill marks as the
+    /// [`Buffer`] does. Nothing is assumed dropped, late or passed through. See [`SortPlan`].
+    ///
+    /// The byte estimates saturate at [`u64::MAX`] rather than overflow.
+    pub fn plan(&self, estimated_records: usize, avg_record_bytes: usize) -> SortPlan {
+        let bytes = |records: usize| (records as u64).saturating_mul(avg_record_bytes as u64);
+        let output_bytes = bytes(estimated_records);
+        if self.spill == SpillPolicy::NeverSpill || estimated_records == 0 {
+            return SortPlan {
+                spill_files: 0,
+                merge_passes: 0,
+                estimated_bytes_written: output_bytes,
+                estimated_bytes_read: 0,
+            };
+        }
+
+        let (spill_high, spill_low) = self.spill_marks();
+        // the first spill happens at the high mark, and each next one once the in-memory buffer
+        // refills from the low mark back up to it
+        let spills = match estimated_records.checked_sub(spill_high) {
+            Some(beyond) => 1 + beyond / (spill_high - spill_low),
+            None => 0,
+        };
+        let mut spilled = spills * (spill_high - spill_low);
+        let left_in_memory = estimated_records - spilled;
+        // the dump spills whatever is left in memory before merging, unless it's too few records
+        let mut spill_files = spills;
+        if left_in_memory > 0 && left_in_memory >= self.min_spill_records {
+            spill_files += 1;
+            spilled += left_in_memory;
+        }
+        // each record is spilled at most once
+        let spilled_bytes = bytes(spilled)
+            .saturating_add((spill_files as u64).saturating_mul(codec::FORMAT_HEADER_LEN as u64));
+        SortPlan {
+            spill_files,
+            merge_passes: usize::from(spill_files > 0),
+            estimated_bytes_written: spilled_bytes.saturating_add(output_bytes),
+            estimated_bytes_read: spilled_bytes,
+        }
+    }
+
+    /// The spill marks the [`Buffer`] goes by, i.e. [`Config::spill_high`] and
+    /// [`Config::spill_low`], lowered with the `minimal-memory` feature.
+    fn spill_marks(&self) -> (usize, usize) {
+        let spill_high = self.spill_high.unwrap_or(self.max_in_memory);
+        #[cfg(feature = "minimal-memory")]
+        {
+            let spill_high = spill_high.min(MINIMAL_MEMORY_MAX_IN_MEMORY);
+            // not just below the high mark, so that spills don't get tiny
+            (spill_high, self.spill_low.min(spill_high / 2))
+        }
+        #[cfg(not(feature = "minimal-memory"))]
+        (spill_high, self.spill_low)
+    }
+}
+
+/// Estimate of sorting records with a [`Config`], see [`Config::plan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortPlan {
+    /// Number of spill files written, including the one the dump spills the in-memory records
+    /// into
+    pub spill_files: usize,
+    /// Number of passes over the records to merge them. The dump merges all the spill files at
+    /// once, however many there are, so it's one if anything is spilled, zero otherwise.
+    pub merge_passes: usize,
+    /// Bytes written into the spill files (including their headers) and into the output
+    pub estimated_bytes_written: u64,
+    /// Bytes read out of the spill files by the merge
+    pub estimated_bytes_read: u64,
+}
+
+/// Builder of [`Config`], see [`Config::builder`]
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// See [`Config::max_in_memory`]
+    pub fn max_in_memory(mut self, value: usize) -> Self {
+        self.config.max_in_memory = value;
+        self
+    }
+
+    /// See [`Config::file_read_buf_capacity`]
+    pub fn file_read_buf_capacity(mut self, value: usize) -> Self {
+        self.config.file_read_buf_capacity = value;
+        self
+    }
+
+    /// See [`Config::file_read_buf_policy`]
+    pub fn file_read_buf_policy(mut self, value: fn(usize) -> usize) -> Self {
+        self.config.file_read_buf_policy = Some(value);
+        self
+    }
+
+    /// See [`Config::max_merge_buffer_bytes`]
+    pub fn max_merge_buffer_bytes(mut self, value: usize) -> Self {
+        self.config.max_merge_buffer_bytes = Some(value);
+        self
+    }
+
+    /// See [`Config::max_buffered_records`]
+    pub fn max_buffered_records(mut self, value: usize) -> Self {
+        self.config.max_buffered_records = Some(value);
+        self
+    }
+
+    /// See [`Config::max_notify_batch`]
+    pub fn max_notify_batch(mut self, value: NonZero<usize>) -> Self {
+        self.config.max_notify_batch = Some(value);
+        self
+    }
+
+    /// See [`Config::lateness_slack`]
+    pub fn lateness_slack(mut self, value: Timestamp) -> Self {
+        self.config.lateness_slack = value;
+        self
+    }
+
+    /// See [`Config::exclusive_watermark`]
+    pub fn exclusive_watermark(mut self, value: bool) -> Self {
+        self.config.exclusive_watermark = value;
+        self
+    }
+
+    /// See [`Config::serialization`]
+    pub fn serialization(mut self, value: SerializationConfig) -> Self {
+        self.config.serialization = value;
+        self
+    }
+
+    /// See [`Config::int_encoding`]
+    pub fn int_encoding(mut self, value: IntEncoding) -> Self {
+        self.config.int_encoding = value;
+        self
+    }
+
+    /// See [`Config::ndjson_spills`]
+    pub fn ndjson_spills(mut self, value: bool) -> Self {
+        self.config.ndjson_spills = value;
+        self
+    }
+
+    /// See [`Config::flush_interval`]
+    pub fn flush_interval(mut self, value: Duration) -> Self {
+        self.config.flush_interval = Some(value);
+        self
+    }
+
+    /// See [`Config::clock`]
+    pub fn clock(mut self, value: impl Clock + 'static) -> Self {
+        self.config.clock = Arc::new(value);
+        self
+    }
+
+    /// See [`Config::dump_chunk`]
+    pub fn dump_chunk(mut self, value: NonZero<usize>) -> Self {
+        self.config.dump_chunk = Some(value);
+        self
+    }
+
+    /// See [`Config::progress`]
+    pub fn progress(mut self, value: Progress) -> Self {
+        self.config.progress = Some(value);
+        self
+    }
+
+    /// See [`Config::spill_dirs`]
+    pub fn spill_dirs(mut self, value: Vec<PathBuf>) -> Self {
+        self.config.spill_dirs = value;
+        self
+    }
+
+    /// See [`Config::spill`]
+    pub fn spill(mut self, value: SpillPolicy) -> Self {
+        self.config.spill = value;
+        self
+    }
+
+    /// See [`Config::in_memory_strategy`]
+    pub fn in_memory_strategy(mut self, value: InMemoryStrategy) -> Self {
+        self.config.in_memory_strategy = value;
+        self
+    }
+
+    /// See [`Config::spill_high`]
+    pub fn spill_high(mut self, value: usize) -> Self {
+        self.config.spill_high = Some(value);
+        self
+    }
+
+    /// See [`Config::spill_low`]
+    pub fn spill_low(mut self, value: usize) -> Self {
+        self.config.spill_low = value;
+        self
+    }
+
+    /// See [`Config::min_spill_records`]
+    pub fn min_spill_records(mut self, value: usize) -> Self {
+        self.config.min_spill_records = value;
+        self
+    }
+
+    /// See [`Config::drop_older_than`]
+    pub fn drop_older_than(mut self, value: Cutoff) -> Self {
+        self.config.drop_older_than = Some(value);
+        self
+    }
+
+    /// See [`Config::late_output`]
+    pub fn late_output(mut self, value: PathBuf) -> Self {
+        self.config.late_output = Some(value);
+        self
+    }
+
+    /// See [`Config::pass_through_ordered`]
+    pub fn pass_through_ordered(mut self, value: bool) -> Self {
+        self.config.pass_through_ordered = value;
+        self
+    }
+
+    /// See [`Config::spill_file_pool`]
+    pub fn spill_file_pool(mut self, value: usize) -> Self {
+        self.config.spill_file_pool = value;
+        self
+    }
+
+    /// See [`Config::spill_factory`]
+    pub fn spill_factory(
+        mut self,
+        value: impl Fn(usize) -> std::io::Result<File> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.spill_factory = Some(SpillFactory::new(value));
+        self
+    }
+
+    /// See [`Config::keep_spills`]
+    pub fn keep_spills(mut self, value: bool) -> Self {
+        self.config.keep_spills = value;
+        self
+    }
+
+    /// See [`Config::ser_error_policy`]
+    pub fn ser_error_policy(mut self, value: SerErrorPolicy) -> Self {
+        self.config.ser_error_policy = value;
+        self
+    }
+
+    /// See [`Config::merge_strategy`]
+    pub fn merge_strategy(mut self, value: MergeStrategy) -> Self {
+        self.config.merge_strategy = value;
+        self
+    }
+
+    /// Validate and build the configuration.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Invalid [`Config`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// [`Config::max_in_memory`] is zero, so every record would be spilled right away
+    ZeroMaxInMemory,
+    /// [`Config::file_read_buf_capacity`] is zero, so every read would hit the disk
+    ZeroFileReadBufCapacity,
+    /// [`Config::max_merge_buffer_bytes`] is zero, so no file could be read
+    ZeroMaxMergeBufferBytes,
+    /// [`Config::spill_high`] is above [`Config::max_in_memory`]
+    SpillHighAboveMaxInMemory,
+    /// [`Config::spill_low`] is not below [`Config::spill_high`], so nothing would be spilled
+    SpillLowNotBelowHigh,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroMaxInMemory => write!(f, "max_in_memory must be greater than zero"),
+            Self::ZeroFileReadBufCapacity => {
+                write!(f, "file_read_buf_capacity must be greater than zero")
+            }
+            Self::ZeroMaxMergeBufferBytes => {
+                write!(f, "max_merge_buffer_bytes must be greater than zero")
+            }
+            Self::SpillHighAboveMaxInMemory => {
+                write!(f, "spill_high must not be greater than max_in_memory")
+            }
+            Self::SpillLowNotBelowHigh => write!(f, "spill_low must be less than spill_high"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    /// Get the error wrapped into [`std::io::Error`], if it is there.
+    pub fn downcast(err: &std::io::Error) -> Option<&Self> {
+        err.get_ref().and_then(|x| x.downcast_ref())
+    }
+}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(value: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, value)
+    }
+}
+
+/// Reports progress of long dumps, e.g. to log throughput.
+///
+/// Unlike [`PushOutcome::Spilled`], it is reported _during_ [`Buffer::dump_safe`] and
+/// [`Buffer::flush_all`].
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Report after each this number of dumped records
+    pub every: NonZero<u64>,
+    /// Called with the number of records dumped so far in the current dump.
+    ///
+    /// It is called right from the merge loop, so it should be cheap. If it panics, the panic
+    /// propagates to the caller of the dump.
+    pub callback: fn(u64),
+}
+
+/// Statistics of the [`Buffer`] over its lifetime
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    /// Number of records that arrived after the records later than them were already dumped.
+    ///
+    /// Such records are buffered as usual, but they break the global order of the output, unless
+    /// they are diverted, see [`Config::late_output`].
+    pub late_records: u64,
+    /// Number of records dropped as too old, see [`Config::drop_older_than`]
+    pub dropped_records: u64,
+    /// Number of records skipped as they could not be serialised, see
+    /// [`Config::ser_error_policy`]
+    pub skipped_records: u64,
+    /// Number of records filtered out on dumping, see [`Buffer::set_map`]
+    pub filtered_records: u64,
+    /// Number of files the in-memory records were spilled into
+    pub spills: u64,
+    /// Number of bytes written into the spill files, including [`Buffer::compact`], see
+    /// [`FileStorage::bytes_written`]
+    pub bytes_spilled: u64,
+    /// Number of bytes read from the spill files by the dumps (see [`MergeReport::bytes_read`])
+    /// and by [`Buffer::compact`].
+    ///
+    /// Along with [`Stats::bytes_spilled`], it tells the write amplification of spilling.
+    pub bytes_merged: u64,
+    /// Largest number of records held in memory at once, see [`Stats::max_in_memory`]
+    pub peak_in_memory: usize,
+    /// Number of records the buffer could hold in memory, i.e. [`Config::max_in_memory`],
+    /// unless capped by [`MINIMAL_MEMORY_MAX_IN_MEMORY`] with the `minimal-memory` feature.
+    pub max_in_memory: usize,
+    /// Number of records written into the spill files, including [`Buffer::compact`]
+    pub records_spilled: u64,
+    /// Number of records dumped into the output, including [`Config::pass_through_ordered`]
+    pub records_dumped: u64,
+    /// Number of times the late records failed to be written, see [`Config::late_output`].
+    ///
+    /// The records are kept and written along with the next batch, or by [`Buffer::finish`],
+    /// which reports the error. A part of them could be written by the failed attempt already,
+    /// and written twice then.
+    pub late_write_errors: u64,
+}
+
+impl Stats {
+    /// How many times the dumped records were written on disk on average, see
+    /// [`Stats::records_spilled`].
+    ///
+    /// It is about 1 if each record is spilled once and merged straight into the output, and
+    /// grows with each [`Buffer::compact`] pass. Records that never reach the disk (see
+    /// [`SpillPolicy::NeverSpill`] and [`Config::pass_through_ordered`]) bring it below 1.
+    /// [`None`] until anything is dumped.
+    pub fn write_amplification(&self) -> Option<f64> {
+        (self.records_dumped > 0).then(|| self.records_spilled as f64 / self.records_dumped as f64)
+    }
+}
+
+/// _The_ buffer.
+///
+/// It accepts records via [`Buffer::push_record`], and dumps them based on the safe timestamp
+/// with [`Buffer::dump_safe`].
+#[derive(Debug)]
+pub struct Buffer<'w> {
+    in_memory: in_memory::Buffer,
+    files: Vec<on_disk::FileStorage>,
+    files_counter: usize,
+    files_dirs: Vec<PathBuf>,
+    spill_factory: Option<SpillFactory>,
+    /// Ids of the spill files, only tracked if they are reused, see [`Config::spill_file_pool`]
+    files_ids: HashMap<PathBuf, usize>,
+    /// Empty spill files to reuse, along with their ids
+    files_pool: Vec<(usize, on_disk::FileStorage)>,
+    files_pool_size: usize,
+    /// See [`Config::keep_spills`]
+    keep_spills: bool,
+    file_read_buf_capacity: usize,
+    file_read_buf_policy: Option<fn(usize) -> usize>,
+    max_merge_buffer_bytes: Option<usize>,
+    spill_format: Format,
+    serialization: SerializationConfig,
+    ser_error_policy: SerErrorPolicy,
+    merge_strategy: MergeStrategy,
+    flush_interval: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    dump_chunk: Option<NonZero<usize>>,
+    progress: Option<Progress>,
+    spill: SpillPolicy,
+    /// See [`InMemoryStrategy::BoundedWindow`]
+    window: Option<in_memory::Window>,
+    /// See [`Config::spill_high`]
+    spill_high: usize,
+    spill_low: usize,
+    min_spill_records: usize,
+    drop_older_than: Option<Cutoff>,
+    late: Option<LateOutput>,
+    pass_through_ordered: bool,
+    /// Records written since the last dump, see [`Config::pass_through_ordered`]
+    passed_through: DumpedCount,
+    latest_pushed_timestamp: Option<Timestamp>,
+    earliest_buffered_timestamp: Option<Timestamp>,
+    last_dumped_timestamp: Option<Timestamp>,
+    /// Bound of the last dump, up to which records may be passed through, see
+    /// [`Config::pass_through_ordered`]
+    dumped_bound: Option<Bound<Timestamp>>,
+    /// See [`Error::OutputFailed`]
+    output_failed: bool,
+    lateness_slack: Timestamp,
+    exclusive_watermark: bool,
+    stats: Stats,
+    output: Output<'w>,
+    map: Option<RecordMap<'w>>,
+}
+
+/// See [`Buffer::set_map`]
+struct RecordMap<'w>(Box<dyn FnMut(Record) -> Option<Record> + Send + 'w>);
+
+impl std::fmt::Debug for RecordMap<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecordMap").finish_non_exhaustive()
+    }
+}
+
+/// Late records diverted from the output, see [`Config::late_output`]
+#[derive(Debug)]
+struct LateOutput {
+    path: PathBuf,
+    writer: output::Writer,
+    /// Late records pushed since the last dump
+    pending: Vec<Record>,
+    /// Number of pending records they are written at, same as [`Config::max_in_memory`]
+    capacity: usize,
+}
+
+impl LateOutput {
+    fn open(path: PathBuf, capacity: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: output::Writer::open(&path)?,
+            path,
+            pending: Vec::new(),
+            capacity,
+        })
+    }
+
+    /// Add a late record, returning whether the pending ones are to be written
+    fn push(&mut self, record: Record) -> bool {
+        self.pending.push(record);
+        self.pending.len() >= self.capacity
+    }
+
+    /// Write the pending records, sorted among themselves, keeping them pending on error
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        // stable, so records with the same timestamp stay in the order they were pushed
+        self.pending.sort_by_key(Record::timestamp);
+        self.writer.write_all(&self.pending)?;
+        self.writer.flush()?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write the pending records, close the file and sort it as a whole
+    fn finish(mut self) -> std::io::Result<()> {
+        self.write_pending()?;
+        let path = std::mem::take(&mut self.path);
+        let config = Config {
+            max_in_memory: self.capacity,
+            ..Config::default()
+        };
+        drop(self);
+        output::sort_file(&path, &path, config).map(drop)
+    }
+}
+
+impl Drop for LateOutput {
+    fn drop(&mut self) {
+        // the buffer could be dropped without being finished, and the records mustn't be lost
+        let _ = self.write_pending();
+    }
+}
+
+/// Where the [`Buffer`] dumps records to
+#[derive(Debug)]
+enum Output<'w> {
+    Single(&'w mut output::Writer),
+    /// Records are written along with their sources, see [`Buffer::new_tagged`]
+    Tagged(&'w mut output::Writer<SourceTagged<Record>>),
+    Sharded(&'w mut output::Shards),
+    Channel(mpsc::Sender<Record>),
+    /// Records are collected temporarily, see [`Buffer::drain_sorted_vec`]
+    Collect(Vec<Record>),
+}
+
+impl Output<'_> {
+    /// Write the record, the source is only written by [`Output::Tagged`]
+    fn write(&mut self, record: Record, source: SourceId) -> std::io::Result<()> {
+        match self {
+            Self::Single(writer) => writer.write(&record),
+            Self::Tagged(writer) => writer.write(&SourceTagged { source, record }),
+            Self::Sharded(shards) => shards.write(&record),
+            Self::Channel(sender) => sender.send(record).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "receiver of dumped records is dropped",
+                )
+            }),
+            Self::Collect(records) => {
+                records.push(record);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the records, emptying the vector. The writer gets them at once, see
+    /// [`output::Writer::write_all`].
+    ///
+    /// On failure, the records that are not written are left in the vector, except for the
+    /// channel, as its receiver is gone for good. `sources` are those of the records.
+    fn write_chunk(
+        &mut self,
+        records: &mut Vec<Record>,
+        sources: &[SourceId],
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Single(writer) => {
+                writer.write_all(records)?;
+                records.clear();
+                Ok(())
+            }
+            Self::Tagged(writer) => {
+                let tagged: Vec<_> = (records.drain(..).zip(sources))
+                    .map(|(record, &source)| SourceTagged { source, record })
+                    .collect();
+                writer.write_all(&tagged).inspect_err(|_| {
+                    records.extend(tagged.into_iter().map(|x| x.record));
+                })
+            }
+            Self::Sharded(shards) => {
+                let mut written = 0;
+                let result = records.iter().try_for_each(|record| {
+                    shards.write(record)?;
+                    written += 1;
+                    Ok(())
+                });
+                records.drain(..written);
+                result
+            }
+            _ => (records.drain(..).zip(sources))
+                .try_for_each(|(record, &source)| self.write(record, source)),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Single(writer) => writer.flush(),
+            Self::Tagged(writer) => writer.flush(),
+            Self::Sharded(shards) => shards.flush(),
+            Self::Channel(_) | Self::Collect(_) => Ok(()),
+        }
+    }
+}
+
+impl<'w> Buffer<'w> {
+    /// Create the buffer, spilling records into `files_dir` (or [`Config::spill_dirs`]).
+    ///
+    /// The directories are created if they don't exist yet, unless spilling is disabled with
+    /// [`SpillPolicy::NeverSpill`]. Fails with [`ConfigError`] (see [`ConfigError::downcast`]) if
+    /// the `config` is invalid, see [`Config::validate`].
+    pub fn new(
+        files_dir: impl AsRef<Path>,
+        output: &'w mut output::Writer,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        Self::with_output(files_dir, Output::Single(output), config)
+    }
+
+    /// Same as [`Buffer::new`], but writes the records along with their sources, see
+    /// [`Buffer::push_tagged`].
+    ///
+    /// The output is read with [`output::Reader::with_records`] then. Records written into the
+    /// late output (see [`Config::late_output`]) are not tagged.
+    pub fn new_tagged(
+        files_dir: impl AsRef<Path>,
+        output: &'w mut output::Writer<SourceTagged<Record>>,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        Self::with_output(files_dir, Output::Tagged(output), config)
+    }
+
+    /// Same as [`Buffer::new`], but dumps records into the shards, see [`output::Shards`].
+    pub fn new_sharded(
+        files_dir: impl AsRef<Path>,
+        shards: &'w mut output::Shards,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        Self::with_output(files_dir, Output::Sharded(shards), config)
+    }
+
+    /// Same as [`Buffer::new`], but sends dumped records over the channel, in the same order
+    /// they would be written into the output.
+    ///
+    /// Dumping fails with [`std::io::ErrorKind::BrokenPipe`] once the receiver is dropped.
+    pub fn new_channel(
+        files_dir: impl AsRef<Path>,
+        sender: mpsc::Sender<Record>,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        Self::with_output(files_dir, Output::Channel(sender), config)
+    }
+
+    fn with_output(
+        files_dir: impl AsRef<Path>,
+        output: Output<'w>,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        config.validate()?;
+        let (spill_high, spill_low) = config.spill_marks();
+        let Config {
+            max_in_memory,
+            file_read_buf_capacity,
+            file_read_buf_policy,
+            max_merge_buffer_bytes,
+            lateness_slack,
+            exclusive_watermark,
+            int_encoding,
+            ndjson_spills,
+            serialization,
+            flush_interval,
+            clock,
+            dump_chunk,
+            progress,
+            spill_dirs,
+            spill_factory,
+            spill,
+            in_memory_strategy,
+            min_spill_records,
+            drop_older_than,
+            late_output,
+            pass_through_ordered,
+            spill_file_pool,
+            keep_spills,
+            ser_error_policy,
+            merge_strategy,
+            ..
+        } = config;
+        #[cfg(feature = "minimal-memory")]
+        let (max_in_memory, dump_chunk, in_memory_strategy) = {
+            let max_in_memory = max_in_memory.min(MINIMAL_MEMORY_MAX_IN_MEMORY);
+            let cap = NonZero::new(max_in_memory).expect("validated");
+            let in_memory_strategy = match in_memory_strategy {
+                InMemoryStrategy::BoundedWindow { k } => {
+                    InMemoryStrategy::BoundedWindow { k: k.min(cap) }
+                }
+                strategy => strategy,
+            };
+            (
+                max_in_memory,
+                dump_chunk.map(|chunk| chunk.min(cap)),
+                in_memory_strategy,
+            )
+        };
+        let files_dirs = if spill_dirs.is_empty() {
+            vec![files_dir.as_ref().to_path_buf()]
+        } else {
+            spill_dirs
+        };
+        if spill == SpillPolicy::Spill {
+            for dir in &files_dirs {
+                std::fs::create_dir_all(dir).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("failed to create buffer dir {}: {err}", dir.display()),
+                    )
+                })?;
+            }
+        }
+
+        Ok(Self {
+            in_memory: in_memory::Buffer::with_capacity(max_in_memory),
+            files: vec![],
+            files_counter: 0,
+            files_dirs,
+            spill_factory,
+            files_ids: HashMap::new(),
+            files_pool: Vec::with_capacity(spill_file_pool),
+            // reused files are rewritten
+            files_pool_size: if keep_spills { 0 } else { spill_file_pool },
+            keep_spills,
+            file_read_buf_capacity,
+            file_read_buf_policy,
+            max_merge_buffer_bytes,
+            spill_format: if ndjson_spills {
+                Format::Ndjson
+            } else {
+                int_encoding.into()
+            },
+            serialization,
+            ser_error_policy,
+            merge_strategy,
+            flush_interval,
+            clock,
+            dump_chunk,
+            progress,
+            spill,
+            window: match in_memory_strategy {
+                InMemoryStrategy::Heap => None,
+                InMemoryStrategy::BoundedWindow { k } => Some(in_memory::Window::new(k.get())),
+            },
+            spill_high,
+            spill_low,
+            min_spill_records,
+            drop_older_than,
+            late: late_output
+                .map(|path| LateOutput::open(path, max_in_memory))
+                .transpose()?,
+            pass_through_ordered,
+            passed_through: DumpedCount::default(),
+            latest_pushed_timestamp: None,
+            earliest_buffered_timestamp: None,
+            last_dumped_timestamp: None,
+            dumped_bound: None,
+            output_failed: false,
+            lateness_slack,
+            exclusive_watermark,
+            stats: Stats {
+                max_in_memory,
+                ..Stats::default()
+            },
+            output,
+            map: None,
+        })
+    }
+
+    /// Total number of buffered records, both in-memory and on-disk.
+    pub fn len(&self) -> usize {
+        self.in_memory.len()
+            + self.window.as_ref().map_or(0, in_memory::Window::len)
+            + self.files.iter().map(|x| x.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The earliest timestamp among the buffered records, if there are any.
+    ///
+    /// Calling [`Buffer::dump_safe`] with an earlier timestamp won't dump anything.
+    pub fn earliest_buffered_timestamp(&self) -> Option<Timestamp> {
+        self.earliest_buffered_timestamp
+    }
+
+    /// Records held in memory, including the window of [`InMemoryStrategy::BoundedWindow`], in
+    /// arbitrary order
+    fn in_memory_records(&self) -> impl Iterator<Item = &Record> {
+        let window = self.window.iter().flat_map(in_memory::Window::iter);
+        (self.in_memory.iter()).chain(window.map(|x| &x.record))
+    }
+
+    /// Approximate distribution of the timestamps of the records held **in memory**.
+    ///
+    /// Records that are already on disk are not included. The range between the minimal and the
+    /// maximal timestamps is split into `buckets` of equal width; each bucket is returned with its
+    /// starting timestamp and the number of records in it. Returns nothing if there are no records
+    /// in memory.
+    pub fn timestamp_histogram(&self, buckets: usize) -> Vec<(Timestamp, u64)> {
+        let mut timestamps = self.in_memory_records().map(Record::timestamp);
+        let Some(first) = timestamps.next() else {
+            return vec![];
+        };
+        if buckets == 0 {
+            return vec![];
+        }
+        let (min, max) = timestamps.fold((first, first), |(min, max), ts| {
+            (std::cmp::min(min, ts), std::cmp::max(max, ts))
+        });
+
+        let width = (max.0 - min.0) / buckets as u128 + 1;
+        let mut histogram: Vec<_> = (0..buckets)
+            .map(|i| (Timestamp(min.0 + width * i as u128), 0))
+            .collect();
+        for ts in self.in_memory_records().map(Record::timestamp) {
+            histogram[((ts.0 - min.0) / width) as usize].1 += 1;
+        }
+        histogram
+    }
+
+    /// Statistics accumulated so far
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Dump into another writer from now on, e.g. to rotate the output files between dumps.
+    ///
+    /// Buffered records are kept, and are written into the new writer by the next dumps. Since
+    /// each dump flushes the output at its end, the previous writer is flushed already, unless
+    /// writing into it failed (see [`Error::OutputFailed`]), which it is swapped to recover from.
+    /// Returns it, or [`None`] if the buffer didn't dump into a writer (see
+    /// [`Buffer::new_channel`]).
+    pub fn swap_writer(
+        &mut self,
+        writer: &'w mut output::Writer,
+    ) -> Option<&'w mut output::Writer> {
+        self.output_failed = false;
+        match std::mem::replace(&mut self.output, Output::Single(writer)) {
+            Output::Single(previous) => Some(previous),
+            _ => None,
+        }
+    }
+
+    /// Same as [`Buffer::swap_writer`], but for the writer of [`Buffer::new_tagged`].
+    pub fn swap_tagged_writer(
+        &mut self,
+        writer: &'w mut output::Writer<SourceTagged<Record>>,
+    ) -> Option<&'w mut output::Writer<SourceTagged<Record>>> {
+        self.output_failed = false;
+        match std::mem::replace(&mut self.output, Output::Tagged(writer)) {
+            Output::Tagged(previous) => Some(previous),
+            _ => None,
+        }
+    }
+
+    /// Path of the file that late records are diverted into, if they are, see
+    /// [`Config::late_output`].
+    ///
+    /// Some of the records pushed since the last dump may not be written there yet, and the file
+    /// is only sorted as a whole by [`Buffer::finish`].
+    pub fn late_output(&self) -> Option<&Path> {
+        self.late.as_ref().map(|x| x.path.as_path())
+    }
+
+    /// Dump all the buffered records, then write the pending late records and sort the late
+    /// output file, see [`Config::late_output`].
+    ///
+    /// Unlike dropping the buffer, it reports the failure to write the late records.
+    pub fn finish(mut self) -> std::io::Result<DumpedCount> {
+        let dumped = self.flush_all()?;
+        if let Some(late) = self.late.take() {
+            late.finish()?;
+        }
+        Ok(dumped)
+    }
+
+    /// Write the pending late records, if any, counting a failure in
+    /// [`Stats::late_write_errors`]
+    fn write_late(&mut self) {
+        if let Some(late) = self.late.as_mut() {
+            if late.write_pending().is_err() {
+                self.stats.late_write_errors += 1;
+            }
+        }
+    }
+
+    /// Transform each record right before it is written, or filter it out by returning [`None`].
+    ///
+    /// The map must keep the timestamp of the record, so that the output stays ordered (it is
+    /// only checked in debug builds). Filtered out records are not reported by the dumps, and are
+    /// counted in [`Stats::filtered_records`].
+    ///
+    /// The records kept buffered after a failed write are the mapped ones, so the retried dump
+    /// maps them again.
+    pub fn set_map(&mut self, map: impl FnMut(Record) -> Option<Record> + Send + 'w) {
+        self.map = Some(RecordMap(Box::new(map)));
+    }
+
+    /// Push a new record into the buffer.
+    ///
+    /// With [`SpillPolicy::NeverSpill`], fails if the in-memory buffer is full. Fails with
+    /// [`Error::RecordTooLarge`] if the record is larger than [`Config::serialization`] allows.
+    pub fn push_record(&mut self, record: Record) -> std::io::Result<PushOutcome> {
+        self.push_tagged(SourceTagged { source: 0, record })
+    }
+
+    /// Same as [`Buffer::push_record`], but the source of the record is carried along through
+    /// the in-memory buffer and the spill files into the output of [`Buffer::new_tagged`].
+    ///
+    /// Other outputs only get the record, e.g. [`Buffer::new`].
+    pub fn push_tagged(&mut self, tagged: SourceTagged<Record>) -> std::io::Result<PushOutcome> {
+        let SourceTagged { source, record } = tagged;
+        let ts = record.timestamp();
+        if self.is_cut_off(ts) {
+            return Ok(PushOutcome::Dropped);
+        }
+        self.check_capacity()?;
+        self.check_record_size(&record)?;
+        if self.last_dumped_timestamp.is_some_and(|last| ts < last) {
+            self.stats.late_records += 1;
+            if let Some(late) = self.late.as_mut() {
+                if late.push(record) {
+                    self.write_late();
+                }
+                return Ok(PushOutcome::DivertedLate);
+            }
+        }
+        if self.can_pass_through(ts) {
+            self.pass_through(record, source)?;
+            return Ok(PushOutcome::PassedThrough);
+        }
+        self.earliest_buffered_timestamp.replace(
+            self.earliest_buffered_timestamp
+                .map_or(ts, |prev| if ts < prev { ts } else { prev }),
+        );
+        if self.window.is_some() {
+            self.push_into_window(SourceTagged { source, record })?;
+            return Ok(PushOutcome::Buffered);
+        }
+
+        self.in_memory.push(record, source);
+        self.update_peak_in_memory();
+        if self.should_spill() {
+            let (file_id, records) = self
+                .dump_in_memory(self.spill_low)?
+                .expect("in-memory is above the low mark");
+            return Ok(PushOutcome::Spilled { file_id, records });
+        }
+
+        Ok(PushOutcome::Buffered)
+    }
+
+    /// Same as [`Buffer::push_record`], but the record is borrowed rather than moved: it is
+    /// serialised straight into the latest spill file, bypassing the in-memory heap.
+    ///
+    /// It is only meant for spilling eagerly, so it requires a spill target, failing with
+    /// [`std::io::ErrorKind::Unsupported`] with [`SpillPolicy::NeverSpill`]. The record is never
+    /// passed through (see [`Config::pass_through_ordered`]) nor kept in the window (see
+    /// [`InMemoryStrategy::BoundedWindow`]), and it is cloned if it is diverted as late (see
+    /// [`Config::late_output`]).
+    ///
+    /// Each spill file is sorted, so the record is only appended if it isn't earlier than the
+    /// last one in the file, and spilled into a new file otherwise. Records pushed in order thus
+    /// end up in a single file, but out of order ones could leave a lot of small files, see
+    /// [`Buffer::compact`].
+    pub fn push_record_ref(&mut self, record: &Record) -> std::io::Result<()> {
+        if self.spill == SpillPolicy::NeverSpill {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "records can only be pushed by reference into the spill files",
+            ));
+        }
+        let ts = record.timestamp();
+        if self.is_cut_off(ts) {
+            return Ok(());
+        }
+        self.check_record_size(record)?;
+        if self.last_dumped_timestamp.is_some_and(|last| ts < last) {
+            self.stats.late_records += 1;
+            if let Some(late) = self.late.as_mut() {
+                if late.push(record.clone()) {
+                    self.write_late();
+                }
+                return Ok(());
+            }
+        }
+
+        let sequenced = SequencedRef {
+            seq: self.in_memory.take_seq(),
+            record,
+            source: 0,
+        };
+        let mut serialized = Vec::new();
+        let result = self
+            .spill_format
+            .serialize_into(&mut serialized, &sequenced);
+        if self.ser_error_policy.handle(result)?.is_none() {
+            self.stats.skipped_records += 1;
+            return Ok(());
+        }
+        let appended = match self.files.last_mut() {
+            Some(file) => file.append(&serialized, ts)?,
+            None => false,
+        };
+        if appended {
+            self.stats.bytes_spilled += serialized.len() as u64;
+        } else {
+            let mut file = self.write_spill_file(&mut std::iter::empty())?;
+            assert!(file.append(&serialized, ts)?, "the new file is empty");
+            self.stats.spills += 1;
+            self.stats.bytes_spilled += file.bytes_written();
+            self.files.push(file);
+        }
+        self.stats.records_spilled += 1;
+        self.earliest_buffered_timestamp = Some(
+            self.earliest_buffered_timestamp
+                .map_or(ts, |prev| prev.min(ts)),
+        );
+        Ok(())
+    }
+
+    /// Push a record without a meaningful timestamp of its own, e.g. a marker event, so that it
+    /// is ordered right where it was pushed.
+    ///
+    /// The record is stamped with the latest timestamp pushed so far, placing it after all the
+    /// records pushed before it. Records with the same timestamp keep the order they were pushed
+    /// in (see [`Sequenced`]), so a few markers stamped the same way are dumped first-in
+    /// first-out. If nothing is pushed yet, the record keeps its own timestamp.
+    pub fn push_marker(&mut self, mut record: Record) -> std::io::Result<PushOutcome> {
+        if let Some(latest) = self.latest_pushed_timestamp {
+            *record.timestamp_mut() = latest;
+        }
+        self.push_record(record)
+    }
+
+    /// Push a batch of records, same as calling [`Buffer::push_record`] for each of them, spilling
+    /// as needed along the way.
+    ///
+    /// Stops at the first error, leaving the rest of the records unpushed.
+    pub fn push_records<I: IntoIterator<Item = Record>>(
+        &mut self,
+        records: I,
+    ) -> std::io::Result<()> {
+        for record in records {
+            self.push_record(record)?;
+        }
+        Ok(())
+    }
+
+    /// Add a file with `record_count` sorted records, written by another buffer, so that the
+    /// next dumps merge it as if its records were pushed.
+    ///
+    /// The file must be written in the same format, see [`Config::int_encoding`] and
+    /// [`Config::ndjson_spills`]. It is only checked to be sorted in debug builds, see
+    /// [`FileStorage::open`].
+    pub fn import_sorted(
+        &mut self,
+        path: impl AsRef<Path>,
+        record_count: usize,
+    ) -> std::io::Result<()> {
+        if record_count == 0 {
+            return Ok(());
+        }
+        let reader = on_disk::FileStorage::open(path, record_count, self.spill_format)?
+            .read(self.file_read_buf_capacity)?;
+        let ts = reader.peek_timestamp().expect("storage isn't empty");
+        self.files.push(reader.close()?);
+        self.earliest_buffered_timestamp = Some(
+            self.earliest_buffered_timestamp
+                .map_or(ts, |prev| prev.min(ts)),
+        );
+        Ok(())
+    }
+
+    /// Read buffer capacity for a file with `remaining` records, merged along with `files_count`
+    /// files in total.
+    fn read_buf_capacity(&self, remaining: usize, files_count: usize) -> usize {
+        let capacity = self
+            .file_read_buf_policy
+            .map_or(self.file_read_buf_capacity, |policy| policy(remaining));
+        match self.max_merge_buffer_bytes {
+            Some(max) => capacity.min(max / files_count).max(1),
+            None => capacity,
+        }
+    }
+
+    /// Whether the record could be written right away, see [`Config::pass_through_ordered`]
+    fn can_pass_through(&self, ts: Timestamp) -> bool {
+        self.pass_through_ordered
+            && !self.output_failed
+            && self.is_empty()
+            && self.last_dumped_timestamp.is_none_or(|last| last <= ts)
+            && self
+                .dumped_bound
+                .is_some_and(|bound| (Bound::Unbounded, bound).contains(&ts))
+    }
+
+    fn pass_through(&mut self, record: Record, source: SourceId) -> std::io::Result<()> {
+        self.last_dumped_timestamp = Some(record.timestamp());
+        let Some(record) = self.map(record) else {
+            return Ok(());
+        };
+        self.passed_through.add(&record);
+        let written = self.output.write(record, source);
+        self.check_output(written)
+    }
+
+    /// Remember that writing into the output failed, see [`Error::OutputFailed`]
+    fn check_output<T>(&mut self, result: std::io::Result<T>) -> std::io::Result<T> {
+        self.output_failed |= result.is_err();
+        result
+    }
+
+    /// Apply the map to a record about to be written, see [`Buffer::set_map`]
+    fn map(&mut self, record: Record) -> Option<Record> {
+        let Some(RecordMap(map)) = self.map.as_mut() else {
+            return Some(record);
+        };
+        let ts = record.timestamp();
+        let mapped = map(record);
+        debug_assert!(
+            mapped.as_ref().is_none_or(|x| x.timestamp() == ts),
+            "the map must keep the timestamps"
+        );
+        if mapped.is_none() {
+            self.stats.filtered_records += 1;
+        }
+        mapped
+    }
+
+    /// Whether the record should be dropped, see [`Config::drop_older_than`]
+    fn is_cut_off(&mut self, ts: Timestamp) -> bool {
+        let latest = self.latest_pushed_timestamp.map_or(ts, |x| x.max(ts));
+        self.latest_pushed_timestamp = Some(latest);
+        let cut_off = match self.drop_older_than {
+            None => false,
+            Some(Cutoff::Fixed(cutoff)) => ts < cutoff,
+            Some(Cutoff::BehindLatest(lag)) => ts.0 < latest.0.saturating_sub(lag.0),
+        };
+        if cut_off {
+            self.stats.dropped_records += 1;
+        }
+        cut_off
+    }
+
+    fn check_capacity(&self) -> std::io::Result<()> {
+        if self.spill == SpillPolicy::NeverSpill && self.in_memory.is_full() {
+            return Err(Error::CapacityExceeded {
+                capacity: self.in_memory.capacity(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fail if the record is larger than [`Config::serialization`] allows.
+    ///
+    /// Records that could not be serialised at all are let in, so that the spill handles them
+    /// according to [`Config::ser_error_policy`].
+    fn check_record_size(&self, record: &Record) -> std::io::Result<()> {
+        if self.serialization.max_record_size.is_none() {
+            return Ok(());
+        }
+        let Ok(size) = self.spill_format.serialized_size(record) else {
+            return Ok(());
+        };
+        // not counting the trailing newline, same as the output
+        let size = size - u64::from(self.spill_format == Format::Ndjson);
+        match self
+            .serialization
+            .check_record_size(self.spill_format, size)
+        {
+            Err(output::Error::RecordTooLarge { size, limit }) => {
+                Err(Error::RecordTooLarge { size, limit }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Push the record into the window, releasing the earliest record if it overflows, see
+    /// [`InMemoryStrategy::BoundedWindow`]
+    fn push_into_window(&mut self, record: SourceTagged<Record>) -> std::io::Result<()> {
+        let window = self.window.as_mut().expect("checked by the caller");
+        match window.insert(record) {
+            Some(earliest) => self.release_from_window(earliest),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the record taken out of the window, unless other records are buffered, which it is
+    /// buffered along with then.
+    fn release_from_window(&mut self, tagged: SourceTagged<Record>) -> std::io::Result<()> {
+        let SourceTagged { source, record } = tagged;
+        if self.in_memory.is_empty() && self.files.is_empty() {
+            return self.pass_through(record, source);
+        }
+        // the capacity is checked before pushing into the window
+        self.in_memory.push(record, source);
+        self.update_peak_in_memory();
+        if self.should_spill() {
+            self.dump_in_memory(self.spill_low)?;
+        }
+        Ok(())
+    }
+
+    /// Earliest timestamp in the window, see [`InMemoryStrategy::BoundedWindow`]
+    fn window_front(&self) -> Option<Timestamp> {
+        self.window
+            .as_ref()
+            .and_then(in_memory::Window::front_timestamp)
+    }
+
+    /// See [`Stats::peak_in_memory`]
+    fn update_peak_in_memory(&mut self) {
+        self.stats.peak_in_memory = self.stats.peak_in_memory.max(self.in_memory.len());
+    }
+
+    /// Whether the in-memory buffer reached the high mark, see [`Config::spill_high`]
+    fn should_spill(&self) -> bool {
+        self.spill == SpillPolicy::Spill && self.in_memory.len() >= self.spill_high
+    }
+
+    /// Spill the in-memory records, except for the `keep` latest ones.
+    ///
+    /// Returns the id of the new file and the number of records in it, if there were any records.
+    ///
+    /// On error, the records that were not spilled stay in memory.
+    fn dump_in_memory(&mut self, keep: usize) -> std::io::Result<Option<(usize, usize)>> {
+        let mut earliest = (keep > 0).then(|| self.in_memory.split_off_earliest(keep));
+        let in_memory = earliest.as_mut().unwrap_or(&mut self.in_memory);
+        if in_memory.is_empty() {
+            return Ok(None);
+        };
+        let drained = in_memory.len();
+        let spilled = match self.files_pool.pop() {
+            Some((id, storage)) => in_memory
+                .drain_into_storage(storage, self.spill_format, self.ser_error_policy)
+                .map(|file| (id, file)),
+            None if self.spill_factory.is_some() => {
+                let id = self.files_counter;
+                self.files_counter += 1;
+                let factory = self.spill_factory.as_ref().expect("checked above");
+                factory
+                    .create(id)
+                    .and_then(|file| {
+                        in_memory.drain_into_opened_file(
+                            file,
+                            self.spill_format,
+                            self.ser_error_policy,
+                        )
+                    })
+                    .map(|file| (id, file))
+            }
+            None => loop {
+                let id = self.files_counter;
+                self.files_counter += 1;
+                let path = spill_path(&self.files_dirs, id);
+                match in_memory.drain_into_file(path, self.spill_format, self.ser_error_policy) {
+                    // e.g. another buffer spills into the same directory, so try the next id
+                    Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+                    file => break file.map(|file| (id, file)),
+                }
+            },
+        };
+        let (id, file) = match spilled {
+            Ok(spilled) => spilled,
+            Err(err) => {
+                // the earliest records are split off the rest, so they'd be lost otherwise
+                if let Some(earliest) = earliest {
+                    self.in_memory.merge_back(earliest);
+                }
+                return Err(err);
+            }
+        };
+        let file = file.expect("in-memory isn't empty");
+        self.stats.skipped_records += (drained - file.len()) as u64;
+        self.stats.spills += 1;
+        self.stats.bytes_spilled += file.bytes_written();
+        self.stats.records_spilled += file.len() as u64;
+        if let Some(path) = file.path().filter(|_| self.files_pool_size > 0) {
+            self.files_ids.insert(path.to_path_buf(), id);
+        }
+        let records = file.len();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(id, records, path = ?file.path(), "spilled in-memory records");
+        self.files.push(file);
+        Ok(Some((id, records)))
+    }
+
+    /// Whether [`Buffer::dump_safe`] would dump any records with the timestamp, including those
+    /// written by [`Config::pass_through_ordered`].
+    ///
+    /// Unlike the dump, it doesn't touch the files, so it is cheap enough to be checked on each
+    /// record, skipping the dump when nothing is safe to dump yet.
+    pub fn has_safe_records(&self, safe_to_dump_timestamp: Timestamp) -> bool {
+        let bound = self.safe_bound(safe_to_dump_timestamp);
+        self.passed_through.count > 0
+            || self
+                .earliest_buffered_timestamp
+                .is_some_and(|ts| (Bound::Unbounded, bound).contains(&ts))
+    }
+
+    /// Write already sorted records into a new spill file, e.g. merged from other spill files.
+    ///
+    /// The records were serialised once already, so they are not expected to fail.
+    fn write_spill_file(
+        &mut self,
+        records: &mut impl Iterator<Item = std::io::Result<Sequenced>>,
+    ) -> std::io::Result<on_disk::FileStorage> {
+        let policy = SerErrorPolicy::Panic;
+        if let Some(factory) = self.spill_factory.clone() {
+            let id = self.files_counter;
+            self.files_counter += 1;
+            let file = factory.create(id)?;
+            return on_disk::FileStorage::from_sorted_file(
+                records,
+                file,
+                self.spill_format,
+                policy,
+            );
+        }
+        loop {
+            let id = self.files_counter;
+            self.files_counter += 1;
+            let path = spill_path(&self.files_dirs, id);
+            match on_disk::FileStorage::from_sorted(&mut *records, path, self.spill_format, policy)
+            {
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+                file => break file,
+            }
+        }
+    }
+
+    /// Put the records taken out of the merge back, e.g. as they could not be written into the
+    /// output. They must be sorted, and earlier than all the other buffered records.
+    fn restore_unwritten(&mut self, records: Vec<Sequenced>) -> std::io::Result<()> {
+        let Some(first) = records.first() else {
+            return Ok(());
+        };
+        self.earliest_buffered_timestamp = Some(first.timestamp());
+        match self.spill {
+            SpillPolicy::Spill => {
+                let file = self.write_spill_file(&mut records.into_iter().map(Ok))?;
+                self.files.push(file);
+            }
+            // they were taken out of the in-memory buffer, so they fit back in
+            SpillPolicy::NeverSpill => records
+                .into_iter()
+                .for_each(|record| self.in_memory.push_sequenced(record)),
+        }
+        Ok(())
+    }
+
+    /// Merge the smallest spill files together, so that there are at most `max_files` of them
+    /// (but at least one), e.g. while the input is idle.
+    ///
+    /// The next dumps merge fewer files then, each through a larger read buffer. Nothing is
+    /// written into the output, and the in-memory records stay there. The merged files are
+    /// deleted, unless they are kept (see [`Config::keep_spills`]).
+    pub fn compact(&mut self, max_files: usize) -> std::io::Result<()> {
+        let max_files = max_files.max(1);
+        if self.files.len() <= max_files {
+            return Ok(());
+        }
+
+        // each record is rewritten, so the largest files are left as they are
+        self.files.sort_by_key(|x| std::cmp::Reverse(x.len()));
+        let merged = self.files.split_off(max_files - 1);
+        let paths: Vec<_> = merged
+            .iter()
+            .filter_map(|x| x.path())
+            .map(Path::to_path_buf)
+            .collect();
+        let files_count = merged.len();
+        let readers = merged
+            .into_iter()
+            .map(|x| {
+                let capacity = self.read_buf_capacity(x.len(), files_count);
+                x.read(capacity)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut merge = KMerge::new(readers).with_strategy(self.merge_strategy);
+        let file = self.write_spill_file(&mut merge.sequenced())?;
+        self.stats.bytes_merged += merge.bytes_consumed();
+        self.stats.bytes_spilled += file.bytes_written();
+        self.stats.records_spilled += file.len() as u64;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            files = files_count,
+            records = file.len(),
+            path = ?file.path(),
+            "compacted spill files"
+        );
+        self.files.push(file);
+
+        for path in paths {
+            self.files_ids.remove(&path);
+            if !self.keep_spills {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Dump the records that are safe to dump. It could as well be none!
+    ///
+    /// The safe timestamp is shifted earlier by [`Config::lateness_slack`], and is exclusive with
+    /// [`Config::exclusive_watermark`]. Spill files starting past it are left out of the merge,
+    /// see [`FileStorage::front_timestamp`].
+    ///
+    /// If writing into the output fails, the records not written yet stay buffered, so that the
+    /// dump could be retried after [`Buffer::swap_writer`]. The output could end with a part of a
+    /// record then, so the dumps fail with [`Error::OutputFailed`] until the writer is swapped.
+    pub fn dump_safe(&mut self, safe_to_dump_timestamp: Timestamp) -> std::io::Result<DumpedCount> {
+        let bound = self.safe_bound(safe_to_dump_timestamp);
+        self.dump_up_to(bound, None, None, None)
+    }
+
+    /// Same as [`Buffer::dump_safe`], but dumps no more than `max_records`, keeping the rest
+    /// buffered for the next dump.
+    ///
+    /// Records written by [`Config::pass_through_ordered`] are reported by the next dump all at
+    /// once, so they could exceed the limit. A dump of fewer records than the limit means that no
+    /// more records are safe to dump.
+    pub fn dump_safe_at_most(
+        &mut self,
+        safe_to_dump_timestamp: Timestamp,
+        max_records: NonZero<usize>,
+    ) -> std::io::Result<DumpedCount> {
+        let bound = self.safe_bound(safe_to_dump_timestamp);
+        self.dump_up_to(bound, None, Some(max_records.get()), None)
+    }
+
+    /// Same as [`Buffer::dump_safe`], but stops early once `cancel` is set.
+    ///
+    /// The flag is checked before each record. On cancellation, the records dumped so far are
+    /// flushed and the rest stay buffered, so that the next dump resumes from the first record
+    /// not dumped. [`DumpedCount::cancelled`] tells whether it happened.
+    pub fn dump_safe_cancellable(
+        &mut self,
+        safe_to_dump_timestamp: Timestamp,
+        cancel: &AtomicBool,
+    ) -> std::io::Result<DumpedCount> {
+        let bound = self.safe_bound(safe_to_dump_timestamp);
+        self.dump_up_to(bound, Some(cancel), None, None)
+    }
+
+    /// Same as [`Buffer::dump_safe`], but the safe timestamp is read from `watermark`, which is
+    /// read again whenever the merge reaches the previous one, so that a single dump goes on as
+    /// long as the watermark keeps advancing, e.g. when it's updated by other threads.
+    ///
+    /// The watermark must never go back. All the spill files are merged, as any of them could
+    /// become safe to dump, see [`FileStorage::front_timestamp`].
+    pub fn dump_safe_dynamic(
+        &mut self,
+        watermark: &dyn Fn() -> Timestamp,
+    ) -> std::io::Result<DumpedCount> {
+        let bound = self.safe_bound(watermark());
+        self.dump_up_to(bound, None, None, Some(watermark))
+    }
+
+    /// Dump all the buffered records, regardless of their timestamps.
+    pub fn flush_all(&mut self) -> std::io::Result<DumpedCount> {
+        self.dump_up_to(Bound::Unbounded, None, None, None)
+    }
+
+    /// Same as [`Buffer::flush_all`], but dumps no more than `max_records`, same as
+    /// [`Buffer::dump_safe_at_most`] does.
+    pub fn flush_all_at_most(
+        &mut self,
+        max_records: NonZero<usize>,
+    ) -> std::io::Result<DumpedCount> {
+        self.dump_up_to(Bound::Unbounded, None, Some(max_records.get()), None)
+    }
+
+    /// Same as [`Buffer::flush_all`], but collects the records into a vector instead of dumping
+    /// them into the output, e.g. for tests or small jobs.
+    ///
+    /// The merged spill files are deleted, unless they are kept for reuse (see
+    /// [`Config::spill_file_pool`]) or for inspection (see [`Config::keep_spills`]). Records
+    /// written by [`Config::pass_through_ordered`] are in the output already, so they are not
+    /// collected, and are still reported by the next dump.
+    pub fn drain_sorted_vec(&mut self) -> std::io::Result<Vec<Record>> {
+        if self.spill == SpillPolicy::Spill {
+            // to know all the files merged
+            self.dump_in_memory(0)?;
+        }
+        let spilled: Vec<_> = self
+            .files
+            .iter()
+            .filter_map(|x| x.path())
+            .map(Path::to_path_buf)
+            .collect();
+        let mut records = Vec::new();
+        self.dump_into(&mut records, Bound::Unbounded)?;
+
+        for path in spilled.into_iter().filter(|_| !self.keep_spills) {
+            if !self.files_pool.iter().any(|(_, x)| x.path() == Some(&path)) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(records)
+    }
+
+    /// Same as [`Buffer::dump_safe`], but pushes the records into `records` instead of writing
+    /// them into the output, e.g. for a consumer in the same process, sparing the serialisation.
+    ///
+    /// Records written by [`Config::pass_through_ordered`] are in the output already, so they are
+    /// neither pushed nor reported, but reported by the next dump into the output.
+    pub fn dump_safe_into(
+        &mut self,
+        safe_to_dump_timestamp: Timestamp,
+        records: &mut Vec<Record>,
+    ) -> std::io::Result<DumpedCount> {
+        let bound = self.safe_bound(safe_to_dump_timestamp);
+        self.dump_into(records, bound)
+    }
+
+    /// Dump up to the bound into the vector, which the output is temporarily replaced with
+    fn dump_into(
+        &mut self,
+        records: &mut Vec<Record>,
+        bound: Bound<Timestamp>,
+    ) -> std::io::Result<DumpedCount> {
+        let passed_through = std::mem::take(&mut self.passed_through);
+        let collect = Output::Collect(std::mem::take(records));
+        let output = std::mem::replace(&mut self.output, collect);
+        let dumped = self.dump_up_to(bound, None, None, None);
+        let Output::Collect(collected) = std::mem::replace(&mut self.output, output) else {
+            unreachable!("the output is only replaced here");
+        };
+        *records = collected;
+        self.passed_through = passed_through;
+        dumped
+    }
+
+    /// Snapshot all the buffered records, both in memory and on disk, in sorted order, e.g. for
+    /// diagnostics. Nothing is consumed, so the buffer is left exactly as it was.
+    ///
+    /// Records written by [`Config::pass_through_ordered`] are in the output already, so they are
+    /// not included. The spill files are read whole, so it's not meant for the hot path, and it's
+    /// not available with the `minimal-memory` feature.
+    #[cfg(not(feature = "minimal-memory"))]
+    pub fn peek_all(&self) -> std::io::Result<Vec<Record>> {
+        let mut records = self.in_memory.clone_records();
+        for file in &self.files {
+            records.extend(file.peek_records(self.file_read_buf_capacity)?);
+        }
+        // the window keeps the records with equal timestamps in the order they were pushed
+        records.extend(
+            self.window
+                .iter()
+                .flat_map(in_memory::Window::iter)
+                .map(|x| Sequenced {
+                    seq: u64::MAX,
+                    record: x.record.clone(),
+                    source: x.source,
+                }),
+        );
+        // the spill files are sorted runs already, which the stable sort merges
+        records.sort();
+        Ok(records.into_iter().map(|x| x.record).collect())
+    }
+
+    /// Bound of the records safe to dump, see [`Config::lateness_slack`] and
+    /// [`Config::exclusive_watermark`]
+    fn safe_bound(&self, ts: Timestamp) -> Bound<Timestamp> {
+        let ts = Timestamp(ts.0.saturating_sub(self.lateness_slack.0));
+        if self.exclusive_watermark {
+            Bound::Excluded(ts)
+        } else {
+            Bound::Included(ts)
+        }
+    }
+
+    /// Dump records up to the bound, but no more than `limit` (including the passed through ones).
+    ///
+    /// The bound is advanced by `watermark` once reached, see [`Buffer::dump_safe_dynamic`].
+    fn dump_up_to(
+        &mut self,
+        mut bound: Bound<Timestamp>,
+        cancel: Option<&AtomicBool>,
+        limit: Option<usize>,
+        watermark: Option<&dyn Fn() -> Timestamp>,
+    ) -> std::io::Result<DumpedCount> {
+        // records are only collected temporarily, so they are never in the way
+        if self.output_failed && !matches!(self.output, Output::Collect(_)) {
+            return Err(Error::OutputFailed.into());
+        }
+        // the window is sorted, so its records up to the bound are either written right away or
+        // merged along with the other buffered records
+        while let Some(record) = self.window.as_mut().and_then(|x| x.pop_within(bound)) {
+            self.release_from_window(record)?;
+        }
+        let has_something_to_dump = self
+            .earliest_buffered_timestamp
+            .map(|ts| (Bound::Unbounded, bound).contains(&ts))
+            .unwrap_or(false);
+        let mut passed_through = std::mem::take(&mut self.passed_through);
+        if !has_something_to_dump {
+            if passed_through.count > 0 {
+                let flushed = self.output.flush();
+                self.check_output(flushed)?;
+            }
+            passed_through.next_earliest = self.earliest_buffered_timestamp;
+            self.stats.records_dumped += passed_through.count as u64;
+            self.write_late();
+            return Ok(passed_through);
+        };
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "dump",
+            watermark = ?bound,
+            dumped = tracing::field::Empty
+        )
+        .entered();
+
+        // too few in-memory records are merged right from memory rather than spilled
+        let merge_in_memory = match self.spill {
+            SpillPolicy::Spill if self.in_memory.len() >= self.min_spill_records => {
+                self.dump_in_memory(0)?;
+                false
+            }
+            SpillPolicy::Spill | SpillPolicy::NeverSpill => true,
+        };
+
+        // files starting past the bound are left out of the merge, sparing the IO of reading them,
+        // unless the bound could advance during the merge
+        let mut untouched = Vec::new();
+        let mut untouched_front = None;
+        let mut files = std::mem::take(&mut self.files);
+        if bound != Bound::Unbounded && watermark.is_none() {
+            let mut fronts = Vec::with_capacity(files.len());
+            for file in &mut files {
+                match file.front_timestamp(self.file_read_buf_capacity) {
+                    Ok(front) => fronts.push(front),
+                    Err(err) => {
+                        self.files = files;
+                        return Err(err);
+                    }
+                }
+            }
+            let (merged, skipped): (Vec<_>, Vec<_>) =
+                files.into_iter().zip(fronts).partition(|(_, front)| {
+                    front.is_none_or(|front| (Bound::Unbounded, bound).contains(&front))
+                });
+            files = merged.into_iter().map(|(file, _)| file).collect();
+            untouched_front = skipped.iter().filter_map(|(_, front)| *front).min();
+            untouched = skipped.into_iter().map(|(file, _)| file).collect();
+        }
+        let files_count = files.len();
+        let readers: Vec<_> = files
+            .into_iter()
+            .map(|x| {
+                let capacity = self.read_buf_capacity(x.len(), files_count);
+                x.reader(capacity)
+            })
+            .collect();
+        let mut merge = KMerge::new(readers).with_strategy(self.merge_strategy);
+        if merge_in_memory {
+            merge = merge.with_heap(self.in_memory.take_heap());
+        }
+        let limit = limit.map(|limit| limit.saturating_sub(passed_through.count));
+        let mut dumped = DumpedCount::default();
+        let mut last_flush = self.clock.now();
+        // records are written one by one, unless chunked
+        let chunk_size = self.dump_chunk.map_or(1, NonZero::get);
+        let mut chunk = Vec::with_capacity(chunk_size);
+        // sequence numbers of the records in the chunk, to put them back if they aren't written
+        let mut chunk_seqs = Vec::with_capacity(chunk_size);
+        let mut chunk_sources = Vec::with_capacity(chunk_size);
+        let mut last_written = self.last_dumped_timestamp;
+        let result = 'merge: {
+            loop {
+                match merge.peek_timestamp() {
+                    None => {
+                        // all readers are empty
+                        self.earliest_buffered_timestamp = None;
+                        break;
+                    }
+                    Some(ts) if !(Bound::Unbounded, bound).contains(&ts) => {
+                        let advanced = watermark
+                            .map(|watermark| self.safe_bound(watermark()))
+                            .filter(|fresh| (Bound::Unbounded, *fresh).contains(&ts));
+                        match advanced {
+                            Some(fresh) => bound = fresh,
+                            None => {
+                                // we can no longer proceed with the merge sort
+                                self.earliest_buffered_timestamp = Some(ts);
+                                break;
+                            }
+                        }
+                    }
+                    Some(ts)
+                        if cancel.is_some_and(|cancel| cancel.load(atomic::Ordering::Relaxed)) =>
+                    {
+                        // readers are closed below, keeping the rest of the records buffered
+                        self.earliest_buffered_timestamp = Some(ts);
+                        dumped.cancelled = true;
+                        break;
+                    }
+                    Some(ts) if limit.is_some_and(|limit| dumped.count >= limit) => {
+                        self.earliest_buffered_timestamp = Some(ts);
+                        break;
+                    }
+                    Some(_) => {}
+                }
+
+                let Sequenced {
+                    record,
+                    seq,
+                    source,
+                } = match merge.next_sequenced().expect("must be due to peeking") {
+                    Ok(record) => record,
+                    Err(err) => break 'merge Err(err),
+                };
+                self.last_dumped_timestamp = Some(record.timestamp());
+                let Some(record) = self.map(record) else {
+                    last_written = self.last_dumped_timestamp;
+                    continue;
+                };
+                dumped.add(&record);
+                chunk.push(record);
+                chunk_seqs.push(seq);
+                chunk_sources.push(source);
+                if chunk.len() >= chunk_size {
+                    let written = self.output.write_chunk(&mut chunk, &chunk_sources);
+                    if let Err(err) = self.check_output(written) {
+                        break 'merge Err(err);
+                    }
+                    chunk_seqs.clear();
+                    chunk_sources.clear();
+                    last_written = self.last_dumped_timestamp;
+                    if self.dump_chunk.is_some() {
+                        let flushed = self.output.flush();
+                        if let Err(err) = self.check_output(flushed) {
+                            break 'merge Err(err);
+                        }
+                    }
+                }
+
+                if let Some(Progress { every, callback }) = self.progress {
+                    let count = dumped.count as u64;
+                    if count % every == 0 {
+                        callback(count);
+                    }
+                }
+
+                // records are written whole, so flushing between them is safe
+                if let Some(interval) = self.flush_interval {
+                    let now = self.clock.now();
+                    if now.duration_since(last_flush) >= interval {
+                        let flushed = self.output.flush();
+                        if let Err(err) = self.check_output(flushed) {
+                            break 'merge Err(err);
+                        }
+                        last_flush = now;
+                    }
+                }
+            }
+            let written = self.output.write_chunk(&mut chunk, &chunk_sources);
+            self.check_output(written)
+        };
+
+        dumped.merge = MergeReport {
+            readers: merge.readers_count(),
+            bytes_read: merge.bytes_consumed(),
+            passes: usize::from(merge.readers_count() > 0 || dumped.count > 0),
+        };
+        self.stats.bytes_merged += dumped.merge.bytes_read;
+        let next = merge.peek_timestamp();
+        // every storage is put back, even if some of them fail to close
+        let ((files, heap), closed) = merge.close_keeping();
+        let result = result.and(closed);
+        for file in files {
+            if !file.is_empty() {
+                self.files.push(file);
+            } else if let Some(id) = file.path().and_then(|x| self.files_ids.remove(x)) {
+                if self.files_pool.len() < self.files_pool_size {
+                    self.files_pool.push((id, file));
+                }
+            }
+        }
+        if merge_in_memory {
+            self.in_memory.restore_heap(heap);
+        }
+        self.files.extend(untouched);
+        if let Err(err) = result {
+            // the rest of the records stay buffered for the next dump, which reports the passed
+            // through ones as well
+            let seqs = &chunk_seqs[chunk_seqs.len() - chunk.len()..];
+            let sources = &chunk_sources[chunk_sources.len() - chunk.len()..];
+            let unwritten = (chunk.into_iter().zip(seqs).zip(sources))
+                .map(|((record, &seq), &source)| Sequenced {
+                    seq,
+                    record,
+                    source,
+                })
+                .collect();
+            self.earliest_buffered_timestamp = (next.into_iter().chain(untouched_front))
+                .chain(self.window_front())
+                .min();
+            self.restore_unwritten(unwritten)?;
+            self.last_dumped_timestamp = last_written;
+            self.passed_through = passed_through;
+            return Err(err);
+        }
+        self.earliest_buffered_timestamp = (self.earliest_buffered_timestamp.into_iter())
+            .chain(untouched_front)
+            .chain(self.window_front())
+            .min();
+        let flushed = self.output.flush();
+        self.check_output(flushed)?;
+        if bound != Bound::Unbounded {
+            // flushing everything says nothing about the records to come
+            self.dumped_bound = Some(bound);
+        }
+        dumped.next_earliest = self.earliest_buffered_timestamp;
+        dumped.prepend(passed_through);
+        self.stats.records_dumped += dumped.count as u64;
+        // the records are dumped regardless, so a failure is only counted, see `Buffer::finish`
+        self.write_late();
+
+        #[cfg(feature = "tracing")]
+        span.record("dumped", dumped.count);
+
+        Ok(dumped)
+    }
+}
+
+/// Path of the spill file with the id, the directories are taken in turns
+fn spill_path(dirs: &[PathBuf], id: usize) -> PathBuf {
+    dirs[id % dirs.len()].join(format!("dump-{id}"))
+}
+
+/// What happened to a record pushed with [`Buffer::push_record`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// The record is buffered in memory
+    Buffered,
+    /// The record is too old and dropped, see [`Config::drop_older_than`]
+    Dropped,
+    /// The record is written into the output right away, see [`Config::pass_through_ordered`]
+    PassedThrough,
+    /// The record is late, so it is diverted into another file, see [`Config::late_output`]
+    DivertedLate,
+    /// The record filled the in-memory buffer, so it was spilled on disk
+    Spilled {
+        /// Id of the spill file, i.e. `dump-{file_id}` in the buffer directory
+        file_id: usize,
+        /// Number of records spilled
+        records: usize,
+    },
+}
+
+/// The number of dumped records
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DumpedCount {
+    pub count: usize,
+    /// Counts of each kind of the dumped records, indexed by [`RecordKind`]
+    pub per_kind: [usize; 5],
+    /// Timestamps of the first and the last dumped records, [`None`] if nothing is dumped
+    pub range: Option<RangeInclusive<Timestamp>>,
+    /// Whether the dump was cancelled, see [`Buffer::dump_safe_cancellable`]
+    pub cancelled: bool,
+    /// How much work the merge took
+    pub merge: MergeReport,
+    /// Earliest timestamp left buffered after the dump, see
+    /// [`Buffer::earliest_buffered_timestamp`].
+    ///
+    /// The next dump makes no progress until the safe timestamp reaches it, unless earlier records
+    /// are pushed meanwhile. [`None`] if nothing is left buffered.
+    pub next_earliest: Option<Timestamp>,
+}
+
+/// Work done by the merge of a single dump, see [`DumpedCount::merge`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Number of on-disk buffers merged, including those that were not read to the end
+    pub readers: usize,
+    /// Number of bytes of the dumped records read from the on-disk buffers
+    pub bytes_read: u64,
+    /// Number of merge passes over the buffers.
+    ///
+    /// A dump merges all of the buffers (on-disk and in-memory) at once, however many there are,
+    /// so it is a flag in fact: `1` if anything was merged, even the in-memory records only, and
+    /// `0` otherwise. Unlike [`SortPlan::merge_passes`], it doesn't tell whether anything was
+    /// spilled, see [`MergeReport::readers`] for that.
+    pub passes: usize,
+}
+
+impl DumpedCount {
+    /// The number of dumped records of the given kind
+    pub fn of_kind(&self, kind: RecordKind) -> usize {
+        self.per_kind[kind as usize]
+    }
+
+    /// Account for the records dumped before these ones
+    fn prepend(&mut self, earlier: DumpedCount) {
+        self.count += earlier.count;
+        for (count, earlier) in self.per_kind.iter_mut().zip(earlier.per_kind) {
+            *count += earlier;
+        }
+        self.range = match (earlier.range, self.range.take()) {
+            (Some(earlier), Some(range)) => Some(*earlier.start()..=*range.end()),
+            (earlier, range) => earlier.or(range),
+        };
+    }
+
+    fn add(&mut self, record: &Record) {
+        let ts = record.timestamp();
+        self.count += 1;
+        self.per_kind[record.kind() as usize] += 1;
+        self.range = Some(self.range.as_ref().map_or(ts, |x| *x.start())..=ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, record};
+    use assert_matches::assert_matches;
+    use rand::prelude::IteratorRandom;
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    #[cfg(test)]
+    mod storage {
+        use super::*;
+        use std::borrow::Cow;
+
+        fn in_memory_factory() -> in_memory::Buffer {
+            let mut buffer = in_memory::Buffer::with_capacity(256);
+
+            buffer.push(
+                Record::A(DataA {
+                    timestamp: Timestamp(5),
+                    foo: "foo".into(),
+                }),
+                0,
+            );
+            buffer.push(
+                Record::C(DataC {
+                    timestamp: Timestamp(2),
+                    baz: (1, 2),
+                }),
+                0,
+            );
+            buffer.push(
+                Record::E(DataE {
+                    timestamp: Timestamp(10),
+                    def: vec![3, 1, 2],
+                }),
+                0,
+            );
+
+            buffer
+        }
+
+        #[test]
+        fn in_memory_len_and_capacity() {
+            let mut buffer = in_memory::Buffer::with_capacity(2);
+            assert!(buffer.is_empty());
+            assert_eq!(buffer.capacity(), 2);
+
+            for ts in 0..buffer.capacity() {
+                assert!(!buffer.is_full());
+                buffer.push(record(ts as u128), 0);
+                assert!(!buffer.is_empty());
+            }
+            assert!(buffer.is_full());
+            assert_eq!(buffer.len(), buffer.capacity());
+
+            // the heap could be reallocated with a larger capacity, e.g. when it is restored
+            let mut heap = buffer.take_heap();
+            heap.reserve(100);
+            buffer.restore_heap(heap);
+            assert!(buffer.is_full());
+        }
+
+        #[test]
+        fn static_strings_are_not_allocated_in_memory() -> std::io::Result<()> {
+            let mut in_memory = in_memory_factory();
+            assert!(in_memory.iter().any(|record| matches!(
+                record,
+                Record::A(DataA {
+                    foo: Cow::Borrowed("foo"),
+                    ..
+                })
+            )));
+
+            let (_dir, path) = test_support::temp_output()?;
+            let reader = in_memory
+                .drain_into_file(&path, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty")
+                .read(8_192)?;
+            let records = KMerge::new(vec![reader]).collect::<std::io::Result<Vec<_>>>()?;
+            assert!(records.iter().any(|x| matches!(
+                x,
+                Record::A(DataA { foo: Cow::Owned(foo), .. }) if foo == "foo"
+            )));
+
+            Ok(())
+        }
+
+        #[test]
+        fn truncated_file_is_reported() -> std::io::Result<()> {
+            // `seq` (8), variant (4), timestamp (16) and source (4)
+            const RECORD_LEN: u64 = 32;
+            let (_dir, tmp) = test_support::temp_output()?;
+            let open = |len| {
+                // spill files are not overwritten
+                if tmp.exists() {
+                    std::fs::remove_file(&tmp)?;
+                }
+                let mut in_memory = in_memory::Buffer::with_capacity(3);
+                for ts in 0..3 {
+                    in_memory.push(record(ts), 0);
+                }
+                let file = in_memory
+                    .drain_into_file(&tmp, IntEncoding::default(), SerErrorPolicy::default())?
+                    .expect("in-memory isn't empty");
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&tmp)?
+                    .set_len(len)?;
+                file.read(8_192)
+            };
+            let full_len = codec::FORMAT_HEADER_LEN as u64 + 3 * RECORD_LEN;
+
+            let mut reader = open(full_len - RECORD_LEN)?;
+            reader.pop()?;
+            reader.pop()?;
+            let err = reader.pop().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            assert_matches!(output::Error::downcast(&err), Some(output::Error::Eof));
+
+            let mut reader = open(full_len - 5)?;
+            reader.pop()?;
+            reader.pop()?;
+            let err = reader.pop().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            assert_matches!(
+                output::Error::downcast(&err),
+                Some(output::Error::TruncatedRecord { read: 27 })
+            );
+
+            Ok(())
+        }
+
+        #[test]
+        fn keep_popped_record_on_error_of_next_one() -> std::io::Result<()> {
+            // `seq` (8), variant (4), timestamp (16) and source (4)
+            const RECORD_LEN: usize = 32;
+            let (_dir, tmp) = test_support::temp_output()?;
+            let mut in_memory = in_memory::Buffer::with_capacity(3);
+            for ts in 0..3 {
+                in_memory.push(record(ts), 0);
+            }
+            let file = in_memory
+                .drain_into_file(&tmp, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+            let bytes = std::fs::read(&tmp)?;
+            // the second record is cut off, e.g. not written completely yet
+            let second = codec::FORMAT_HEADER_LEN + RECORD_LEN;
+            std::fs::write(&tmp, &bytes[..second + 4])?;
+
+            let mut reader = file.read(8_192)?;
+            assert_eq!(reader.pop()?.unwrap().timestamp(), Timestamp(0));
+            assert_eq!(reader.remaining(), 2);
+            assert_eq!(reader.position(), second as u64);
+            let err = reader.pop().unwrap_err();
+            assert_matches!(
+                output::Error::downcast(&err),
+                Some(output::Error::TruncatedRecord { read: 4 })
+            );
+            assert_eq!(reader.remaining(), 2);
+            assert_eq!(reader.position(), second as u64);
+            assert!(reader.pop().is_err());
+
+            // the record is read again from its start
+            std::fs::write(&tmp, &bytes)?;
+            assert_eq!(reader.pop()?.unwrap().timestamp(), Timestamp(1));
+            let file = reader.close()?;
+            assert_eq!(file.len(), 1);
+
+            // closed after an error, the storage resumes from the failed record
+            let third = second + RECORD_LEN;
+            std::fs::write(&tmp, &bytes[..third + 4])?;
+            let mut reader = file.read(8_192)?;
+            assert!(reader.pop().is_err());
+            let file = reader.close()?;
+            assert_eq!(file.len(), 1);
+            std::fs::write(&tmp, &bytes)?;
+            let mut reader = file.read(8_192)?;
+            assert_eq!(reader.pop()?.unwrap().timestamp(), Timestamp(2));
+            assert!(reader.pop()?.is_none());
+
+            Ok(())
+        }
+
+        #[test]
+        fn peek_timestamp_without_deserialising() -> std::io::Result<()> {
+            let (_dir, tmp) = test_support::temp_output()?;
+            let mut in_memory = in_memory::Buffer::with_capacity(2);
+            for ts in [7, 3] {
+                in_memory.push(
+                    Record::E(DataE {
+                        timestamp: Timestamp(ts),
+                        def: vec![1; 100],
+                    }),
+                    0,
+                );
+            }
+            let file = in_memory
+                .drain_into_file(&tmp, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+            // cut the data of the last record, keeping its timestamp
+            let len = std::fs::metadata(&tmp)?.len();
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&tmp)?
+                .set_len(len - 10)?;
+
+            let mut reader = file.read(8_192)?;
+            assert_eq!(reader.peek_timestamp(), Some(Timestamp(3)));
+            assert_eq!(reader.bytes_consumed(), 0);
+            reader.pop()?;
+            assert_eq!(reader.peek_timestamp(), Some(Timestamp(7)));
+            let err = reader.pop().unwrap_err();
+            assert_matches!(
+                output::Error::downcast(&err),
+                Some(output::Error::TruncatedRecord { .. })
+            );
+
+            Ok(())
+        }
+
+        #[test]
+        fn position_advances_by_record_size() -> std::io::Result<()> {
+            let (_dir, tmp) = test_support::temp_output()?;
+            let mut reader = in_memory_factory()
+                .drain_into_file(&tmp, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty")
+                .read(8_192)?;
+
+            assert_eq!(reader.position(), codec::FORMAT_HEADER_LEN as u64);
+            let mut serialized = Vec::new();
+            loop {
+                let position = reader.position();
+                let Some(record) = reader.pop()? else {
+                    break;
+                };
+                serialized.clear();
+                IntEncoding::default().serialize_into(&mut serialized, &record)?;
+                assert_eq!(reader.position(), position + serialized.len() as u64);
+            }
+            assert_eq!(reader.position(), std::fs::metadata(&tmp)?.len());
+
+            Ok(())
+        }
+
+        #[test]
+        fn verify_sorted_storage() -> std::io::Result<()> {
+            // `seq` (8), variant (4), timestamp (16) and source (4)
+            const RECORD_LEN: usize = 32;
+            let (_dir, tmp) = test_support::temp_output()?;
+            let mut in_memory = in_memory::Buffer::with_capacity(3);
+            for ts in [5, 2, 10] {
+                in_memory.push(record(ts), 0);
+            }
+            let file = in_memory
+                .drain_into_file(&tmp, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+            assert!(file.verify_sorted(16)?);
+
+            // Overwrite the last record with the first one
+            let mut bytes = std::fs::read(&tmp)?;
+            let first = codec::FORMAT_HEADER_LEN;
+            bytes.copy_within(first..first + RECORD_LEN, first + 2 * RECORD_LEN);
+            std::fs::write(&tmp, bytes)?;
+            assert!(!file.verify_sorted(16)?);
+
+            // Verification doesn't move the reading position
+            let mut reader = file.read(8_192)?;
+            assert_eq!(reader.last()?.unwrap().timestamp(), Timestamp(2));
+            reader.read_next()?;
+            assert_eq!(reader.last()?.unwrap().timestamp(), Timestamp(5));
+
+            Ok(())
+        }
+
+        #[test]
+        fn dump_in_memory_and_read_from_disk() -> std::io::Result<()> {
+            let mut in_memory = in_memory_factory();
+            let (_dir, tmp) = test_support::temp_output()?;
+
+            let file = in_memory
+                .drain_into_file(&tmp, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+            codec::read_format_header(File::open(&tmp)?)?;
+            assert_eq!(file.records_consumed(), 0);
+            let mut reader = file.read(8_192)?;
+
+            assert!(in_memory.is_empty());
+            assert_eq!(reader.last()?.unwrap().timestamp(), Timestamp(2));
+            assert_eq!(reader.remaining(), 3);
+
+            reader.read_next()?;
+            assert_eq!(reader.last()?.unwrap().timestamp(), Timestamp(5));
+            assert_eq!(reader.remaining(), 2);
+
+            reader.read_next()?;
+            assert_eq!(reader.last()?.unwrap().timestamp(), Timestamp(10));
+
+            reader.read_next()?;
+            assert!(reader.last()?.is_none());
+            assert_eq!(reader.remaining(), 0);
+
+            let file = reader.close()?;
+            assert!(file.is_empty());
+            assert_eq!(file.records_consumed(), 3);
+
+            Ok(())
+        }
+
+        #[test]
+        fn front_timestamp_without_reader() -> std::io::Result<()> {
+            let mut in_memory = in_memory_factory();
+            let (_dir, path) = test_support::temp_output()?;
+            in_memory
+                .drain_into_file(&path, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+
+            // nothing is cached for a file opened anew
+            let mut storage = FileStorage::open(&path, 3, IntEncoding::default())?;
+            assert_eq!(storage.front_timestamp(64)?, Some(Timestamp(2)));
+            assert_eq!(storage.front_timestamp(64)?, Some(Timestamp(2)));
+
+            let mut reader = storage.read(8_192)?;
+            assert_eq!(reader.pop()?.unwrap().timestamp(), Timestamp(2));
+            let mut storage = reader.close()?;
+            assert_eq!(storage.front_timestamp(64)?, Some(Timestamp(5)));
+
+            let mut reader = storage.read(8_192)?;
+            assert_eq!(reader.pop()?.unwrap().timestamp(), Timestamp(5));
+            assert_eq!(reader.pop()?.unwrap().timestamp(), Timestamp(10));
+            let mut storage = reader.close()?;
+            assert_eq!(storage.front_timestamp(64)?, None);
+
+            Ok(())
+        }
+
+        #[test]
+        fn reopen_spill_file() -> std::io::Result<()> {
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("dump-0");
+            let storage = in_memory_factory()
+                .drain_into_file(&path, IntEncoding::Varint, SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+            let len = storage.len();
+            // e.g. the process is restarted
+            drop(storage);
+
+            let storage = FileStorage::open(&path, len, IntEncoding::Varint)?;
+            assert_eq!((storage.len(), storage.records_consumed()), (3, 0));
+            assert_eq!(storage.path(), Some(path.as_path()));
+            let mut reader = storage.read(8_192)?;
+            for ts in [2, 5, 10] {
+                assert_eq!(reader.pop()?.unwrap().timestamp(), Timestamp(ts));
+            }
+            assert!(reader.pop()?.is_none());
+
+            Ok(())
+        }
+
+        #[test]
+        fn reading_same_record_from_disk_repeatedly() -> std::io::Result<()> {
+            let mut in_memory = in_memory_factory();
+            let (_dir, path) = test_support::temp_output()?;
+
+            let mut file = in_memory
+                .drain_into_file(&path, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+
+            for _ in 0..5 {
+                let mut reader = file.read(8_192)?;
+                assert_eq!(
+                    reader
+                        .last()?
+                        .expect("we never call `read_next`")
+                        .timestamp(),
+                    Timestamp(2)
+                );
+                file = reader.close()?;
+            }
+
+            let mut reader = file.read(8_192)?;
+            reader.read_next()?;
+            reader.read_next()?;
+            reader.read_next()?;
+            assert!(reader.last()?.is_none());
+
+            Ok(())
+        }
+
+        #[cfg(feature = "mmap")]
+        #[test]
+        fn resume_reading_with_mmap() -> std::io::Result<()> {
+            let mut in_memory = in_memory_factory();
+            let (_dir, path) = test_support::temp_output()?;
+
+            let file = in_memory
+                .drain_into_file(&path, IntEncoding::default(), SerErrorPolicy::default())?
+                .expect("in-memory isn't empty");
+
+            let mut reader = file.read_mmap()?;
+            assert_eq!(reader.last().unwrap().timestamp(), Timestamp(2));
+            reader.read_next()?;
+            let file = reader.close()?;
+            assert_eq!(file.records_consumed(), 1);
+
+            // resumes from the last unconsumed record, with either of the readers
+            let reader = file.read_mmap()?;
+            assert_eq!(reader.last().unwrap().timestamp(), Timestamp(5));
+            let file = reader.close()?;
+            let mut reader = file.read(8_192)?;
+            assert_eq!(reader.last()?.unwrap().timestamp(), Timestamp(5));
+            reader.read_next()?;
+            let file = reader.close()?;
+
+            let mut reader = file.read_mmap()?;
+            assert_eq!(reader.last().unwrap().timestamp(), Timestamp(10));
+            reader.read_next()?;
+            assert!(reader.last().is_none());
+            assert!(reader.close()?.is_empty());
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn merge_files_and_heap_and_resume() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let heap_of = |timestamps: &[u128]| {
+            timestamps
+                .iter()
+                .map(|ts| {
+                    Reverse(Sequenced {
+                        seq: 0,
+                        record: record(*ts),
+                        source: 0,
+                    })
+                })
+                .collect::<BinaryHeap<_>>()
+        };
+
+        let files = [&[1, 4, 7][..], &[2, 5, 8]]
+            .into_iter()
+            .enumerate()
+            .map(|(i, timestamps)| {
+                FileStorage::new(
+                    &mut heap_of(timestamps),
+                    dir.path().join(format!("{i}")),
+                    IntEncoding::Varint,
+                    SerErrorPolicy::default(),
+                )
+                .map(|x| x.expect("heap isn't empty"))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let readers = files
+            .into_iter()
+            .map(|x| x.read(8_192))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut merge = KMerge::new(readers).with_heap(heap_of(&[3, 6, 9]));
+        let timestamps = merge
+            .by_ref()
+            .take(4)
+            .map(|x| x.map(|record| record.timestamp().0))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(timestamps, [1, 2, 3, 4]);
+        assert_eq!(merge.peek_timestamp(), Some(Timestamp(5)));
+        assert_eq!(merge.peek()?.map(Record::timestamp), Some(Timestamp(5)));
+
+        let (files, heap) = merge.close()?;
+        let readers = files
+            .into_iter()
+            .map(|x| x.read(8_192))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let timestamps = KMerge::new(readers)
+            .with_heap(heap)
+            .map(|x| x.map(|record| record.timestamp().0))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(timestamps, [5, 6, 7, 8, 9]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_and_refill_storages_in_memory() -> std::io::Result<()> {
+        let heap_of = |timestamps: &[u128]| {
+            timestamps
+                .iter()
+                .map(|ts| {
+                    Reverse(Sequenced {
+                        seq: 0,
+                        record: Record::B(DataB {
+                            timestamp: Timestamp(*ts),
+                            bar: false,
+                        }),
+                        source: 0,
+                    })
+                })
+                .collect::<BinaryHeap<_>>()
+        };
+        let storage = |timestamps: &[u128]| {
+            FileStorage::from_backing(
+                &mut heap_of(timestamps),
+                Backing::memory(),
+                IntEncoding::default(),
+                SerErrorPolicy::default(),
+            )
+            .map(|x| x.expect("heap isn't empty"))
+        };
+        let timestamps = |merge: &mut KMerge, n| {
+            merge
+                .by_ref()
+                .take(n)
+                .map(|x| x.map(|record| record.timestamp().0))
+                .collect::<std::io::Result<Vec<_>>>()
+        };
+
+        let readers = vec![storage(&[1, 4, 7])?.read(16)?, storage(&[2, 5])?.read(16)?];
+        let mut merge = KMerge::new(readers).with_strategy(MergeStrategy::LoserTree);
+        assert_eq!(timestamps(&mut merge, 3)?, [1, 2, 4]);
+
+        let (files, _) = merge.close_all()?;
+        let mut merge = KMerge::new(
+            files
+                .into_iter()
+                .map(|x| x.read(16))
+                .collect::<std::io::Result<_>>()?,
+        );
+        assert_eq!(timestamps(&mut merge, 1)?, [5]);
+        let (mut files, _) = merge.close_all()?;
+        assert_eq!(files[1].len(), 0);
+
+        // the emptied storage is rewritten in place
+        let refilled = files
+            .pop()
+            .expect("two files")
+            .refill(
+                &mut heap_of(&[6, 8]),
+                IntEncoding::default(),
+                SerErrorPolicy::default(),
+            )?
+            .expect("heap isn't empty");
+        assert_eq!(refilled.records_consumed(), 0);
+        files.push(refilled);
+        let mut merge = KMerge::new(
+            files
+                .into_iter()
+                .map(|x| x.read(16))
+                .collect::<std::io::Result<_>>()?,
+        );
+        assert_eq!(timestamps(&mut merge, 10)?, [6, 7, 8]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_strategies_yield_same_order() -> std::io::Result<()> {
+        // timestamps and sequence numbers collide across files, so ties are broken by file
+        let files = |strategy: MergeStrategy| {
+            let readers = (0..37_u32)
+                .map(|i| {
+                    let mut heap = (0..i % 5 * 3)
+                        .map(|j| {
+                            Reverse(Sequenced {
+                                seq: u64::from(j % 2),
+                                record: Record::C(DataC {
+                                    timestamp: Timestamp(u128::from((i * 7 + j * 13) % 20)),
+                                    baz: (i, j),
+                                }),
+                                source: 0,
+                            })
+                        })
+                        .collect();
+                    // merged in memory, the files don't matter here
+                    match FileStorage::from_backing(
+                        &mut heap,
+                        Backing::memory(),
+                        IntEncoding::default(),
+                        SerErrorPolicy::default(),
+                    )? {
+                        Some(file) => file.read(64).map(Some),
+                        None => Ok(None),
+                    }
+                })
+                .filter_map(Result::transpose)
+                .collect::<std::io::Result<Vec<_>>>()?;
+            KMerge::new(readers)
+                .with_strategy(strategy)
+                .collect::<std::io::Result<Vec<_>>>()
+        };
+
+        let scanned = files(MergeStrategy::Scan)?;
+        assert_eq!(scanned.len(), 213);
+        assert!(scanned.is_sorted());
+        assert_eq!(files(MergeStrategy::LoserTree)?, scanned);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_two_file_storages() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let heap_of = |timestamps: &[u128]| {
+            timestamps
+                .iter()
+                .map(|ts| {
+                    Reverse(Sequenced {
+                        seq: 0,
+                        record: Record::A(DataA {
+                            timestamp: Timestamp(*ts),
+                            foo: format!("{ts}").into(),
+                        }),
+                        source: 0,
+                    })
+                })
+                .collect::<BinaryHeap<_>>()
+        };
+
+        let a = FileStorage::new(
+            &mut heap_of(&[1, 3, 5]),
+            dir.path().join("a"),
+            IntEncoding::Varint,
+            SerErrorPolicy::default(),
+        )?
+        .expect("not empty");
+        let b = FileStorage::new(
+            &mut heap_of(&[2, 4]),
+            dir.path().join("b"),
+            IntEncoding::Fixint,
+            SerErrorPolicy::default(),
+        )?
+        .expect("not empty");
+        let merged = a.merge_with(b, dir.path().join("merged"), 1_024)?;
+
+        assert_eq!(merged.len(), 5);
+        assert!(!dir.path().join("a").exists());
+        assert!(!dir.path().join("b").exists());
+        let timestamps = KMerge::new(vec![merged.read(1_024)?])
+            .map(|x| x.map(|record| record.timestamp().0))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(timestamps, [1, 2, 3, 4, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn process_a_few_records_in_buffer() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut reader = output::Reader::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(10))?;
+
+        sut.push_record(Record::A(DataA {
+            timestamp: Timestamp(5),
+            foo: "foo".into(),
+        }))?;
+        sut.push_record(Record::A(DataA {
+            timestamp: Timestamp(1),
+            foo: "foo".into(),
+        }))?;
+        let outcome = sut.push_record(Record::B(DataB {
+            timestamp: Timestamp(3),
+            bar: false,
+        }))?;
+        assert_eq!(outcome, PushOutcome::Buffered);
+
+        let dumped = sut.dump_safe(Timestamp(10))?;
+        assert_eq!(dumped.count, 3);
+        assert_eq!(dumped.per_kind, [2, 1, 0, 0, 0]);
+        assert_eq!(dumped.of_kind(RecordKind::B), 1);
+        assert_eq!(dumped.range, Some(Timestamp(1)..=Timestamp(5)));
+
+        assert_eq!(reader.read().unwrap().timestamp(), Timestamp(1));
+        assert_eq!(reader.read().unwrap().timestamp(), Timestamp(3));
+        assert_eq!(reader.read().unwrap().timestamp(), Timestamp(5));
+        let _ = reader.read().unwrap_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn push_reports_spills() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(2))?;
+
+        let spill_at = sut.in_memory.capacity();
+        for _ in 1..spill_at {
+            assert_eq!(sut.push_record(record(0))?, PushOutcome::Buffered);
+        }
+        assert_eq!(
+            sut.push_record(record(0))?,
+            PushOutcome::Spilled {
+                file_id: 0,
+                records: spill_at
+            }
+        );
+        assert!(dir.path().join("dump-0").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn push_records_by_reference_into_spill_files() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut reader = output::Reader::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, Config::default())?;
+
+        // appended while in order
+        for ts in [1, 3, 5] {
+            sut.push_record_ref(&record(ts))?;
+        }
+        assert_eq!(sut.files.len(), 1);
+        sut.push_record_ref(&record(2))?;
+        assert_eq!(sut.files.len(), 2);
+        sut.push_record(record(4))?;
+        assert_eq!(sut.in_memory.len(), 1);
+        assert_eq!(sut.len(), 5);
+        assert_eq!(sut.stats().records_spilled, 4);
+
+        assert_eq!(sut.dump_safe(Timestamp(10))?.count, 5);
+        for ts in 1..=5 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+        drop(sut);
+
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                spill: SpillPolicy::NeverSpill,
+                ..Default::default()
+            },
+        )?;
+        let err = sut.push_record_ref(&record(1)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lateness_slack_holds_back_records() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut reader = output::Reader::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                max_in_memory: 10,
+                lateness_slack: Timestamp(5),
+                ..Default::default()
+            },
+        )?;
+
+        sut.push_record(record(10))?;
+        sut.push_record(record(12))?;
+        assert_eq!(sut.dump_safe(Timestamp(14))?.count, 0);
+
+        // within the slack
+        sut.push_record(record(8))?;
+        assert_eq!(sut.dump_safe(Timestamp(16))?.count, 2);
+        assert_eq!(reader.read()?.timestamp(), Timestamp(8));
+        assert_eq!(reader.read()?.timestamp(), Timestamp(10));
+        assert_eq!(sut.stats().late_records, 0);
+
+        // beyond the slack
+        sut.push_record(record(9))?;
+        assert_eq!(sut.stats().late_records, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn hold_back_records_at_exclusive_watermark() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+
+        // the equal records are split across the dumps
+        let mut sut = Buffer::new(dir.path(), &mut writer, Config::default())?;
+        sut.push_record(record(5))?;
+        assert_eq!(sut.dump_safe(Timestamp(5))?.count, 1);
+        sut.push_record(record(5))?;
+        assert_eq!(sut.dump_safe(Timestamp(6))?.count, 1);
+        drop(sut);
+
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder().exclusive_watermark(true).build().unwrap(),
+        )?;
+        sut.push_record(record(0))?;
+        assert!(!sut.has_safe_records(Timestamp(0)));
+        assert_eq!(sut.dump_safe(Timestamp(0))?.count, 0);
+        sut.push_record(record(5))?;
+        assert_eq!(sut.dump_safe(Timestamp(5))?.count, 1);
+        // the straggler with the same timestamp
+        sut.push_record(record(5))?;
+        assert_eq!(sut.dump_safe(Timestamp(5))?.count, 0);
+        let dumped = sut.dump_safe(Timestamp(6))?;
+        assert_eq!(dumped.count, 2);
+        assert_eq!(dumped.range, Some(Timestamp(5)..=Timestamp(5)));
+        assert_eq!(sut.stats().late_records, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn histogram_of_in_memory_timestamps() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(10))?;
+        assert_eq!(sut.timestamp_histogram(2), []);
+
+        for ts in [10, 11, 12, 19, 20] {
+            sut.push_record(record(ts))?;
+        }
+        assert_eq!(
+            sut.timestamp_histogram(2),
+            [(Timestamp(10), 3), (Timestamp(16), 2)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_all_records() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                max_in_memory: 2,
+                lateness_slack: Timestamp(100),
+                ..Default::default()
+            },
+        )?;
+        for ts in [u128::MAX, 7, 0] {
+            sut.push_record(record(ts))?;
+        }
+
+        assert_eq!(sut.flush_all()?.count, 3);
+        assert!(sut.is_empty());
+        assert_eq!(sut.earliest_buffered_timestamp(), None);
+        drop(sut);
+        assert_eq!(output::Reader::open(&output)?.records_remaining()?, Some(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_dump_progress() -> std::io::Result<()> {
+        static REPORTED: std::sync::Mutex<Vec<u64>> = std::sync::Mutex::new(Vec::new());
+
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                max_in_memory: 4,
+                progress: Some(Progress {
+                    every: NonZero::new(3).unwrap(),
+                    callback: |dumped| REPORTED.lock().unwrap().push(dumped),
+                }),
+                ..Default::default()
+            },
+        )?;
+        for ts in (0..10).rev() {
+            sut.push_record(record(ts))?;
+        }
+
+        assert_eq!(sut.dump_safe(Timestamp(7))?.count, 8);
+        assert_eq!(*REPORTED.lock().unwrap(), [3, 6]);
+        assert_eq!(sut.flush_all()?.count, 2);
+        assert_eq!(*REPORTED.lock().unwrap(), [3, 6]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_merge_work() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        for ts in 0..9 {
+            sut.push_record(record(ts))?;
+        }
+
+        // `seq` (8), variant (4), timestamp (16) and source (4)
+        const RECORD_LEN: u64 = 32;
+        // the file starting past the watermark is not even opened
+        let dumped = sut.dump_safe(Timestamp(4))?;
+        assert_eq!(
+            dumped.merge,
+            MergeReport {
+                readers: 2,
+                bytes_read: 5 * RECORD_LEN,
+                passes: 1,
+            }
+        );
+        let dumped = sut.flush_all()?;
+        assert_eq!(
+            dumped.merge,
+            MergeReport {
+                readers: 2,
+                bytes_read: 4 * RECORD_LEN,
+                passes: 1,
+            }
+        );
+        assert_eq!(sut.flush_all()?.merge, MergeReport::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_dump_and_resume() -> std::io::Result<()> {
+        static CANCEL: AtomicBool = AtomicBool::new(false);
+
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                max_in_memory: 3,
+                progress: Some(Progress {
+                    every: NonZero::new(4).unwrap(),
+                    callback: |_| CANCEL.store(true, atomic::Ordering::Relaxed),
+                }),
+                ..Default::default()
+            },
+        )?;
+        for ts in [9, 2, 5, 0, 7, 3, 8, 1, 6, 4] {
+            sut.push_record(record(ts))?;
+        }
+
+        let dumped = sut.dump_safe_cancellable(Timestamp(100), &CANCEL)?;
+        assert!(dumped.cancelled);
+        assert_eq!(dumped.range, Some(Timestamp(0)..=Timestamp(3)));
+        assert_eq!(sut.len(), 6);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(4)));
+
+        CANCEL.store(false, atomic::Ordering::Relaxed);
+        let dumped = sut.dump_safe_cancellable(Timestamp(5), &CANCEL)?;
+        assert!(!dumped.cancelled);
+        assert_eq!(dumped.range, Some(Timestamp(4)..=Timestamp(5)));
+        CANCEL.store(false, atomic::Ordering::Relaxed);
+        assert_eq!(sut.flush_all()?.count, 4);
+        drop(sut);
+
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 0..10 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_in_limited_batches() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        for ts in [9, 2, 5, 0, 7, 3, 8, 1, 6, 4] {
+            sut.push_record(record(ts))?;
+        }
+
+        let max = NonZero::new(4).unwrap();
+        let dumped = sut.dump_safe_at_most(Timestamp(8), max)?;
+        assert_eq!(dumped.range, Some(Timestamp(0)..=Timestamp(3)));
+        assert!(!dumped.cancelled);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(4)));
+        let dumped = sut.dump_safe_at_most(Timestamp(8), max)?;
+        assert_eq!(dumped.range, Some(Timestamp(4)..=Timestamp(7)));
+        let dumped = sut.dump_safe_at_most(Timestamp(8), max)?;
+        assert_eq!(dumped.count, 1);
+        assert_eq!(sut.dump_safe_at_most(Timestamp(8), max)?.count, 0);
+        assert_eq!(sut.flush_all()?.count, 1);
+        drop(sut);
+
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 0..10 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_in_chunks() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .max_in_memory(3)
+                .dump_chunk(NonZero::new(4).unwrap())
+                .build()
+                .unwrap(),
+        )?;
+        for ts in [9, 2, 5, 0, 7, 3, 8, 1, 6, 4] {
+            sut.push_record(record(ts))?;
+        }
+
+        let dumped = sut.dump_safe(Timestamp(6))?;
+        assert_eq!(dumped.count, 7);
+        assert_eq!(dumped.range, Some(Timestamp(0)..=Timestamp(6)));
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(7)));
+        assert_eq!(sut.flush_all()?.count, 3);
+        drop(sut);
+
+        assert_eq!(writer.records_written(), Some(10));
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 0..10 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn drain_into_sorted_vec() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        for ts in [9, 2, 5, 0, 7, 3, 8, 1, 6, 4] {
+            sut.push_record(record(ts))?;
+        }
+
+        let sorted = sut.drain_sorted_vec()?;
+        assert_eq!(
+            sorted.iter().map(|x| x.timestamp().0).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert!(sut.is_empty());
+        assert!(sut.drain_sorted_vec()?.is_empty());
+        drop(sut);
+
+        assert_eq!(writer.records_written(), Some(0));
+        let files: Vec<_> = std::fs::read_dir(dir.path())?
+            .map(|x| x.map(|x| x.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        assert_eq!(files, ["output"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_safe_into_vec() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        for ts in [9, 2, 5, 0, 7, 3, 8, 1, 6, 4] {
+            sut.push_record(record(ts))?;
+        }
+
+        let mut records = vec![];
+        let dumped = sut.dump_safe_into(Timestamp(4), &mut records)?;
+        assert_eq!(dumped.count, 5);
+        assert_eq!(dumped.range, Some(Timestamp(0)..=Timestamp(4)));
+        // appended to whatever is in the vector already
+        let dumped = sut.dump_safe_into(Timestamp(6), &mut records)?;
+        assert_eq!(dumped.count, 2);
+        assert_eq!(
+            records.iter().map(|x| x.timestamp().0).collect::<Vec<_>>(),
+            (0..7).collect::<Vec<_>>()
+        );
+        assert_eq!(sut.len(), 3);
+
+        // the rest is dumped into the output as usual
+        assert_eq!(sut.flush_all()?.count, 3);
+        drop(sut);
+        assert_eq!(writer.records_written(), Some(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_records_larger_than_limit() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .serialization(SerializationConfig {
+                    max_record_size: Some(100),
+                })
+                .build()
+                .unwrap(),
+        )?;
+        let record = |ts, len| {
+            Record::E(DataE {
+                timestamp: Timestamp(ts),
+                def: vec![0; len],
+            })
+        };
+
+        assert_matches!(sut.push_record(record(1, 10))?, PushOutcome::Buffered);
+        let err = sut.push_record(record(2, 100)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_matches!(
+            Error::downcast(&err),
+            Some(Error::RecordTooLarge {
+                size: 228,
+                limit: 100
+            })
+        );
+        sut.push_records([record(3, 10), record(4, 1_000), record(5, 10)])
+            .unwrap_err();
+        assert_eq!(sut.len(), 2);
+
+        assert_eq!(sut.flush_all()?.count, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn carry_sources_into_output() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?.with_records();
+        let mut sut = Buffer::new_tagged(dir.path(), &mut writer, test_support::config(3))?;
+        for (ts, source) in [(9, 1), (2, 2), (5, 3), (0, 4), (7, 5), (3, 6), (8, 7)] {
+            sut.push_tagged(SourceTagged {
+                source,
+                record: record(ts),
+            })?;
+        }
+        sut.push_record(record(1))?;
+        assert!(sut.stats().spills > 0);
+        assert_eq!(sut.flush_all()?.count, 8);
+        drop(sut);
+        drop(writer);
+
+        let tagged = output::Reader::open(&output)?
+            .with_records::<SourceTagged<Record>>()
+            .try_iter()
+            .map(|x| x.map(|x| (x.timestamp().0, x.source)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(
+            tagged,
+            [
+                (0, 4),
+                (1, 0),
+                (2, 2),
+                (3, 6),
+                (5, 3),
+                (7, 5),
+                (8, 7),
+                (9, 1)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sort_nearly_sorted_records_in_window() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let read_timestamps = || -> std::io::Result<Vec<_>> {
+            output::Reader::open(&output)?
+                .try_iter()
+                .map(|x| x.map(|x| x.timestamp().0))
+                .collect()
+        };
+        let config = Config::builder()
+            .in_memory_strategy(InMemoryStrategy::BoundedWindow {
+                k: NonZero::new(2).unwrap(),
+            })
+            .build()
+            .unwrap();
+
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, config.clone())?;
+        sut.push_records([1, 0, 3, 2, 5, 4, 8, 6, 7].map(record))?;
+        // all but the window are written already
+        assert_eq!(sut.len(), 2);
+        let dumped = sut.dump_safe(Timestamp(7))?;
+        assert_eq!(dumped.count, 8);
+        assert_eq!(dumped.next_earliest, Some(Timestamp(8)));
+        assert_eq!(sut.flush_all()?.count, 1);
+        assert_eq!(sut.stats().spills, 0);
+        drop(sut);
+        assert_eq!(read_timestamps()?, (0..9).collect::<Vec<_>>());
+
+        // out of order by more than the window
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, config)?;
+        sut.push_records([5, 6, 7, 8, 1].map(record))?;
+        assert_eq!(sut.stats().late_records, 1);
+        assert_eq!(sut.flush_all()?.count, 5);
+        drop(sut);
+        assert_eq!(read_timestamps()?, [5, 6, 1, 7, 8]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_next_earliest_timestamp() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        for ts in [9, 2, 7, 0, 3] {
+            sut.push_record(record(ts))?;
+        }
+
+        let dumped = sut.dump_safe(Timestamp(4))?;
+        assert_eq!(dumped.count, 3);
+        assert_eq!(dumped.next_earliest, Some(Timestamp(7)));
+        // nothing to dump until the watermark reaches it
+        let dumped = sut.dump_safe(Timestamp(6))?;
+        assert_eq!(dumped.count, 0);
+        assert_eq!(dumped.next_earliest, Some(Timestamp(7)));
+        let dumped = sut.flush_all()?;
+        assert_eq!(dumped.count, 2);
+        assert_eq!(dumped.next_earliest, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_safe_with_advancing_watermark() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        for ts in [9, 2, 5, 0, 7, 3, 8, 1, 6, 4, 12] {
+            sut.push_record(record(ts))?;
+        }
+
+        // advances by 3 with each reading, up to 10
+        let reads = std::cell::Cell::new(0);
+        let watermark = || {
+            reads.set(reads.get() + 1);
+            Timestamp((reads.get() * 3 - 1).min(10))
+        };
+        let dumped = sut.dump_safe_dynamic(&watermark)?;
+        assert_eq!(dumped.count, 10);
+        assert_eq!(dumped.range, Some(Timestamp(0)..=Timestamp(9)));
+        // read again at 3, 6, 9 and 12
+        assert_eq!(reads.get(), 5);
+        assert_eq!(sut.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "minimal-memory")]
+    fn cap_in_memory_records_with_minimal_memory() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .spill_high(50_000)
+                .spill_low(10_000)
+                .build()
+                .unwrap(),
+        )?;
+        let count = 10 * MINIMAL_MEMORY_MAX_IN_MEMORY as u128;
+        sut.push_records((0..count).rev().map(record))?;
+        for ts in 0..count {
+            sut.push_record(record(ts))?;
+        }
+
+        assert_eq!(sut.stats().max_in_memory, MINIMAL_MEMORY_MAX_IN_MEMORY);
+        assert_eq!(sut.stats().peak_in_memory, MINIMAL_MEMORY_MAX_IN_MEMORY);
+        assert!(sut.stats().spills > 0);
+        assert_eq!(sut.flush_all()?.count, 2 * count as usize);
+        drop(sut);
+
+        // the window is capped too
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .in_memory_strategy(InMemoryStrategy::BoundedWindow {
+                    k: NonZero::new(50_000).unwrap(),
+                })
+                .build()
+                .unwrap(),
+        )?;
+        sut.push_records((0..count).map(record))?;
+        let window = sut.window.as_ref().map(in_memory::Window::len);
+        assert_eq!(window, Some(MINIMAL_MEMORY_MAX_IN_MEMORY));
+        assert_eq!(sut.flush_all()?.count, count as usize);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal-memory"))]
+    fn peek_all_without_draining() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        for ts in [9, 2, 5, 0, 7, 3, 8, 1, 6, 4] {
+            sut.push_record(record(ts))?;
+        }
+        assert_eq!(sut.dump_safe(Timestamp(1))?.count, 2);
+        // in memory, along with the partially read spill files
+        sut.push_record(record(10))?;
+
+        let timestamps =
+            |records: Vec<Record>| -> Vec<_> { records.iter().map(|x| x.timestamp().0).collect() };
+        assert_eq!(timestamps(sut.peek_all()?), (2..11).collect::<Vec<_>>());
+        assert_eq!(timestamps(sut.peek_all()?), (2..11).collect::<Vec<_>>());
+        assert_eq!(sut.len(), 9);
+        assert_eq!(
+            timestamps(sut.drain_sorted_vec()?),
+            (2..11).collect::<Vec<_>>()
+        );
+        assert!(sut.peek_all()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_spill_files() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .max_in_memory(2)
+                .spill_file_pool(2)
+                .keep_spills(true)
+                .build()
+                .unwrap(),
+        )?;
+        for ts in [3, 1, 4, 2] {
+            sut.push_record(record(ts))?;
+        }
+        assert_eq!(sut.flush_all()?.count, 4);
+        sut.push_record(record(5))?;
+        assert_eq!(sut.drain_sorted_vec()?.len(), 1);
+        drop(sut);
+
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())?
+            .map(|x| x.map(|x| x.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        files.sort();
+        assert_eq!(files, ["dump-0", "dump-1", "dump-2", "output"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_interval_by_clock() -> std::io::Result<()> {
+        use crate::clock::MockClock;
+        use std::sync::atomic::AtomicUsize;
+
+        /// Counts the flushes reaching the sink
+        struct Flushes(Arc<AtomicUsize>);
+        impl std::io::Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.fetch_add(1, atomic::Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        let dir = tempfile::tempdir()?;
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let mut writer = output::Writer::from_write(Flushes(flushes.clone()))?;
+        let clock = Arc::new(MockClock::new());
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .flush_interval(Duration::from_millis(10))
+                .clock(clock.clone())
+                .build()
+                .unwrap(),
+        )?;
+        let dump = |sut: &mut Buffer| -> std::io::Result<usize> {
+            for ts in 0..10 {
+                sut.push_record(record(ts))?;
+            }
+            let before = flushes.load(atomic::Ordering::Relaxed);
+            assert_eq!(sut.flush_all()?.count, 10);
+            Ok(flushes.load(atomic::Ordering::Relaxed) - before)
+        };
+
+        // the clock stands still, so the output is only flushed at the end
+        assert_eq!(dump(&mut sut)?, 1);
+        drop(sut);
+        // the clock passes the interval on each record
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .flush_interval(Duration::from_millis(10))
+                .clock(MockClock::with_step(Duration::from_millis(10)))
+                .build()
+                .unwrap(),
+        )?;
+        assert_eq!(dump(&mut sut)?, 11);
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+
+        Ok(())
+    }
+
+    #[test]
+    // the records are kept in memory
+    #[cfg(not(feature = "minimal-memory"))]
+    fn keep_unwritten_records_on_write_error() -> std::io::Result<()> {
+        use std::sync::atomic::AtomicUsize;
+
+        /// Fails the writes past the byte budget
+        struct Budget(Arc<AtomicUsize>);
+        impl std::io::Write for Budget {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0
+                    .fetch_update(
+                        atomic::Ordering::Relaxed,
+                        atomic::Ordering::Relaxed,
+                        |left| left.checked_sub(buf.len()),
+                    )
+                    .map_err(|_| std::io::Error::other("out of budget"))?;
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for spill in [SpillPolicy::Spill, SpillPolicy::NeverSpill] {
+            let dir = tempfile::tempdir()?;
+            // a chunk of 1000 records is 24000 bytes, past the write buffer, so only the first
+            // chunk fits into the budget
+            let mut failing =
+                output::Writer::from_write(Budget(Arc::new(AtomicUsize::new(30_000))))?;
+            let output = dir.path().join("output");
+            let mut writer = output::Writer::open(&output)?;
+            let mut sut = Buffer::new(
+                dir.path(),
+                &mut failing,
+                Config::builder()
+                    .max_in_memory(5_000)
+                    .dump_chunk(NonZero::new(1_000).unwrap())
+                    .spill(spill)
+                    .build()
+                    .unwrap(),
+            )?;
+            for ts in (0..3_000).rev() {
+                sut.push_record(record(ts))?;
+            }
+            sut.flush_all().expect_err("the second chunk must fail");
+            assert_eq!(sut.earliest_buffered_timestamp, Some(Timestamp(1_000)));
+            // the failed writer could have a part of a record written
+            let err = sut.flush_all().unwrap_err();
+            assert_matches!(Error::downcast(&err), Some(Error::OutputFailed));
+
+            sut.swap_writer(&mut writer);
+            assert_eq!(sut.flush_all()?.count, 2_000);
+            drop(sut);
+            let timestamps = output::Reader::open(&output)?
+                .try_iter()
+                .map(|x| x.map(|x| x.timestamp().0))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            assert_eq!(timestamps, (1_000..3_000).collect::<Vec<_>>(), "{spill:?}");
+        }
+
+        // a spill file failing to be read keeps its records, and those of the other files
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(10))?;
+        for ts in (0..30).rev() {
+            sut.push_record(record(ts))?;
+        }
+        let spill = dir.path().join("dump-1");
+        let bytes = std::fs::read(&spill)?;
+        std::fs::write(&spill, &bytes[..bytes.len() - 5])?;
+        let err = sut.flush_all().unwrap_err();
+        assert_matches!(
+            output::Error::downcast(&err),
+            Some(output::Error::TruncatedRecord { .. })
+        );
+        assert_eq!(sut.files.len(), 2);
+        std::fs::write(&spill, &bytes)?;
+        sut.flush_all()?;
+        assert!(sut.is_empty());
+        drop(sut);
+        let timestamps = output::Reader::open(&output)?
+            .try_iter()
+            .map(|x| x.map(|x| x.timestamp().0))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(timestamps, (0..30).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unnamed_spill_files() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let spill_dir = dir.path().join("spills");
+        std::fs::create_dir(&spill_dir)?;
+        let output = dir.path().join("output");
+        let mut writer = output::Writer::open(&output)?;
+        let ids = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .max_in_memory(2)
+                .spill_file_pool(2)
+                .spill_factory({
+                    let (ids, spill_dir) = (ids.clone(), spill_dir.clone());
+                    move |id| {
+                        ids.lock().unwrap().push(id);
+                        tempfile::tempfile_in(&spill_dir)
+                    }
+                })
+                .build()
+                .unwrap(),
+        )?;
+        for ts in [3, 1, 4, 2, 0] {
+            sut.push_record(record(ts))?;
+        }
+        assert!(sut.files.iter().all(|x| x.path().is_none()));
+        assert_eq!(sut.flush_all()?.count, 5);
+        sut.push_record(record(5))?;
+        assert_eq!(sut.drain_sorted_vec()?.len(), 1);
+        drop(sut);
+
+        assert_eq!(*ids.lock().unwrap(), [0, 1, 2, 3]);
+        assert_eq!(std::fs::read_dir(&spill_dir)?.count(), 0);
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 0..5 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn spill_files_across_directories() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let spill_dirs = vec![dir.path().join("a"), dir.path().join("b")];
+        for dir in &spill_dirs {
+            std::fs::create_dir(dir)?;
+        }
+        let output = dir.path().join("output");
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                max_in_memory: 2,
+                spill_dirs: spill_dirs.clone(),
+                ..Default::default()
+            },
+        )?;
+        for ts in [5, 4, 3, 2, 1, 0] {
+            sut.push_record(record(ts))?;
+        }
+
+        let parents: Vec<_> = sut.files.iter().map(|x| x.path()?.parent()).collect();
+        assert_eq!(
+            parents,
+            [&spill_dirs[0], &spill_dirs[1], &spill_dirs[0]].map(|x| Some(x.as_path()))
+        );
+        assert_eq!(sut.flush_all()?.count, 6);
+        drop(sut);
+
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 0..6 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn equal_timestamps_keep_push_order() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        let record = |ts, i| {
+            Record::C(DataC {
+                timestamp: Timestamp(ts),
+                baz: (i, 0),
+            })
+        };
+        // spread over several files, some of which are partially dumped and merged again
+        for (i, ts) in [5, 1, 5, 5, 1, 3, 5, 1, 5, 3, 5].into_iter().enumerate() {
+            sut.push_record(record(ts, i as u32))?;
+            if i == 7 {
+                sut.dump_safe(Timestamp(1))?;
+            }
+        }
+        sut.flush_all()?;
+        drop(sut);
+
+        let mut reader = output::Reader::open(&output)?;
+        for (ts, i) in [
+            (1, 1),
+            (1, 4),
+            (1, 7),
+            (3, 5),
+            (3, 9),
+            (5, 0),
+            (5, 2),
+            (5, 3),
+            (5, 6),
+            (5, 8),
+            (5, 10),
+        ] {
+            assert_eq!(reader.read()?, record(ts, i));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_buf_capacity_depends_on_file_size() -> std::io::Result<()> {
+        static REMAINING: std::sync::Mutex<Vec<usize>> = std::sync::Mutex::new(Vec::new());
+
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                max_in_memory: 3,
+                file_read_buf_policy: Some(|remaining| {
+                    REMAINING.lock().unwrap().push(remaining);
+                    remaining * 64
+                }),
+                ..Default::default()
+            },
+        )?;
+        for ts in 0..8 {
+            sut.push_record(record(ts))?;
+        }
+
+        assert_eq!(sut.flush_all()?.count, 8);
+        let mut remaining = REMAINING.lock().unwrap().clone();
+        remaining.sort();
+        assert_eq!(remaining, [2, 3, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn safe_records_respect_lateness_slack() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .lateness_slack(Timestamp(5))
+                .build()
+                .unwrap(),
+        )?;
+        assert!(!sut.has_safe_records(Timestamp(u128::MAX)));
+        for ts in [12, 10] {
+            sut.push_record(record(ts))?;
+        }
+        assert!(!sut.has_safe_records(Timestamp(14)));
+        assert_eq!(sut.dump_safe(Timestamp(14))?.count, 0);
+        assert!(sut.has_safe_records(Timestamp(15)));
+        assert_eq!(sut.dump_safe(Timestamp(15))?.count, 1);
+        assert!(!sut.has_safe_records(Timestamp(16)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_and_validate_config() {
+        let config = Config::builder()
+            .max_in_memory(10)
+            .lateness_slack(Timestamp(5))
+            .build()
+            .unwrap();
+        assert_eq!(config.max_in_memory, 10);
+        assert_eq!(config.lateness_slack, Timestamp(5));
+        assert_eq!(
+            config.file_read_buf_capacity,
+            Config::default().file_read_buf_capacity
+        );
+        Config::default().validate().unwrap();
+
+        let err = Config::builder().max_in_memory(0).build().unwrap_err();
+        assert_eq!(err, ConfigError::ZeroMaxInMemory);
+        let err = Config::builder()
+            .file_read_buf_capacity(0)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::ZeroFileReadBufCapacity);
+        let err = Config::builder()
+            .max_merge_buffer_bytes(0)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::ZeroMaxMergeBufferBytes);
+        let err = Config::builder()
+            .max_in_memory(10)
+            .spill_high(11)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::SpillHighAboveMaxInMemory);
+        let err = Config::builder()
+            .max_in_memory(10)
+            .spill_low(10)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::SpillLowNotBelowHigh);
+    }
+
+    #[test]
+    fn reject_invalid_config() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        for (config, expected) in [
+            (test_support::config(0), ConfigError::ZeroMaxInMemory),
+            (
+                Config {
+                    file_read_buf_capacity: 0,
+                    ..Default::default()
+                },
+                ConfigError::ZeroFileReadBufCapacity,
+            ),
+        ] {
+            let err = Buffer::new(dir.path(), &mut writer, config).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(ConfigError::downcast(&err), Some(&expected));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn markers_inherit_latest_timestamp() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (tx, rx) = mpsc::channel();
+        let mut sut = Buffer::new_channel(dir.path(), tx, test_support::config(2))?;
+        let marker = |foo: &'static str| {
+            Record::A(DataA {
+                timestamp: Timestamp(0),
+                foo: foo.into(),
+            })
+        };
+
+        sut.push_record(record(5))?;
+        sut.push_record(record(2))?;
+        sut.push_marker(marker("first"))?;
+        sut.push_marker(marker("second"))?;
+        sut.push_record(Record::B(DataB {
+            timestamp: Timestamp(5),
+            bar: true,
+        }))?;
+        sut.flush_all()?;
+
+        let dumped = rx
+            .try_iter()
+            .map(|x| match x {
+                Record::A(x) => format!("{}@{}", x.foo, x.timestamp.0),
+                x => format!("{:?}@{}", x.kind(), x.timestamp().0),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(dumped, ["D@2", "D@5", "first@5", "second@5", "B@5"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn divert_late_records() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let late = dir.path().join("late.bin");
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .max_in_memory(2)
+                .late_output(late.clone())
+                .build()
+                .unwrap(),
+        )?;
+        assert_eq!(sut.late_output(), Some(late.as_path()));
+        let timestamps = |path| -> std::io::Result<Vec<u128>> {
+            output::Reader::open(path)?
+                .try_iter()
+                .map(|x| x.map(|x| x.timestamp().0))
+                .collect()
+        };
+
+        sut.push_records([4, 6, 8].map(record))?;
+        assert_eq!(sut.dump_safe(Timestamp(6))?.count, 2);
+        assert_eq!(sut.push_record(record(3))?, PushOutcome::DivertedLate);
+        sut.push_records([9, 1, 7, 5].map(record))?;
+        assert_eq!(sut.stats().late_records, 3);
+        // the first two are written already, as many as the in-memory records
+        assert_eq!(timestamps(&late)?, [1, 3]);
+        assert_eq!(sut.flush_all()?.count, 3);
+        assert_eq!(timestamps(&late)?, [1, 3, 5]);
+        // written and sorted as a whole, without another dump
+        sut.push_record(record(2))?;
+        assert_eq!(sut.finish()?.count, 0);
+
+        assert_eq!(timestamps(&output)?, [4, 6, 7, 8, 9]);
+        assert_eq!(timestamps(&late)?, [1, 2, 3, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn count_late_write_errors() -> std::io::Result<()> {
+        /// Fails the writes past the byte budget
+        struct Full(usize);
+        impl std::io::Write for Full {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 = (self.0.checked_sub(buf.len()))
+                    .ok_or(std::io::Error::from(std::io::ErrorKind::StorageFull))?;
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (dir, output) = test_support::temp_output()?;
+        let late = dir.path().join("late.bin");
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder().late_output(late.clone()).build().unwrap(),
+        )?;
+        let timestamps = |path| -> std::io::Result<Vec<u128>> {
+            output::Reader::open(path)?
+                .try_iter()
+                .map(|x| x.map(|x| x.timestamp().0))
+                .collect()
+        };
+        // only the header fits, along with the records count
+        let full = || output::Writer::from_write(Full(codec::FORMAT_HEADER_LEN + 8));
+        sut.push_records([4, 6, 8].map(record))?;
+        assert_eq!(sut.dump_safe(Timestamp(6))?.count, 2);
+        let late_writer = &mut sut.late.as_mut().unwrap().writer;
+        let file = std::mem::replace(late_writer, full()?);
+
+        // the dump succeeds regardless
+        sut.push_record(record(3))?;
+        assert_eq!(sut.flush_all()?.count, 1);
+        assert_eq!(sut.stats().records_dumped, 3);
+        assert_eq!(sut.stats().late_write_errors, 1);
+        assert_eq!(sut.last_dumped_timestamp, Some(Timestamp(8)));
+
+        // the pending record is written by the next dump, even with nothing to dump
+        sut.late.as_mut().unwrap().writer = file;
+        assert_eq!(sut.flush_all()?.count, 0);
+        assert_eq!(timestamps(&late)?, [3]);
+        sut.push_record(record(1))?;
+        assert_eq!(sut.finish()?.count, 0);
+
+        assert_eq!(timestamps(&output)?, [4, 6, 8]);
+        assert_eq!(timestamps(&late)?, [1, 3]);
+
+        // reported once the buffer is finished
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .late_output(dir.path().join("late-full.bin"))
+                .build()
+                .unwrap(),
+        )?;
+        sut.late.as_mut().unwrap().writer = full()?;
+        sut.push_records([2, 1].map(record))?;
+        assert_eq!(sut.dump_safe(Timestamp(2))?.count, 2);
+        sut.push_record(record(0))?;
+        let err = sut.finish().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+
+        Ok(())
+    }
+
+    #[test]
+    fn plan_spills_as_buffer_does() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let config = Config::builder()
+            .max_in_memory(10)
+            .spill_low(4)
+            .build()
+            .unwrap();
+        const RECORD_LEN: usize = 32;
+
+        assert_eq!(
+            config.plan(0, RECORD_LEN),
+            SortPlan {
+                spill_files: 0,
+                merge_passes: 0,
+                estimated_bytes_written: 0,
+                estimated_bytes_read: 0,
+            }
+        );
+        let plan = config.plan(100, RECORD_LEN);
+        // 16 spills of 6 records, and the last 4 records spilled by the dump
+        assert_eq!(plan.spill_files, 17);
+        assert_eq!(plan.merge_passes, 1);
+
+        let mut sut = Buffer::new(dir.path(), &mut writer, config.clone())?;
+        sut.push_records((0..100).rev().map(record))?;
+        assert_eq!(sut.dump_safe(Timestamp(100))?.count, 100);
+        let stats = sut.stats();
+        assert_eq!(stats.spills, plan.spill_files as u64);
+        assert_eq!(
+            plan.estimated_bytes_written,
+            stats.bytes_spilled + (100 * RECORD_LEN) as u64
+        );
+        assert_eq!(plan.estimated_bytes_read, stats.bytes_spilled);
+
+        let config = Config {
+            spill: SpillPolicy::NeverSpill,
+            ..config
+        };
+        assert_eq!(config.plan(10, RECORD_LEN).spill_files, 0);
+        assert_eq!(
+            config.plan(usize::MAX, usize::MAX).estimated_bytes_written,
+            u64::MAX
+        );
+
+        // the bytes saturate rather than overflow
+        let plan = Config::default().plan(usize::MAX, 1 << 20);
+        assert!(plan.spill_files > 0);
+        assert_eq!(plan.estimated_bytes_written, u64::MAX);
+        assert_eq!(plan.estimated_bytes_read, u64::MAX);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_few_in_memory_records_without_spilling() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut reader = output::Reader::open(&output)?;
+        let config = Config::builder()
+            .max_in_memory(10)
+            .min_spill_records(3)
+            .build()
+            .unwrap();
+        // 9 spills of 10 records, and the last 5 records are spilled by the dump, unless there
+        // are too few of them
+        assert_eq!(config.plan(95, 32).spill_files, 10);
+        let plan = Config {
+            min_spill_records: 6,
+            ..config.clone()
+        }
+        .plan(95, 32);
+        assert_eq!(plan.spill_files, 9);
+        let mut sut = Buffer::new(dir.path(), &mut writer, config)?;
+
+        sut.push_records([record(20), record(10)])?;
+        assert_eq!(sut.dump_safe(Timestamp(15))?.count, 1);
+        assert_eq!(sut.stats().spills, 0);
+        // the rest stays in memory
+        assert_eq!(sut.in_memory.len(), 1);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(20)));
+
+        sut.push_records([record(40), record(30)])?;
+        assert_eq!(sut.dump_safe(Timestamp(35))?.count, 2);
+        assert_eq!(sut.stats().spills, 1);
+        assert_eq!(sut.in_memory.len(), 0);
+        assert_eq!(sut.len(), 1);
+
+        for ts in [10, 20, 30] {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn spill_down_to_low_mark() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .max_in_memory(5)
+                .spill_high(4)
+                .spill_low(1)
+                .build()
+                .unwrap(),
+        )?;
+
+        assert_eq!(sut.stats().max_in_memory, 5);
+        for ts in [8, 2, 5] {
+            assert_eq!(sut.push_record(record(ts))?, PushOutcome::Buffered);
+        }
+        assert_eq!(
+            sut.push_record(record(0))?,
+            PushOutcome::Spilled {
+                file_id: 0,
+                records: 3
+            }
+        );
+        // the latest record is kept
+        assert_eq!(sut.in_memory.iter().next(), Some(&record(8)));
+        for ts in [3, 9] {
+            assert_eq!(sut.push_record(record(ts))?, PushOutcome::Buffered);
+        }
+        assert_eq!(
+            sut.push_record(record(1))?,
+            PushOutcome::Spilled {
+                file_id: 1,
+                records: 3
+            }
+        );
+        assert_eq!(sut.in_memory.len(), 1);
+        assert_eq!(sut.len(), 7);
+        assert_eq!(sut.stats().spills, 2);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(0)));
+        let sorted = sut.drain_sorted_vec()?;
+        assert_eq!(
+            sorted.iter().map(|x| x.timestamp().0).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 5, 8, 9]
+        );
+        // the last record is spilled before merging, and each record is merged once
+        let stats = sut.stats();
+        assert_eq!(stats.spills, 3);
+        assert_eq!(stats.bytes_merged % 7, 0);
+        assert_eq!(
+            stats.bytes_spilled,
+            3 * codec::FORMAT_HEADER_LEN as u64 + stats.bytes_merged
+        );
+        assert_eq!(stats.records_spilled, 7);
+        assert_eq!(stats.write_amplification(), Some(1.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_records_of_failed_spill_in_memory() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .max_in_memory(5)
+                .spill_high(4)
+                .spill_low(1)
+                .spill_factory(|id| match id {
+                    0 => Err(std::io::Error::other("no space left")),
+                    _ => tempfile::tempfile(),
+                })
+                .build()
+                .unwrap(),
+        )?;
+
+        sut.push_records([8, 2, 5].map(record))?;
+        assert!(sut.push_record(record(0)).is_err());
+        assert_eq!(sut.in_memory.len(), 4);
+        assert_eq!(
+            sut.push_record(record(3))?,
+            PushOutcome::Spilled {
+                file_id: 1,
+                records: 4
+            }
+        );
+        assert_eq!(sut.in_memory.iter().next(), Some(&record(8)));
+        let sorted = sut.drain_sorted_vec()?;
+        assert_eq!(
+            sorted.iter().map(|x| x.timestamp().0).collect::<Vec<_>>(),
+            [0, 2, 3, 5, 8]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_buffers_are_capped() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config::builder()
+                .max_in_memory(2)
+                .file_read_buf_capacity(1_024)
+                .max_merge_buffer_bytes(3_000)
+                .build()
+                .unwrap(),
+        )?;
+        assert_eq!(sut.read_buf_capacity(2, 1), 1_024);
+        assert_eq!(sut.read_buf_capacity(2, 4), 750);
+        assert_eq!(sut.read_buf_capacity(2, 10_000), 1);
+
+        for ts in (0..20).rev() {
+            sut.push_record(record(ts))?;
+        }
+        assert_eq!(sut.flush_all()?.count, 20);
+        drop(sut);
+
+        let mut reader = output::Reader::open(dir.path().join("output"))?;
+        for ts in 0..20 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn push_records_in_bulk() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(3))?;
+        sut.push_records([8, 3, 9, 5, 7].map(record))?;
+        assert_eq!(sut.len(), 5);
+        assert_eq!(sut.files.len(), 1);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(3)));
+
+        assert_eq!(sut.dump_safe(Timestamp(5))?.count, 2);
+        sut.push_records([4, 1].map(record))?;
+        assert_eq!(sut.files.len(), 2);
+        assert_eq!(sut.stats().late_records, 2);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(1)));
+        assert_eq!(sut.flush_all()?.count, 5);
+        drop(sut);
+
+        // the records before the failed one stay pushed
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                serialization: SerializationConfig {
+                    max_record_size: Some(20),
+                },
+                ..test_support::config(3)
+            },
+        )?;
+        let large = Record::E(DataE {
+            timestamp: Timestamp(0),
+            def: vec![0; 10],
+        });
+        let err = sut
+            .push_records([record(12), record(11), large, record(10)])
+            .unwrap_err();
+        assert_matches!(Error::downcast(&err), Some(Error::RecordTooLarge { .. }));
+        assert_eq!(sut.len(), 2);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(11)));
+
+        Ok(())
     }
-}
-
-/// [`Buffer`] configuration
-pub struct Config {
-    /// Number of records is allowed to store in memory
-    pub max_in_memory: usize,
-    /// Buffer capacity for reading from each file buffer, i.e. merge-sort buffer capacity
-    pub file_read_buf_capacity: usize,
-}
 
-/// _The_ buffer.
-///
-/// It accepts records via [`Buffer::push_record`], and dumps them based on the safe timestamp
-/// with [`Buffer::dump_safe`].
-#[derive(Debug)]
-pub(crate) struct Buffer<'w> {
-    in_memory: in_memory::Buffer,
-    files: Vec<on_disk::FileStorage>,
-    files_counter: usize,
-    files_dir: PathBuf,
-    file_read_buf_capacity: usize,
-    earliest_buffered_timestamp: Option<Timestamp>,
-    output: &'w mut output::Writer,
-}
+    #[test]
+    fn dump_into_shards() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut shards = output::Shards::new(dir.path(), |ts| (ts.0 / 10) as u64);
+        let mut sut = Buffer::new_sharded(dir.path(), &mut shards, test_support::config(3))?;
+        for ts in [25, 3, 21, 7, 28, 1, 22] {
+            sut.push_record(record(ts))?;
+        }
+        assert_eq!(sut.flush_all()?.count, 7);
+        drop(sut);
 
-impl<'w> Buffer<'w> {
-    pub fn new(
-        files_dir: impl AsRef<Path>,
-        output: &'w mut output::Writer,
-        Config {
-            max_in_memory,
-            file_read_buf_capacity,
-        }: Config,
-    ) -> Self {
-        Self {
-            in_memory: in_memory::Buffer::with_capacity(max_in_memory),
-            files: vec![],
-            files_counter: 0,
-            files_dir: files_dir.as_ref().to_path_buf(),
-            file_read_buf_capacity,
-            earliest_buffered_timestamp: None,
-            output,
+        assert_eq!(shards.shards().collect::<Vec<_>>(), [0, 2]);
+        for (shard, timestamps) in [(0, &[1, 3, 7][..]), (2, &[21, 22, 25, 28])] {
+            let mut reader = output::Reader::open(shards.path(shard))?;
+            assert_eq!(reader.records_remaining()?, Some(timestamps.len() as u64));
+            for ts in timestamps {
+                assert_eq!(reader.read()?.timestamp(), Timestamp(*ts));
+            }
         }
+        assert!(!shards.path(1).exists());
+
+        Ok(())
     }
 
-    /// Push a new record into the buffer.
-    pub fn push_record(&mut self, record: Record) -> std::io::Result<()> {
-        let ts = record.timestamp();
-        self.earliest_buffered_timestamp.replace(
-            self.earliest_buffered_timestamp
-                .map_or(ts, |prev| if ts < prev { ts } else { prev }),
+    #[test]
+    fn never_spill_records() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path().join("nonexistent"),
+            &mut writer,
+            Config {
+                max_in_memory: 3,
+                spill: SpillPolicy::NeverSpill,
+                ..Default::default()
+            },
+        )?;
+        let capacity = sut.in_memory.capacity();
+        for ts in (0..capacity as u128).rev() {
+            assert_eq!(sut.push_record(record(ts))?, PushOutcome::Buffered);
+        }
+        let err = sut.push_record(record(100)).unwrap_err();
+        assert_matches!(
+            Error::downcast(&err),
+            Some(Error::CapacityExceeded { capacity: x }) if *x == capacity
         );
+        assert_eq!(sut.len(), capacity);
 
-        self.in_memory.push(record);
-        if self.in_memory.is_full() {
-            self.dump_in_memory()?;
-        }
+        assert_eq!(sut.dump_safe(Timestamp(1))?.count, 2);
+        assert_eq!(sut.len(), capacity - 2);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(2)));
+        sut.push_record(record(100))?;
+        assert_eq!(sut.flush_all()?.count, capacity - 1);
+        assert!(sut.files.is_empty());
+        drop(sut);
+
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        let mut reader = output::Reader::open(&output)?;
+        let timestamps = reader
+            .try_iter()
+            .map(|x| x.map(|record| record.timestamp().0))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(
+            timestamps,
+            (0..capacity as u128).chain([100]).collect::<Vec<_>>()
+        );
 
         Ok(())
     }
 
-    fn dump_in_memory(&mut self) -> std::io::Result<()> {
-        // FIXME not nice code
+    #[test]
+    fn drop_records_older_than_cutoff() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        let mut writer = output::Writer::open(dir.path().join("fixed"))?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                drop_older_than: Some(Cutoff::Fixed(Timestamp(10))),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(sut.push_record(record(9))?, PushOutcome::Dropped);
+        assert_eq!(sut.push_record(record(10))?, PushOutcome::Buffered);
+        sut.push_records([5, 20, 3].map(record))?;
+        assert_eq!(sut.len(), 2);
+        assert_eq!(sut.stats().dropped_records, 3);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(10)));
+        drop(sut);
+
+        let mut writer = output::Writer::open(dir.path().join("behind"))?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                drop_older_than: Some(Cutoff::BehindLatest(Timestamp(5))),
+                ..Default::default()
+            },
+        )?;
+        sut.push_records([3, 10, 5, 4, 12, 7, 6].map(record))?;
+        assert_eq!(sut.len(), 5);
+        assert_eq!(sut.stats().dropped_records, 2);
+        assert_eq!(sut.flush_all()?.range, Some(Timestamp(3)..=Timestamp(12)));
 
-        if self.in_memory.len() == 0 {
-            return Ok(());
-        };
-        let id = self.files_counter;
-        self.files_counter += 1;
-        eprintln!("dumping in-memory (#{id})");
-        let file = self
-            .in_memory
-            .drain_into_file(self.files_dir.join(format!("dump-{id}")))?
-            .expect("in-memory isn't empty");
-        self.files.push(file);
         Ok(())
     }
 
-    /// Dump the records that are safe to dump. It could as well be none!
-    pub fn dump_safe(&mut self, safe_to_dump_timestamp: Timestamp) -> std::io::Result<DumpedCount> {
-        let has_something_to_dump = self
-            .earliest_buffered_timestamp
-            .map(|ts| ts <= safe_to_dump_timestamp)
-            .unwrap_or(false);
-        if !has_something_to_dump {
-            return Ok(DumpedCount(0));
-        };
+    #[test]
+    fn map_records_on_dumping() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(2))?;
+        sut.set_map(|record| match record {
+            Record::A(x) => Some(Record::A(DataA {
+                foo: "redacted".into(),
+                ..x
+            })),
+            Record::D(x) if x.timestamp.0 % 2 == 1 => None,
+            record => Some(record),
+        });
+        for ts in [4, 1, 3, 0, 2] {
+            sut.push_record(record(ts))?;
+        }
+        sut.push_record(Record::A(DataA {
+            timestamp: Timestamp(5),
+            foo: "secret".into(),
+        }))?;
 
-        // we will perform merge-sort only with files
-        // FIXME: avoid this and use in-memory buffer alongside with file buffers
-        self.dump_in_memory()?;
+        let dumped = sut.dump_safe(Timestamp(4))?;
+        assert_eq!(dumped.count, 3);
+        assert_eq!(dumped.range, Some(Timestamp(0)..=Timestamp(4)));
+        assert_eq!(sut.flush_all()?.count, 1);
+        assert_eq!(sut.stats().filtered_records, 2);
+        drop(sut);
 
-        let mut dumped = 0;
+        let mut reader = output::Reader::open(&output)?;
+        for ts in [0, 2, 4] {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+        assert_matches!(reader.read()?, Record::A(DataA { foo, .. }) if foo == "redacted");
+        assert_eq!(reader.records_remaining()?, Some(0));
 
-        // merge sort
-        let mut readers: Vec<_> = self
-            .files
-            .drain(0..)
-            .map(|x| x.read(self.file_read_buf_capacity))
-            .collect::<Result<Vec<_>, _>>()?;
-        loop {
-            let reader_with_earliest_timestamp = readers
-                .iter_mut()
-                .filter_map(|x| {
-                    let ts = x.last().map(|y| y.timestamp());
-                    ts.map(|ts| (x, ts))
-                })
-                .min_by_key(|(_, ts)| *ts)
-                .map(|(reader, _)| reader);
-
-            if let Some(reader) = reader_with_earliest_timestamp {
-                let record = reader.last().expect("must be due to filtering");
-                if record.timestamp() > safe_to_dump_timestamp {
-                    // we can no longer proceed with the merge sort
-                    self.earliest_buffered_timestamp = Some(record.timestamp());
-                    break;
-                }
+        Ok(())
+    }
 
-                // dump the record
-                self.output.write(&record)?;
-                reader.read_next()?;
-                dumped += 1;
-            } else {
-                // all readers are empty
-                self.earliest_buffered_timestamp = None;
-                break;
-            }
+    #[test]
+    fn compact_spill_files() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(2))?;
+        for ts in [6, 1, 5, 3, 0, 4, 2, 7] {
+            sut.push_record(record(ts))?;
         }
-        self.output.flush()?;
+        let spill_files = |dir: &Path| -> std::io::Result<usize> {
+            Ok(std::fs::read_dir(dir)?
+                .filter(|x| x.as_ref().is_ok_and(|x| x.file_name() != "output"))
+                .count())
+        };
+        assert_eq!(sut.files.len(), 4);
+        assert_eq!(sut.stats().write_amplification(), None);
 
-        // close the readers
-        self.files = readers
-            .into_iter()
-            .filter_map(|reader| match reader.close() {
-                Err(err) => Some(Err(err)),
-                Ok(file) if file.is_empty() => None,
-                Ok(file) => Some(Ok(file)),
-            })
-            .collect::<Result<_, _>>()?;
+        sut.compact(2)?;
+        assert_eq!(sut.files.len(), 2);
+        assert_eq!(sut.len(), 8);
+        assert_eq!(spill_files(dir.path())?, 2);
+        sut.compact(0)?;
+        assert_eq!(sut.files.len(), 1);
+        assert_eq!(spill_files(dir.path())?, 1);
+        assert_eq!(sut.len(), 8);
+        assert!(sut.stats().bytes_merged > 0);
+
+        assert_eq!(sut.flush_all()?.count, 8);
+        // each record is spilled, and then compacted once or twice
+        let stats = sut.stats();
+        assert!(stats.records_spilled > 2 * 8 && stats.records_spilled <= 3 * 8);
+        assert_eq!(
+            stats.write_amplification(),
+            Some(stats.records_spilled as f64 / 8.0)
+        );
+        drop(sut);
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 0..8 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
 
-        Ok(DumpedCount(dumped))
+        Ok(())
     }
-}
 
-/// The number of dumped records
-pub(crate) struct DumpedCount(pub usize);
+    #[test]
+    fn skip_spill_files_past_watermark() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, test_support::config(2))?;
+        for ts in [10, 11, 1, 2] {
+            sut.push_record(record(ts))?;
+        }
+        assert_eq!(sut.files.len(), 2);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::prelude::IteratorRandom;
-    use rand::thread_rng;
+        let dumped = sut.dump_safe(Timestamp(5))?;
+        assert_eq!(dumped.count, 2);
+        assert_eq!(dumped.merge.readers, 1);
+        assert_eq!(sut.files.len(), 1);
+        assert_eq!(sut.earliest_buffered_timestamp, Some(Timestamp(10)));
+        assert_eq!(sut.dump_safe(Timestamp(9))?.count, 0);
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
+        let dumped = sut.dump_safe(Timestamp(10))?;
+        assert_eq!((dumped.count, dumped.merge.readers), (1, 1));
+        assert_eq!(sut.earliest_buffered_timestamp, Some(Timestamp(11)));
+        assert_eq!(sut.flush_all()?.count, 1);
 
-        fn in_memory_factory() -> in_memory::Buffer {
-            let mut buffer = in_memory::Buffer::with_capacity(256);
+        Ok(())
+    }
 
-            buffer.push(Record::A(DataA {
-                timestamp: Timestamp(5),
-                foo: "foo".to_string(),
-            }));
-            buffer.push(Record::C(DataC {
-                timestamp: Timestamp(2),
-                baz: (1, 2),
-            }));
-            buffer.push(Record::E(DataE {
-                timestamp: Timestamp(10),
-                def: vec![3, 1, 2],
-            }));
+    #[test]
+    fn pass_through_ordered_records() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
 
-            buffer
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(
+            dir.path(),
+            &mut writer,
+            Config {
+                max_in_memory: 8,
+                pass_through_ordered: true,
+                ..Default::default()
+            },
+        )?;
+        // nothing is known to be safe before the first dump
+        for ts in [2, 1, 2] {
+            assert_eq!(sut.push_record(record(ts))?, PushOutcome::Buffered);
         }
+        assert_eq!(sut.dump_safe(Timestamp(5))?.count, 3);
+        for ts in [3, 5] {
+            assert_eq!(sut.push_record(record(ts))?, PushOutcome::PassedThrough);
+        }
+        assert!(sut.is_empty());
+        // past the last dump, so it could still be preceded by a later push
+        assert_eq!(sut.push_record(record(7))?, PushOutcome::Buffered);
+        assert_eq!(sut.push_record(record(6))?, PushOutcome::Buffered);
+        sut.push_records([9, 8].map(record))?;
+        assert_eq!(sut.len(), 4);
+        assert_eq!(sut.stats().late_records, 0);
 
-        #[test]
-        fn dump_in_memory_and_read_from_disk() -> std::io::Result<()> {
-            let mut in_memory = in_memory_factory();
-            let file = tempfile::NamedTempFile::new().unwrap();
-
-            let file = in_memory
-                .drain_into_file(file.path())?
-                .expect("in-memory isn't empty");
-            let mut reader = file.read(8_192)?;
-
-            assert_eq!(in_memory.len(), 0);
-            assert_eq!(reader.last().unwrap().timestamp(), Timestamp(2));
-
-            reader.read_next()?;
-            assert_eq!(reader.last().unwrap().timestamp(), Timestamp(5));
+        // the passed through records are reported regardless of the timestamp
+        assert!(sut.has_safe_records(Timestamp(0)));
+        let dumped = sut.dump_safe(Timestamp(8))?;
+        assert_eq!(dumped.count, 5);
+        assert_eq!(dumped.range, Some(Timestamp(3)..=Timestamp(8)));
+        assert!(!sut.has_safe_records(Timestamp(8)));
+        assert!(sut.has_safe_records(Timestamp(9)));
+        // not passed through while anything is buffered
+        assert_eq!(sut.push_record(record(8))?, PushOutcome::Buffered);
+        assert_eq!(sut.push_record(record(10))?, PushOutcome::Buffered);
+        assert_eq!(sut.flush_all()?.count, 3);
+        // flushing doesn't advance the bound
+        assert_eq!(sut.push_record(record(11))?, PushOutcome::Buffered);
+        assert_eq!(sut.dump_safe(Timestamp(12))?.count, 1);
+        assert_eq!(sut.push_record(record(12))?, PushOutcome::PassedThrough);
+        let dumped = sut.flush_all()?;
+        assert_eq!(dumped.count, 1);
+        assert_eq!(dumped.merge, MergeReport::default());
+        drop(sut);
 
-            reader.read_next()?;
-            assert_eq!(reader.last().unwrap().timestamp(), Timestamp(10));
+        let mut reader = output::Reader::open(&output)?;
+        for ts in [1, 2, 2, 3, 5, 6, 7, 8, 8, 9, 10, 11, 12] {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
 
-            reader.read_next()?;
-            assert!(reader.last().is_none());
+        Ok(())
+    }
 
-            let file = reader.close()?;
-            assert!(file.is_empty());
+    #[test]
+    fn import_sorted_file() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
 
-            Ok(())
+        let sorted = dir.path().join("sorted");
+        let mut in_memory = in_memory::Buffer::with_capacity(3);
+        for ts in [7, 1, 4] {
+            in_memory.push(record(ts), 0);
         }
+        in_memory.drain_into_file(&sorted, IntEncoding::default(), SerErrorPolicy::default())?;
 
-        #[test]
-        fn reading_same_record_from_disk_repeatedly() -> std::io::Result<()> {
-            let mut in_memory = in_memory_factory();
-            let file = tempfile::NamedTempFile::new().unwrap();
-
-            let mut file = in_memory
-                .drain_into_file(file.path())?
-                .expect("in-memory isn't empty");
+        let mut writer = output::Writer::open(&output)?;
+        let mut sut = Buffer::new(dir.path(), &mut writer, Config::default())?;
+        sut.push_records([5, 2].map(record))?;
+        sut.import_sorted(&sorted, 3)?;
+        assert_eq!(sut.len(), 5);
+        assert_eq!(sut.earliest_buffered_timestamp(), Some(Timestamp(1)));
+        assert_eq!(sut.dump_safe(Timestamp(5))?.count, 4);
+        assert_eq!(sut.flush_all()?.count, 1);
 
-            for _ in 0..5 {
-                let reader = file.read(8_192)?;
-                assert_eq!(
-                    reader
-                        .last()
-                        .expect("we never call `read_next`")
-                        .timestamp(),
-                    Timestamp(2)
-                );
-                file = reader.close()?;
+        if cfg!(debug_assertions) {
+            let unsorted = dir.path().join("unsorted");
+            let mut file = std::fs::File::create(&unsorted)?;
+            codec::write_format_header(&mut file)?;
+            for (seq, ts) in [(0, 1), (1, 3), (2, 2)] {
+                let record = Sequenced {
+                    seq,
+                    record: record(ts),
+                    source: 0,
+                };
+                IntEncoding::default().serialize_into(&mut file, &record)?;
             }
+            let err = sut.import_sorted(&unsorted, 3).unwrap_err();
+            assert_matches!(Error::downcast(&err), Some(Error::NotSorted { index: 2 }));
+            assert!(sut.is_empty());
+        }
+        drop(sut);
 
-            let mut reader = file.read(8_192)?;
-            reader.read_next()?;
-            reader.read_next()?;
-            reader.read_next()?;
-            assert!(reader.last().is_none());
-
-            Ok(())
+        let mut reader = output::Reader::open(&output)?;
+        for ts in [1, 2, 4, 5, 7] {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
         }
+
+        Ok(())
     }
 
     #[test]
-    fn process_a_few_records_in_buffer() -> std::io::Result<()> {
+    fn reuse_spill_files() -> std::io::Result<()> {
         let dir = tempfile::tempdir()?;
+        let files_dir = dir.path().join("buffer");
         let output = dir.path().join("output");
+
         let mut writer = output::Writer::open(&output)?;
-        let mut reader = output::Reader::open(&output)?;
         let mut sut = Buffer::new(
-            dir.path(),
+            &files_dir,
             &mut writer,
             Config {
-                max_in_memory: 10,
-                file_read_buf_capacity: 8_192,
+                max_in_memory: 2,
+                spill_file_pool: 1,
+                ..Default::default()
             },
+        )?;
+        sut.push_records([3, 1, 4, 2].map(record))?;
+        assert_eq!(sut.flush_all()?.count, 4);
+
+        sut.push_record(record(6))?;
+        assert_matches!(
+            sut.push_record(record(5))?,
+            PushOutcome::Spilled {
+                file_id: 0 | 1,
+                records: 2
+            }
         );
+        sut.push_records([8, 7].map(record))?;
+        assert_eq!(sut.flush_all()?.count, 4);
+        drop(sut);
+        assert_eq!(std::fs::read_dir(&files_dir)?.count(), 3);
 
-        sut.push_record(Record::A(DataA {
-            timestamp: Timestamp(5),
-            foo: "foo".to_owned(),
-        }))?;
-        sut.push_record(Record::A(DataA {
-            timestamp: Timestamp(1),
-            foo: "foo".to_owned(),
-        }))?;
-        sut.push_record(Record::A(DataA {
-            timestamp: Timestamp(3),
-            foo: "foo".to_owned(),
-        }))?;
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 1..=8 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+
+        Ok(())
+    }
 
-        let DumpedCount(count) = sut.dump_safe(Timestamp(10))?;
-        assert_eq!(count, 3);
+    #[test]
+    fn buffers_share_a_dir() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = || test_support::config(2);
 
-        assert_eq!(reader.read().unwrap().timestamp(), Timestamp(1));
-        assert_eq!(reader.read().unwrap().timestamp(), Timestamp(3));
-        assert_eq!(reader.read().unwrap().timestamp(), Timestamp(5));
-        let _ = reader.read().unwrap_err();
+        let (tx_a, rx_a) = mpsc::channel();
+        let mut a = Buffer::new_channel(dir.path(), tx_a, config())?;
+        let (tx_b, rx_b) = mpsc::channel();
+        let mut b = Buffer::new_channel(dir.path(), tx_b, config())?;
+        a.push_record(record(1))?;
+        assert_eq!(
+            a.push_record(record(3))?,
+            PushOutcome::Spilled {
+                file_id: 0,
+                records: 2
+            }
+        );
+        b.push_record(record(2))?;
+        assert_eq!(
+            b.push_record(record(4))?,
+            PushOutcome::Spilled {
+                file_id: 1,
+                records: 2
+            }
+        );
+
+        let mut heap = BinaryHeap::from([Reverse(Sequenced {
+            seq: 0,
+            record: record(5),
+            source: 0,
+        })]);
+        let err = FileStorage::new(
+            &mut heap,
+            dir.path().join("dump-0"),
+            IntEncoding::default(),
+            SerErrorPolicy::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(heap.len(), 1);
+
+        a.flush_all()?;
+        b.flush_all()?;
+        let timestamps =
+            |rx: mpsc::Receiver<Record>| rx.try_iter().map(|x| x.timestamp().0).collect::<Vec<_>>();
+        assert_eq!(timestamps(rx_a), [1, 3]);
+        assert_eq!(timestamps(rx_b), [2, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_missing_buffer_dir() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let files_dir = dir.path().join("nested/buffer");
+        let mut writer = output::Writer::open(dir.path().join("output"))?;
+        let mut sut = Buffer::new(&files_dir, &mut writer, test_support::config(2))?;
+        assert!(files_dir.is_dir());
+        for ts in 0..4 {
+            sut.push_record(record(ts))?;
+        }
+        assert_eq!(std::fs::read_dir(&files_dir)?.count(), 2);
+        drop(sut);
+
+        // a file is in the way
+        let err = Buffer::new(dir.path().join("output"), &mut writer, Config::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("failed to create buffer dir"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_into_channel() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (tx, rx) = mpsc::channel();
+        let mut sut = Buffer::new_channel(dir.path(), tx, test_support::config(2))?;
+        for ts in [4, 1, 3, 0, 2] {
+            sut.push_record(record(ts))?;
+        }
+
+        assert_eq!(sut.dump_safe(Timestamp(2))?.count, 3);
+        let timestamps = rx.try_iter().map(|x| x.timestamp().0).collect::<Vec<_>>();
+        assert_eq!(timestamps, [0, 1, 2]);
+
+        drop(rx);
+        let err = sut.flush_all().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ndjson_spill_files() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (tx, rx) = mpsc::channel();
+        let mut sut = Buffer::new_channel(
+            dir.path(),
+            tx,
+            Config::builder()
+                .max_in_memory(2)
+                .ndjson_spills(true)
+                .build()
+                .unwrap(),
+        )?;
+        for ts in [4, 1, 3] {
+            sut.push_record(record(ts))?;
+        }
+
+        let spilled = std::fs::read(dir.path().join("dump-0"))?;
+        let lines = std::str::from_utf8(&spilled[codec::FORMAT_HEADER_LEN..])
+            .expect("JSON is UTF-8")
+            .lines()
+            .map(|line| serde_json::from_str::<Sequenced>(line).map(|x| x.timestamp().0))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(lines, [1, 4]);
+
+        assert_eq!(sut.dump_safe(Timestamp(4))?.count, 3);
+        let timestamps = rx.try_iter().map(|x| x.timestamp().0).collect::<Vec<_>>();
+        assert_eq!(timestamps, [1, 3, 4]);
 
         Ok(())
     }
@@ -503,20 +6373,19 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
         let output = dir.path().join("output");
         let mut writer = output::Writer::open(&output)?;
-        let mut sut = Buffer::new(
-            dir.path(),
-            &mut writer,
-            Config {
-                max_in_memory: 100_000,
-                file_read_buf_capacity: 8_192,
-            },
-        );
+        // spilled into memory, as it is the merge that is tested rather than the disk
+        let config = Config {
+            spill_factory: Some(SpillFactory::in_memory()),
+            ..test_support::config(100_000)
+        };
+        let mut sut = Buffer::new(dir.path(), &mut writer, config)?;
 
+        let mut rng = test_support::rng();
         for _ in 0..RECORDS {
             let record = Record::E(DataE {
                 timestamp: Timestamp(
                     (0..RECORDS as u128)
-                        .choose(&mut thread_rng())
+                        .choose(&mut rng)
                         .expect("there is a plenty of choice"),
                 ),
                 def: vec![],
@@ -525,7 +6394,8 @@ mod tests {
         }
 
         let count = sut.dump_safe(Timestamp(RECORDS as u128))?;
-        assert_eq!(count.0, RECORDS);
+        assert_eq!(count.count, RECORDS);
+        assert!(sut.stats().spills > 0);
 
         let mut reader = output::Reader::open(&output)?;
         let mut prev_ts = reader.read()?.timestamp();
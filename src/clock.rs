@@ -0,0 +1,97 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of the current time, see [`crate::BufferConfig::clock`].
+///
+/// Timestamps of the records are never compared to it: it only measures the wall-clock time
+/// between the events, e.g. for [`crate::BufferConfig::flush_interval`].
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// The actual time, i.e. [`Instant::now`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Manually advanced time, for tests.
+///
+/// It is shared with the tested code by wrapping it in an [`Arc`], so that the test could keep
+/// advancing it.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    step: Duration,
+}
+
+impl MockClock {
+    /// Create the clock, which stays still until advanced.
+    pub fn new() -> Self {
+        Self::with_step(Duration::ZERO)
+    }
+
+    /// Same as [`MockClock::new`], but the clock advances by `step` after each reading.
+    pub fn with_step(step: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            step,
+        }
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// Time elapsed since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        let now = self.start + *elapsed;
+        *elapsed += self.step;
+        now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_advances() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(3));
+
+        let clock = MockClock::with_step(Duration::from_millis(5));
+        let start = clock.now();
+        assert_eq!(clock.now() - start, Duration::from_millis(5));
+        assert_eq!(clock.elapsed(), Duration::from_millis(10));
+    }
+}
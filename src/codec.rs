@@ -0,0 +1,340 @@
+use crate::output::Error;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Read, Write};
+
+/// Files written by this crate (both the output and the on-disk buffers) start with these magic
+/// bytes, followed by [`FORMAT_VERSION`] (`u16` little-endian).
+///
+/// The magic bytes, read as an output frame length prefix, would be an implausibly large record,
+/// so files written before the header was introduced are never confused with the new ones.
+const FORMAT_MAGIC: [u8; 4] = *b"tsk\xff";
+
+/// Version of the files format, to be bumped whenever the layout of the files or of the records
+/// changes.
+pub(crate) const FORMAT_VERSION: u16 = 2;
+
+pub(crate) const FORMAT_HEADER_LEN: usize = FORMAT_MAGIC.len() + size_of::<u16>();
+
+pub(crate) fn write_format_header(mut writer: impl Write) -> std::io::Result<()> {
+    writer.write_all(&FORMAT_MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())
+}
+
+/// Read and validate the header written with [`write_format_header`].
+pub(crate) fn read_format_header(reader: impl Read) -> std::io::Result<()> {
+    read_format_version(reader, FORMAT_VERSION).map(drop)
+}
+
+/// Same as [`read_format_header`], but accepts the versions from `oldest` up to
+/// [`FORMAT_VERSION`], returning the version of the file.
+pub(crate) fn read_format_version(reader: impl Read, oldest: u16) -> std::io::Result<u16> {
+    let mut header = [0; FORMAT_HEADER_LEN];
+    let read = read_up_to(reader, &mut header)?;
+    if read < FORMAT_MAGIC.len() || !header.starts_with(&FORMAT_MAGIC) {
+        return Err(Error::MissingHeader.into());
+    }
+    if read < FORMAT_HEADER_LEN {
+        return Err(Error::TruncatedHeader.into());
+    }
+    let found = u16::from_le_bytes(
+        header[FORMAT_MAGIC.len()..]
+            .try_into()
+            .expect("length is right"),
+    );
+    if !(oldest..=FORMAT_VERSION).contains(&found) {
+        return Err(Error::IncompatibleFormat {
+            found,
+            expected: FORMAT_VERSION,
+        }
+        .into());
+    }
+    Ok(found)
+}
+
+/// Like [`Read::read_exact`], but returns the number of bytes read if the reader ends earlier.
+pub(crate) fn read_up_to(mut reader: impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+/// Tell the clean end of the data from a record cut off in the middle, given the number of bytes
+/// of the record that were read before the [`std::io::ErrorKind::UnexpectedEof`] error.
+///
+/// Other errors are returned as is.
+pub(crate) fn eof_or_truncated(err: std::io::Error, read: usize) -> std::io::Error {
+    if err.kind() != std::io::ErrorKind::UnexpectedEof || Error::downcast(&err).is_some() {
+        return err;
+    }
+    match read {
+        0 => Error::Eof.into(),
+        read => Error::TruncatedRecord { read }.into(),
+    }
+}
+
+/// Encoding of integers in serialised records.
+///
+/// The same encoding must be used for writing and reading the data.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IntEncoding {
+    /// Fixed-size little-endian integers, same as [`bincode::serialize_into`] does
+    #[default]
+    Fixint,
+    /// Variable-length integers, more compact for small values (e.g. timestamps)
+    Varint,
+}
+
+/// Format of the serialised records.
+///
+/// The same format must be used for writing and reading the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Compact binary records, see [`IntEncoding`]
+    Bincode(IntEncoding),
+    /// Newline-delimited JSON, one record per line, so that files could be inspected with `grep`
+    /// or `jq`.
+    ///
+    /// It is much larger and slower than [`Format::Bincode`], and is meant for debugging rather
+    /// than for production. The binary file header is still written before the records, so it
+    /// should be skipped, e.g. `tail -c +15 output | jq` for the output file.
+    Ndjson,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::Bincode(IntEncoding::default())
+    }
+}
+
+impl From<IntEncoding> for Format {
+    fn from(value: IntEncoding) -> Self {
+        Self::Bincode(value)
+    }
+}
+
+impl Format {
+    /// Serialise a value, followed by a newline for [`Format::Ndjson`]. Non-IO errors are returned
+    /// as [`Error::Serialization`], see [`SerErrorPolicy::handle`].
+    pub(crate) fn serialize_into<W: Write, T: Serialize + ?Sized>(
+        self,
+        mut writer: W,
+        value: &T,
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Bincode(encoding) => encoding.serialize_into(writer, value),
+            Self::Ndjson => {
+                serde_json::to_writer(&mut writer, value).map_err(unwrap_json_io_error)?;
+                writer.write_all(b"\n")
+            }
+        }
+    }
+
+    /// Number of bytes [`Format::serialize_into`] would write for the value.
+    pub(crate) fn serialized_size<T: Serialize + ?Sized>(self, value: &T) -> std::io::Result<u64> {
+        let mut counter = ByteCounter(0);
+        self.serialize_into(&mut counter, value)?;
+        Ok(counter.0)
+    }
+
+    /// Deserialise a value. Non-IO errors are returned as [`Error::Serialization`].
+    ///
+    /// For [`Format::Ndjson`], the line is read byte by byte, so that nothing after it is consumed,
+    /// and a line without the trailing newline is considered cut off, i.e.
+    /// [`std::io::ErrorKind::UnexpectedEof`].
+    pub(crate) fn deserialize_from<R: Read, T: DeserializeOwned>(
+        self,
+        mut reader: R,
+    ) -> std::io::Result<T> {
+        match self {
+            Self::Bincode(encoding) => encoding.deserialize_from(reader),
+            Self::Ndjson => {
+                let mut line = Vec::new();
+                let mut byte = [0];
+                loop {
+                    if read_up_to(&mut reader, &mut byte)? == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    match byte[0] {
+                        b'\n' => break,
+                        byte => line.push(byte),
+                    }
+                }
+                serde_json::from_slice(&line).map_err(unwrap_json_io_error)
+            }
+        }
+    }
+}
+
+/// Writer discarding the bytes, only counting them
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Limits of the serialised records.
+///
+/// The same config should be used by the [`Writer`](crate::output::Writer) and the
+/// [`Reader`](crate::output::Reader) of a file, and by the [`Buffer`](crate::Buffer) writing into
+/// it, so that the records let in by one of them are not rejected by another one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SerializationConfig {
+    /// Largest record allowed, in bytes as serialised (not counting the length prefix, or the
+    /// trailing newline of [`Format::Ndjson`]).
+    ///
+    /// [`None`] means no limit, other than what the length prefix of [`Format::Bincode`] could
+    /// tell, see [`SerializationConfig::effective_max_record_size`].
+    pub max_record_size: Option<usize>,
+}
+
+impl SerializationConfig {
+    /// Largest record allowed in the format, i.e. [`SerializationConfig::max_record_size`] capped
+    /// at [`u32::MAX`] bytes for [`Format::Bincode`], as the length prefix couldn't tell more.
+    pub fn effective_max_record_size(&self, format: Format) -> Option<usize> {
+        match format {
+            Format::Bincode(_) => Some(
+                self.max_record_size
+                    .map_or(u32::MAX as usize, |limit| limit.min(u32::MAX as usize)),
+            ),
+            Format::Ndjson => self.max_record_size,
+        }
+    }
+
+    /// Fail with [`Error::RecordTooLarge`] if a record of `size` bytes is larger than the
+    /// effective limit of the format.
+    pub(crate) fn check_record_size(&self, format: Format, size: u64) -> Result<(), Error> {
+        match self.effective_max_record_size(format) {
+            Some(limit) if size > limit as u64 => Err(Error::RecordTooLarge { size, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What to do when a record could not be serialised, e.g. because of a custom `Serialize`
+/// implementation failing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SerErrorPolicy {
+    /// Panic, as the error is considered a bug
+    #[default]
+    Panic,
+    /// Return [`Error::Serialization`]
+    PropagateError,
+    /// Skip the record, counting it, and go on with the next ones
+    SkipRecord,
+}
+
+impl SerErrorPolicy {
+    /// Apply the policy to the result of [`Format::serialize_into`].
+    ///
+    /// Returns [`None`] if the record should be skipped.
+    pub(crate) fn handle<T>(self, result: std::io::Result<T>) -> std::io::Result<Option<T>> {
+        match result {
+            Err(err) if matches!(Error::downcast(&err), Some(Error::Serialization { .. })) => {
+                match self {
+                    Self::Panic => panic!("{err}"),
+                    Self::PropagateError => Err(err),
+                    Self::SkipRecord => Ok(None),
+                }
+            }
+            result => result.map(Some),
+        }
+    }
+}
+
+impl IntEncoding {
+    /// Serialise a value. Non-IO errors are returned as [`Error::Serialization`], see
+    /// [`SerErrorPolicy::handle`].
+    pub(crate) fn serialize_into<W: Write, T: Serialize + ?Sized>(
+        self,
+        writer: W,
+        value: &T,
+    ) -> std::io::Result<()> {
+        let options = bincode::DefaultOptions::new().allow_trailing_bytes();
+        match self {
+            Self::Fixint => options.with_fixint_encoding().serialize_into(writer, value),
+            Self::Varint => options.with_varint_encoding().serialize_into(writer, value),
+        }
+        .map_err(unwrap_bincode_io_error)
+    }
+
+    /// Deserialise a value. Non-IO errors are returned as [`Error::Serialization`].
+    pub(crate) fn deserialize_from<R: Read, T: DeserializeOwned>(
+        self,
+        reader: R,
+    ) -> std::io::Result<T> {
+        let options = bincode::DefaultOptions::new().allow_trailing_bytes();
+        match self {
+            Self::Fixint => options.with_fixint_encoding().deserialize_from(reader),
+            Self::Varint => options.with_varint_encoding().deserialize_from(reader),
+        }
+        .map_err(unwrap_bincode_io_error)
+    }
+}
+
+// takes `bincode::Error` as is, so that it could be passed to `map_err` directly
+#[allow(clippy::boxed_local)]
+fn unwrap_bincode_io_error(err: bincode::Error) -> std::io::Error {
+    match *err {
+        bincode::ErrorKind::Io(err) => err,
+        other => Error::Serialization {
+            message: other.to_string(),
+        }
+        .into(),
+    }
+}
+
+fn unwrap_json_io_error(err: serde_json::Error) -> std::io::Error {
+    if err.is_io() {
+        err.into()
+    } else {
+        Error::Serialization {
+            message: err.to_string(),
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn ser_error_policy_handles_serialization_errors_only() {
+        let serialization = || -> std::io::Result<()> {
+            Err(Error::Serialization {
+                message: "oops".into(),
+            }
+            .into())
+        };
+        let io = || -> std::io::Result<()> { Err(std::io::ErrorKind::BrokenPipe.into()) };
+
+        assert_matches!(SerErrorPolicy::SkipRecord.handle(serialization()), Ok(None));
+        assert_matches!(SerErrorPolicy::SkipRecord.handle(Ok(())), Ok(Some(())));
+        let err = SerErrorPolicy::PropagateError
+            .handle(serialization())
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        for policy in [SerErrorPolicy::SkipRecord, SerErrorPolicy::Panic] {
+            let err = policy.handle(io()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+        std::panic::catch_unwind(|| SerErrorPolicy::Panic.handle(serialization())).unwrap_err();
+    }
+}
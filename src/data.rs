@@ -1,32 +1,62 @@
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize, Copy, Clone)]
 pub struct Timestamp(pub u128);
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+impl Timestamp {
+    /// Milliseconds since the Unix epoch.
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidInput`] if the time is earlier than the epoch.
+    pub fn from_millis_since_epoch(time: SystemTime) -> std::io::Result<Self> {
+        let since_epoch = time
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        Ok(Self::from_duration(since_epoch))
+    }
+
+    /// Whole milliseconds of the duration, e.g. elapsed since some start.
+    pub fn from_duration(duration: Duration) -> Self {
+        Self(duration.as_millis())
+    }
+
+    /// The inverse of [`Timestamp::from_duration`].
+    ///
+    /// Saturates at [`Duration::MAX`] if the timestamp doesn't fit into it.
+    pub fn as_duration(&self) -> Duration {
+        let secs = u64::try_from(self.0 / 1_000).unwrap_or(u64::MAX);
+        let nanos = (self.0 % 1_000) as u32 * 1_000_000;
+        Duration::new(secs, nanos)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DataA {
     pub timestamp: Timestamp,
-    pub foo: String,
+    /// Static strings are kept borrowed on their way through the channels and the in-memory
+    /// buffer, so that they are not allocated for each record. Records read from disk own it.
+    pub foo: Cow<'static, str>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DataB {
     pub timestamp: Timestamp,
     pub bar: bool,
 }
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DataC {
     pub timestamp: Timestamp,
     pub baz: (u32, u32),
 }
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DataD {
     pub timestamp: Timestamp,
     pub abc: (),
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DataE {
     pub timestamp: Timestamp,
     pub def: Vec<u16>,
@@ -35,7 +65,7 @@ pub struct DataE {
 /// Unification of all the data in a single enum.
 ///
 /// Implements ordering by [`Record::timestamp`].
-#[derive(Debug, Serialize, Deserialize, derive_more::From, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, derive_more::From, Eq, PartialEq)]
 pub enum Record {
     A(DataA),
     B(DataB),
@@ -44,7 +74,35 @@ pub enum Record {
     E(DataE),
 }
 
+/// Kind of a [`Record`], i.e. its variant without the data.
+///
+/// Could be used as an index, e.g. `kind as usize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RecordKind {
+    A,
+    B,
+    C,
+    D,
+    E,
+}
+
+impl RecordKind {
+    /// All the kinds, in the order of their indices
+    pub const ALL: [Self; 5] = [Self::A, Self::B, Self::C, Self::D, Self::E];
+}
+
 impl Record {
+    /// The kind of the record
+    pub fn kind(&self) -> RecordKind {
+        match self {
+            Self::A(_) => RecordKind::A,
+            Self::B(_) => RecordKind::B,
+            Self::C(_) => RecordKind::C,
+            Self::D(_) => RecordKind::D,
+            Self::E(_) => RecordKind::E,
+        }
+    }
+
     /// Unified method to access the timestamp
     pub fn timestamp(&self) -> Timestamp {
         match &self {
@@ -55,11 +113,40 @@ impl Record {
             Self::E(x) => x.timestamp,
         }
     }
+
+    /// Mutable access to the timestamp, e.g. to convert it into another unit
+    pub fn timestamp_mut(&mut self) -> &mut Timestamp {
+        match self {
+            Self::A(x) => &mut x.timestamp,
+            Self::B(x) => &mut x.timestamp,
+            Self::C(x) => &mut x.timestamp,
+            Self::D(x) => &mut x.timestamp,
+            Self::E(x) => &mut x.timestamp,
+        }
+    }
+}
+
+/// Identifier of the source of a record, e.g. the producer instance that sent it, see
+/// [`SourceTagged`]
+pub type SourceId = u32;
+
+/// A record along with the id of its source, which is carried along without affecting the
+/// order, see [`crate::Buffer::push_tagged`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SourceTagged<T> {
+    pub source: SourceId,
+    pub record: T,
+}
+
+impl SourceTagged<Record> {
+    pub fn timestamp(&self) -> Timestamp {
+        self.record.timestamp()
+    }
 }
 
 impl PartialOrd for Record {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.timestamp().partial_cmp(&other.timestamp())
+        Some(self.cmp(other))
     }
 }
 
@@ -68,3 +155,27 @@ impl Ord for Record {
         self.timestamp().cmp(&other.timestamp())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp_from_and_into_duration() -> std::io::Result<()> {
+        let duration = Duration::from_micros(1_500_700);
+        assert_eq!(Timestamp::from_duration(duration), Timestamp(1_500));
+        assert_eq!(Timestamp(1_500).as_duration(), Duration::from_millis(1_500));
+        assert_eq!(Timestamp(u128::MAX).as_duration().as_secs(), u64::MAX);
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(
+            Timestamp::from_millis_since_epoch(time)?,
+            Timestamp(1_700_000_000_123)
+        );
+        let err =
+            Timestamp::from_millis_since_epoch(UNIX_EPOCH - Duration::from_secs(1)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        Ok(())
+    }
+}
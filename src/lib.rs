@@ -1,15 +1,37 @@
 //! The main part of the solution is [`UnsortedDataSinkLoop`].
 //! [`SortedOutputListenLoop`] serves merely as an example of a reader.
+//! For the simple case of a single stream of records, there is [`sort_stream`], and
+//! [`output::sort_file`] for a file of unsorted records. Records from anywhere else are sorted
+//! with [`external_sort`].
 //!
 //! - [`UnsortedDataSinkLoop`]: this is a process of listening for five incoming channels
+//!
+//! ## Shutdown
+//!
+//! The loops end one after another, so that no record is left behind:
+//!
+//! 1. The producers drop their senders (and the sender of [`UnsortedDataSinkLoop::control`], if
+//!    set).
+//! 2. The sink dumps the rest of the buffered records, notifies about them, and drops the sender
+//!    of notifications.
+//! 3. The consumer reads the records of the remaining notifications, and ends on the
+//!    disconnection.
+//!
+//! Only then the output file and the buffer directory could be removed. [`run_to_completion`]
+//! runs both loops and returns once both of them have ended.
 
 use std::num::NonZero;
 use std::ops::ControlFlow;
-use std::path::Path;
-use std::sync::mpsc;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
 
 /// Buffering of records.
 mod buffer;
+/// Time source of the time-dependent features, injectable for deterministic tests.
+pub mod clock;
+/// Serialisation of records, shared by the output and the on-disk buffers.
+mod codec;
 /// Program data model.
 ///
 /// The five data structures are with a [`Timestamp`] and some other data are:
@@ -24,8 +46,20 @@ mod buffer;
 pub mod data;
 /// Simple abstractions for working with the output file, both from writing and reading ends.
 pub mod output;
+/// Helpers shared by the tests.
+#[cfg(test)]
+mod test_support;
 
-pub use buffer::Config as BufferConfig;
+#[cfg(feature = "mmap")]
+pub use buffer::MmapReader;
+pub use buffer::{
+    Buffer, Config as BufferConfig, ConfigBuilder as BufferConfigBuilder,
+    ConfigError as BufferConfigError, Cutoff, DumpedCount, Error as BufferError, FileStorage,
+    FileStorageReader, InMemoryStrategy, KMerge, MergeReport, MergeStrategy,
+    Progress as DumpProgress, PushOutcome, Sequenced, SortPlan, SpillFactory, SpillPolicy,
+    Stats as BufferStats, MINIMAL_MEMORY_MAX_IN_MEMORY,
+};
+pub use codec::{Format, IntEncoding, SerErrorPolicy, SerializationConfig};
 use data::*;
 
 pub type ReceiversTuple = (
@@ -36,128 +70,1788 @@ pub type ReceiversTuple = (
     mpsc::Receiver<DataE>,
 );
 
-pub struct NewRecordsAvailable(pub NonZero<usize>);
+/// The other ends of [`ReceiversTuple`], see [`output::replay`]
+pub type SendersTuple = (
+    mpsc::Sender<DataA>,
+    mpsc::Sender<DataB>,
+    mpsc::Sender<DataC>,
+    mpsc::Sender<DataD>,
+    mpsc::Sender<DataE>,
+);
 
+/// Sends records of any kind into the channel of their kind, e.g. to feed
+/// [`UnsortedDataSinkLoop`].
+#[derive(Debug, Clone)]
+pub struct Producer {
+    senders: SendersTuple,
+}
+
+impl Producer {
+    pub fn new(senders: SendersTuple) -> Self {
+        Self { senders }
+    }
+
+    /// Send the record into the channel of its kind.
+    ///
+    /// Fails if the receiver of the channel is dropped, giving the record back.
+    pub fn send(&self, record: impl Into<Record>) -> Result<(), mpsc::SendError<Record>> {
+        fn send<T: Into<Record>>(
+            sender: &mpsc::Sender<T>,
+            data: T,
+        ) -> Result<(), mpsc::SendError<Record>> {
+            sender
+                .send(data)
+                .map_err(|mpsc::SendError(data)| mpsc::SendError(data.into()))
+        }
+
+        match record.into() {
+            Record::A(x) => send(&self.senders.0, x),
+            Record::B(x) => send(&self.senders.1, x),
+            Record::C(x) => send(&self.senders.2, x),
+            Record::D(x) => send(&self.senders.3, x),
+            Record::E(x) => send(&self.senders.4, x),
+        }
+    }
+
+    /// Get the senders back, e.g. to drop some of them
+    pub fn into_senders(self) -> SendersTuple {
+        self.senders
+    }
+}
+
+impl From<SendersTuple> for Producer {
+    fn from(senders: SendersTuple) -> Self {
+        Self::new(senders)
+    }
+}
+
+/// Conversion of timestamps into a common unit, see [`UnsortedDataSinkLoop::normalizers`]
+pub type Normalizer = fn(Timestamp) -> Timestamp;
+
+/// Notification about new records written into the output
+pub struct NewRecordsAvailable {
+    pub count: NonZero<usize>,
+    /// Timestamp of the first new record
+    pub min_ts: Timestamp,
+    /// Timestamp of the last new record
+    pub max_ts: Timestamp,
+}
+
+/// Summary of [`UnsortedDataSinkLoop::run`], produced after the final dump
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    /// Number of records written into the output
+    pub records_written: u64,
+    /// Number of times the in-memory records were spilled on disk, see [`BufferStats::spills`]
+    pub total_spills: u64,
+    /// The last watermark, i.e. the earliest of the latest timestamps of the open inputs
+    pub final_watermark: Option<Timestamp>,
+    /// Number of records larger than [`BufferConfig::serialization`] allows, which are skipped
+    /// rather than failing the sink
+    pub records_rejected: u64,
+}
+
+/// How far an input channel is behind the others, see [`UnsortedDataSinkLoop::lag_metrics`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LagInfo {
+    /// Last timestamp received from the channel, [`None`] if it hasn't produced anything yet
+    pub last_timestamp: Option<Timestamp>,
+    /// Difference between the latest last timestamp among the channels and the one of this
+    /// channel, [`None`] if it hasn't produced anything yet
+    pub lag: Option<Timestamp>,
+    /// The channel is closed with [`Control::CloseInput`], so it doesn't pin the watermark
+    pub closed: bool,
+}
+
+/// Control messages for [`UnsortedDataSinkLoop`], see [`UnsortedDataSinkLoop::control`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Stop listening to the receiver of the given kind of records.
+    ///
+    /// Its last timestamp no longer constrains the watermark, so that a producer which has
+    /// finished early doesn't hold back records from the others. Records received from it
+    /// afterwards are discarded, and the sink doesn't wait for the producer to end. The receiver
+    /// is only dropped once the producer sends a record after the sink is finished, or ends.
+    CloseInput(RecordKind),
+    /// Stop dumping records, e.g. while the output file is swapped, until [`Control::Resume`].
+    ///
+    /// Records keep being received and buffered, but nothing is written into the output and no
+    /// notifications are sent. Thus, the buffer grows for the whole pause: spilling on disk once
+    /// the in-memory part is full, and applying backpressure if
+    /// [`BufferConfig::max_buffered_records`] is set. Since only channels that are ahead of the
+    /// watermark are blocked, the buffer could still exceed the cap. The output is only flushed by
+    /// dumps (see [`BufferConfig::flush_interval`]), so it stays as it was before the pause.
+    Pause,
+    /// Resume dumping after [`Control::Pause`], catching up with the watermark right away.
+    Resume,
+}
+
+/// Shared handle to the watermark of [`UnsortedDataSinkLoop`], see
+/// [`UnsortedDataSinkLoop::watermark`].
+///
+/// The watermark is the minimal last timestamp among the channels that are not closed
+/// explicitly, or [`u128::MAX`] once all of them are. No records earlier than it are expected, so
+/// the records up to it (shifted by [`BufferConfig::lateness_slack`]) are dumped into the output.
+#[derive(Debug, Clone, Default)]
+pub struct Watermark(Arc<Mutex<Option<Timestamp>>>);
+
+impl Watermark {
+    /// The current watermark, [`None`] until every channel has produced a record.
+    pub fn get(&self) -> Option<Timestamp> {
+        *self.0.lock().expect("not poisoned")
+    }
+
+    /// Whether records up to the timestamp are safe to dump already, i.e. no earlier records are
+    /// expected.
+    pub fn is_safe(&self, ts: Timestamp) -> bool {
+        self.get().is_some_and(|watermark| ts <= watermark)
+    }
+
+    fn set(&self, watermark: Option<Timestamp>) {
+        *self.0.lock().expect("not poisoned") = watermark;
+    }
+}
+
+/// What the forwarding threads pass to the sink
+enum Event {
+    /// Tagged with the index of its channel
+    Record(SourceTagged<Record>),
+    Control(Control),
+    /// The forwarding thread of the channel with the index ended, or the one of
+    /// [`UnsortedDataSinkLoop::control`] for [`None`]
+    Ended(Option<usize>),
+}
+
+/// Number of the input channels of [`UnsortedDataSinkLoop`], one per [`RecordKind`]
+const CHANNELS: usize = RecordKind::ALL.len();
+
+/// Spawn a forwarding thread per receiver, pushing their handles into `threads`, indexing the
+/// channels (and their normalizers) in the given order, and drop the sender afterwards.
+///
+/// The number of receivers is checked to be [`CHANNELS`] at compile time.
+macro_rules! sink_channels {
+    ($threads:ident, $tx:ident, $gate:expr, $normalizers:expr; $($rx:expr),+ $(,)?) => {
+        const _: () = assert!([$(sink_channels!(@unit $rx)),+].len() == CHANNELS);
+        sink_channels!(@spawn $threads, $tx, $gate, $normalizers, 0; $($rx),+);
+        drop($tx);
+    };
+    (
+        @spawn $threads:ident, $tx:ident, $gate:expr, $normalizers:expr, $idx:expr;
+        $rx:expr $(, $rest:expr)*
+    ) => {
+        let tx = $tx.clone();
+        let gate = Arc::clone(&$gate);
+        let normalize = $normalizers[$idx];
+        $threads.push(std::thread::spawn(move || {
+            channel_data_as_record($rx, tx, &gate, $idx, normalize)
+        }));
+        sink_channels!(@spawn $threads, $tx, $gate, $normalizers, $idx + 1; $($rest),*);
+    };
+    (@spawn $threads:ident, $tx:ident, $gate:expr, $normalizers:expr, $idx:expr;) => {};
+    (@unit $rx:expr) => {
+        ()
+    };
+}
+
+/// Internal channel bound between the forwarding threads and the sink.
+///
+/// Keeps the forwarding threads from draining producers faster than the sink can buffer records.
+const FORWARD_CHANNEL_BOUND: usize = 1_024;
+
+/// Listens for records from the five channels, buffers them, and dumps those that are safe to
+/// dump.
+///
+/// ## Backpressure
+///
+/// If [`BufferConfig::max_buffered_records`] is set and the buffer exceeds it, the forwarding
+/// threads stop draining those channels that are _ahead_ of the watermark (i.e. whose last
+/// timestamp is greater than the minimal one). Their producers block once their channels are full
+/// (e.g. when created with [`mpsc::sync_channel`]).
+///
+/// Channels that _pin_ the watermark (have the minimal last timestamp or haven't produced
+/// anything yet) are never blocked: only records from them could advance the watermark, which
+/// lets the buffer dump and shrink below the cap. Once it does, all channels are unblocked.
+///
+/// When any of the channels is closed, the watermark could no longer advance past its last
+/// timestamp, so backpressure is disabled entirely rather than deadlocking the producers. This
+/// doesn't apply to channels closed explicitly with [`Control::CloseInput`].
 pub struct UnsortedDataSinkLoop<'w, P> {
     pub receivers: ReceiversTuple,
     pub writer: &'w mut output::Writer,
+    /// Notified after each dump of new records.
+    ///
+    /// Once all the receivers are disconnected, the rest of the buffered records are dumped
+    /// (unless [`Control::Pause`]d), and the sender is dropped, so the consumer could tell the end
+    /// of the stream by [`mpsc::RecvError`], as [`SortedOutputListenLoop`] does.
     pub notify_new_records: mpsc::Sender<NewRecordsAvailable>,
     pub buffer_dir: P,
     pub buffer_config: BufferConfig,
+    /// Optional channel of [`Control`] messages.
+    ///
+    /// If set, the loop keeps running until its sender is dropped too.
+    pub control: Option<mpsc::Receiver<Control>>,
+    /// Optional handle to observe the watermark from other threads, updated on each record.
+    pub watermark: Option<Watermark>,
+    /// Optional conversion of the timestamps of each channel (in the order of
+    /// [`UnsortedDataSinkLoop::receivers`]) into a common unit, e.g. from microseconds into
+    /// milliseconds.
+    ///
+    /// Records are converted as soon as they are received, so everything else (the watermark, the
+    /// buffer and the output) sees the converted timestamps only. [`None`] means the timestamps of
+    /// all channels are in the same unit already.
+    pub normalizers: Option<[Normalizer; CHANNELS]>,
+    /// Optional channel to publish the [`LagInfo`] of each channel (in the order of
+    /// [`UnsortedDataSinkLoop::receivers`]) to, after each burst of records is processed.
+    ///
+    /// The watermark is pinned by the channel that lags the most (unless it is closed), so it
+    /// tells which producer holds back the dumps. Nothing is published once the receiver is
+    /// dropped.
+    pub lag_metrics: Option<mpsc::Sender<[LagInfo; CHANNELS]>>,
+    /// Optional output to write the records into along with the index of their channel (in the
+    /// order of [`UnsortedDataSinkLoop::receivers`]) as their [`SourceId`], instead of
+    /// [`UnsortedDataSinkLoop::writer`], which is left untouched then.
+    ///
+    /// It is read with [`output::Reader::with_records`], see [`Buffer::new_tagged`].
+    pub tagged_writer: Option<&'w mut output::Writer<SourceTagged<Record>>>,
 }
 
 impl<'w, P: AsRef<Path>> UnsortedDataSinkLoop<'w, P> {
-    pub fn run(mut self) {
-        std::thread::scope(|scope| {
-            let (tx, rx) = mpsc::channel::<Record>();
-
-            let tx1 = tx.clone();
-            scope.spawn(move || channel_data_as_record(self.receivers.0, tx1));
-            let tx1 = tx.clone();
-            scope.spawn(move || channel_data_as_record(self.receivers.1, tx1));
-            let tx1 = tx.clone();
-            scope.spawn(move || channel_data_as_record(self.receivers.2, tx1));
-            let tx1 = tx.clone();
-            scope.spawn(move || channel_data_as_record(self.receivers.3, tx1));
-            scope.spawn(move || channel_data_as_record(self.receivers.4, tx));
-
-            let mut buffer =
-                buffer::Buffer::new(&self.buffer_dir, &mut self.writer, self.buffer_config);
-            let mut last_timestamps: [Option<Timestamp>; 5] = [None; 5];
-
-            while let Ok(record) = rx.recv() {
-                let idx = match record {
-                    Record::A(_) => 0,
-                    Record::B(_) => 1,
-                    Record::C(_) => 2,
-                    Record::D(_) => 3,
-                    Record::E(_) => 4,
-                };
-                last_timestamps[idx] = Some(record.timestamp());
+    /// Run until all the receivers (and [`UnsortedDataSinkLoop::control`], if set) are
+    /// disconnected, even if none of them has produced anything, or until the receiver of
+    /// notifications is dropped.
+    ///
+    /// Fails if the buffer could not be created (see [`UnsortedDataSinkLoop::start`]), or once a
+    /// dump fails, see [`Sink::finish`].
+    pub fn run(self) -> std::io::Result<RunReport> {
+        self.start()?.finish()
+    }
+
+    /// Start forwarding the channels into a [`Sink`], without processing anything yet.
+    ///
+    /// Unlike [`UnsortedDataSinkLoop::run`], it lets the caller process records in bursts, e.g. to
+    /// rotate the output file between them with [`Sink::swap_output`]. Fails if the buffer could
+    /// not be created, e.g. the buffer directory or the config is invalid, see [`Buffer::new`].
+    pub fn start(self) -> std::io::Result<Sink<'w>> {
+        let max_buffered_records = self.buffer_config.max_buffered_records;
+        let max_notify_batch = self.buffer_config.max_notify_batch;
+        let buffer = match self.tagged_writer {
+            Some(writer) => Buffer::new_tagged(&self.buffer_dir, writer, self.buffer_config)?,
+            None => Buffer::new(&self.buffer_dir, self.writer, self.buffer_config)?,
+        };
 
-                buffer.push_record(record).expect("push should not fail");
+        let (tx, events) = mpsc::sync_channel::<Event>(FORWARD_CHANNEL_BOUND);
+        let gate = Arc::new(Backpressure::default());
+        // the channels first, so that the thread of each is at its index
+        let mut threads = Vec::with_capacity(CHANNELS + 1);
+        let control = self.control.map(|control| (control, tx.clone()));
+        let control_ended = control.is_none();
 
-                if let Some(ts) = find_earliest_timestamp(last_timestamps.into_iter()) {
-                    let buffer::DumpedCount(count) =
-                        buffer.dump_safe(ts).expect("dump should not fail");
-                    if let Some(count) = NonZero::new(count) {
-                        if let Err(_) = self.notify_new_records.send(NewRecordsAvailable(count)) {
-                            break;
-                        };
+        let normalizers = self.normalizers.unwrap_or([|ts| ts; CHANNELS]);
+        sink_channels!(
+            threads,
+            tx,
+            gate,
+            normalizers;
+            self.receivers.0,
+            self.receivers.1,
+            self.receivers.2,
+            self.receivers.3,
+            self.receivers.4,
+        );
+
+        if let Some((control, tx)) = control {
+            threads.push(std::thread::spawn(move || {
+                for message in control {
+                    if tx.send(Event::Control(message)).is_err() {
+                        return;
                     }
                 }
+                let _ = tx.send(Event::Ended(None));
+            }));
+        }
+
+        Ok(Sink {
+            events,
+            gate,
+            threads,
+            buffer,
+            notify_new_records: self.notify_new_records,
+            watermark: self.watermark,
+            lag_metrics: self.lag_metrics,
+            max_buffered_records,
+            max_notify_batch,
+            channels: [ChannelState::NeverSeen; CHANNELS],
+            ended: [false; CHANNELS],
+            control_ended,
+            paused: false,
+            finished: false,
+            report: RunReport::default(),
+        })
+    }
+}
+
+/// State of a started [`UnsortedDataSinkLoop`], see [`UnsortedDataSinkLoop::start`].
+///
+/// The forwarding threads and the buffer live as long as the sink does, so that records could be
+/// processed in bursts, with the output swapped in between.
+pub struct Sink<'w> {
+    events: mpsc::Receiver<Event>,
+    gate: Arc<Backpressure>,
+    threads: Vec<JoinHandle<()>>,
+    buffer: Buffer<'w>,
+    notify_new_records: mpsc::Sender<NewRecordsAvailable>,
+    watermark: Option<Watermark>,
+    lag_metrics: Option<mpsc::Sender<[LagInfo; CHANNELS]>>,
+    max_buffered_records: Option<usize>,
+    max_notify_batch: Option<NonZero<usize>>,
+    channels: [ChannelState; CHANNELS],
+    /// The forwarding thread of the channel ended, see [`Sink::inputs_ended`]
+    ended: [bool; CHANNELS],
+    /// See [`UnsortedDataSinkLoop::control`], ended from the start if there is none
+    control_ended: bool,
+    paused: bool,
+    /// All the inputs are disconnected, or the receiver of notifications is dropped
+    finished: bool,
+    report: RunReport,
+}
+
+impl<'w> Sink<'w> {
+    /// Wait for the next record (or [`Control`] message), and process it along with all the
+    /// others that are forwarded already, returning once there are no more of them.
+    ///
+    /// Returns `false` once the sink is finished, i.e. all the inputs are disconnected (and the
+    /// rest of the records are dumped) or the receiver of notifications is dropped.
+    ///
+    /// Fails if a dump fails. The records that were not written stay buffered (see
+    /// [`Buffer::dump_safe`]), so the next call dumps them again once the output is swapped with
+    /// [`Sink::swap_output`].
+    pub fn process_until_idle(&mut self) -> std::io::Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+        let mut next = self.events.recv();
+        while let Ok(event) = next {
+            if self.process(event)?.is_break() {
+                self.finished = true;
+                return Ok(false);
+            }
+            if self.inputs_ended() {
+                break;
+            }
+            next = match self.events.try_recv() {
+                Err(mpsc::TryRecvError::Empty) => {
+                    self.publish_lag();
+                    return Ok(true);
+                }
+                Err(mpsc::TryRecvError::Disconnected) => Err(mpsc::RecvError),
+                Ok(event) => Ok(event),
+            };
+        }
+
+        if !self.paused {
+            // no more records could arrive, so all of them are safe to dump, regardless of
+            // `BufferConfig::exclusive_watermark` and `BufferConfig::lateness_slack`
+            let _ = self.dump_and_notify(None)?;
+        }
+        self.publish_lag();
+        self.finished = true;
+        Ok(false)
+    }
+
+    /// Whether no more records could arrive: the forwarding thread of each channel ended, unless
+    /// the channel is closed, and so did the one of the control messages.
+    ///
+    /// The forwarding thread of a closed channel could still wait for its producer, so it is
+    /// neither waited for, nor are the events disconnected until it ends.
+    fn inputs_ended(&self) -> bool {
+        self.control_ended
+            && (self.ended.iter().zip(&self.channels))
+                .all(|(ended, state)| *ended || matches!(state, ChannelState::Closed { .. }))
+    }
+
+    /// See [`UnsortedDataSinkLoop::lag_metrics`]
+    fn publish_lag(&mut self) {
+        let Some(sender) = &self.lag_metrics else {
+            return;
+        };
+        let latest = self.channels.iter().filter_map(ChannelState::last).max();
+        let lags = self.channels.map(|state| {
+            let last = state.last();
+            LagInfo {
+                last_timestamp: last,
+                lag: last
+                    .zip(latest)
+                    .map(|(last, latest)| Timestamp(latest.0 - last.0)),
+                closed: matches!(state, ChannelState::Closed { .. }),
             }
         });
+        if sender.send(lags).is_err() {
+            self.lag_metrics = None;
+        }
+    }
+
+    /// Dump into another output file from now on, returning the previous one.
+    ///
+    /// The records buffered so far are written into the new file by the next dumps. The previous
+    /// file is flushed already, as each dump flushes the output.
+    ///
+    /// Panics if the sink writes into [`UnsortedDataSinkLoop::tagged_writer`], see
+    /// [`Sink::swap_tagged_output`] instead.
+    pub fn swap_output(&mut self, writer: &'w mut output::Writer) -> &'w mut output::Writer {
+        self.buffer
+            .swap_writer(writer)
+            .expect("the sink dumps into an untagged writer")
+    }
+
+    /// Same as [`Sink::swap_output`], but for [`UnsortedDataSinkLoop::tagged_writer`].
+    ///
+    /// Panics if the sink writes into [`UnsortedDataSinkLoop::writer`].
+    pub fn swap_tagged_output(
+        &mut self,
+        writer: &'w mut output::Writer<SourceTagged<Record>>,
+    ) -> &'w mut output::Writer<SourceTagged<Record>> {
+        self.buffer
+            .swap_tagged_writer(writer)
+            .expect("the sink dumps into a tagged writer")
+    }
+
+    /// Transform or filter out the records right before they are written, see
+    /// [`Buffer::set_map`]. Filtered out records are not notified about.
+    pub fn set_map(&mut self, map: impl FnMut(Record) -> Option<Record> + Send + 'w) {
+        self.buffer.set_map(map);
+    }
+
+    /// Process the records until the sink is finished (see [`Sink::process_until_idle`]), finish
+    /// the buffer (see [`Buffer::finish`]) and wait for the forwarding threads to end.
+    ///
+    /// Fails on the first failed dump or push (except for records that are too large, see
+    /// [`RunReport::records_rejected`]), still waiting for the forwarding threads.
+    pub fn finish(mut self) -> std::io::Result<RunReport> {
+        let result = loop {
+            match self.process_until_idle() {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.gate.disable();
+        self.report.total_spills = self.buffer.stats().spills;
+        let Self {
+            events,
+            threads,
+            buffer,
+            channels,
+            report,
+            ..
+        } = self;
+        // writes the late records, see `BufferConfig::late_output`
+        let result = result.and_then(|()| buffer.finish().map(drop));
+        // so that the forwarding threads end on their next record
+        drop(events);
+        for (idx, thread) in threads.into_iter().enumerate() {
+            if matches!(channels.get(idx), Some(ChannelState::Closed { .. })) {
+                // could wait for its producer forever, see `Control::CloseInput`
+                continue;
+            }
+            if let Err(panic) = thread.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+        result.map(|()| report)
+    }
+
+    fn process(&mut self, event: Event) -> std::io::Result<ControlFlow<()>> {
+        match event {
+            Event::Record(tagged) => {
+                let record = &tagged.record;
+                let channel = &mut self.channels[record.kind() as usize];
+                if matches!(channel, ChannelState::Closed { .. }) {
+                    // received after the channel was closed, see `Control::CloseInput`
+                    return Ok(ControlFlow::Continue(()));
+                }
+                channel.record(record.timestamp());
+                match self.buffer.push_tagged(tagged) {
+                    Err(err)
+                        if matches!(
+                            BufferError::downcast(&err),
+                            Some(BufferError::RecordTooLarge { .. })
+                        ) =>
+                    {
+                        self.report.records_rejected += 1;
+                    }
+                    result => {
+                        result?;
+                    }
+                }
+            }
+            Event::Control(Control::CloseInput(kind)) => {
+                self.channels[kind as usize].close();
+                self.gate.close(kind as usize);
+            }
+            Event::Control(Control::Pause) => self.paused = true,
+            Event::Control(Control::Resume) => self.paused = false,
+            Event::Ended(Some(idx)) => {
+                self.ended[idx] = true;
+                if !matches!(self.channels[idx], ChannelState::Closed { .. }) {
+                    // the watermark is stuck now, so blocking other channels could only deadlock
+                    self.gate.disable();
+                }
+            }
+            Event::Ended(None) => self.control_ended = true,
+        }
+
+        let watermark = find_earliest_timestamp(self.channels.into_iter());
+        if let Some(handle) = &self.watermark {
+            handle.set(watermark);
+        }
+        self.report.final_watermark = watermark;
+        // most records don't advance the watermark past the buffered ones, so the dump is skipped
+        if let Some(ts) = watermark.filter(|ts| !self.paused && self.buffer.has_safe_records(*ts)) {
+            if self.dump_and_notify(Some(ts))?.is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+
+        if let Some(cap) = self.max_buffered_records {
+            self.gate.update(if self.buffer.len() > cap {
+                // only the channels ahead of the watermark
+                self.channels.map(|state| match state {
+                    ChannelState::Seen(ts) => watermark.is_none_or(|watermark| ts > watermark),
+                    _ => false,
+                })
+            } else {
+                [false; CHANNELS]
+            });
+        }
+        Ok(ControlFlow::Continue(()))
     }
+
+    /// Dump the records that are safe to dump, notifying about each batch of at most
+    /// [`BufferConfig::max_notify_batch`] records.
+    ///
+    /// Without the safe timestamp, all the records are dumped, e.g. once the inputs are
+    /// disconnected. Breaks if the receiver of notifications is dropped.
+    fn dump_and_notify(&mut self, ts: Option<Timestamp>) -> std::io::Result<ControlFlow<()>> {
+        loop {
+            let dumped = match (ts, self.max_notify_batch) {
+                (Some(ts), Some(max)) => self.buffer.dump_safe_at_most(ts, max),
+                (Some(ts), None) => self.buffer.dump_safe(ts),
+                (None, Some(max)) => self.buffer.flush_all_at_most(max),
+                (None, None) => self.buffer.flush_all(),
+            }?;
+            self.report.records_written += dumped.count as u64;
+            let (Some(count), Some(range)) = (NonZero::new(dumped.count), dumped.range) else {
+                return Ok(ControlFlow::Continue(()));
+            };
+            let new_records = NewRecordsAvailable {
+                count,
+                min_ts: *range.start(),
+                max_ts: *range.end(),
+            };
+            if self.notify_new_records.send(new_records).is_err() {
+                return Ok(ControlFlow::Break(()));
+            }
+            // a full batch is followed by another one, unless nothing more is safe to dump
+            if self.max_notify_batch.is_none_or(|max| count < max)
+                || dumped
+                    .next_earliest
+                    .is_none_or(|next| ts.is_some_and(|ts| next > ts))
+            {
+                return Ok(ControlFlow::Continue(()));
+            }
+        }
+    }
+}
+
+/// Blocks forwarding of particular channels, see [`UnsortedDataSinkLoop`] docs.
+#[derive(Default)]
+struct Backpressure {
+    state: Mutex<BackpressureState>,
+    condvar: Condvar,
 }
 
-fn find_earliest_timestamp(
-    mut items: impl Iterator<Item = Option<Timestamp>>,
-) -> Option<Timestamp> {
-    if let ControlFlow::Continue(Some(value)) = items.try_fold(None, |acc, item| match item {
-        None => ControlFlow::Break(None::<Timestamp>),
-        Some(x) => ControlFlow::Continue(Some(acc.map_or(x, |y| std::cmp::min(x, y)))),
-    }) {
-        Some(value)
-    } else {
-        None
+#[derive(Default)]
+struct BackpressureState {
+    blocked: [bool; CHANNELS],
+    closed: [bool; CHANNELS],
+    disabled: bool,
+}
+
+impl Backpressure {
+    fn update(&self, blocked: [bool; CHANNELS]) {
+        let mut state = self.state.lock().expect("not poisoned");
+        if state.blocked != blocked {
+            state.blocked = blocked;
+            self.condvar.notify_all();
+        }
+    }
+
+    fn disable(&self) {
+        self.state.lock().expect("not poisoned").disabled = true;
+        self.condvar.notify_all();
+    }
+
+    fn close(&self, idx: usize) {
+        self.state.lock().expect("not poisoned").closed[idx] = true;
+        self.condvar.notify_all();
+    }
+
+    /// Returns `false` if the channel is closed.
+    fn wait_unblocked(&self, idx: usize) -> bool {
+        let state = self
+            .condvar
+            .wait_while(self.state.lock().expect("not poisoned"), |state| {
+                !state.disabled && !state.closed[idx] && state.blocked[idx]
+            })
+            .expect("not poisoned");
+        !state.closed[idx]
     }
 }
 
-fn channel_data_as_record<T: Into<Record>>(rx: mpsc::Receiver<T>, tx: mpsc::Sender<Record>) {
-    while let Ok(data) = rx.recv() {
-        if let Err(_) = tx.send(data.into()) {
+/// Sort a single stream of records into the output file.
+///
+/// Records are buffered until the channel is closed, and then dumped all at once. Spilled records
+/// are stored in a temporary directory next to the output file (`<output>.buffer`, or
+/// `<output>.buffer-<n>` if that one exists already), which is removed afterwards, even if sorting
+/// fails.
+///
+/// Returns the number of records written.
+pub fn sort_stream(
+    records: mpsc::Receiver<Record>,
+    output: &Path,
+    config: BufferConfig,
+) -> std::io::Result<u64> {
+    let buffer_dir = TempBufferDir::next_to(output)?;
+    let count = external_sort(records, output, buffer_dir.path(), config)?;
+    buffer_dir.remove()?;
+    Ok(count)
+}
+
+/// Buffer directory created for a single sort, removed once it is dropped.
+///
+/// It is `<output>.buffer` next to the output file, or `<output>.buffer-<n>` if that one exists
+/// already, so that a directory that isn't created here is never removed.
+pub(crate) struct TempBufferDir {
+    path: PathBuf,
+}
+
+impl TempBufferDir {
+    pub(crate) fn next_to(output: &Path) -> std::io::Result<Self> {
+        let mut base = output.as_os_str().to_owned();
+        base.push(".buffer");
+        let mut path = PathBuf::from(&base);
+        let mut n = 0;
+        loop {
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    n += 1;
+                    let mut next = base.clone();
+                    next.push(format!("-{n}"));
+                    path = next.into();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the directory, reporting the failure unlike dropping it.
+    pub(crate) fn remove(self) -> std::io::Result<()> {
+        let mut this = std::mem::ManuallyDrop::new(self);
+        std::fs::remove_dir_all(std::mem::take(&mut this.path))
+    }
+}
+
+impl Drop for TempBufferDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Sort the records into the output file, spilling them into `buffer_dir` as needed.
+///
+/// All the records are pushed into a [`Buffer`] first, and then dumped at once. The buffer
+/// directory is created if it doesn't exist, and the spill files are left in it, so that it is
+/// up to the caller to remove it, same as [`sort_stream`] does.
+///
+/// Returns the number of records written.
+pub fn external_sort<I: IntoIterator<Item = Record>>(
+    records: I,
+    output: &Path,
+    buffer_dir: &Path,
+    config: BufferConfig,
+) -> std::io::Result<u64> {
+    let mut writer = output::Writer::open(output)?;
+    let mut buffer = Buffer::new(buffer_dir, &mut writer, config)?;
+    buffer.push_records(records)?;
+    let DumpedCount { count, .. } = buffer.flush_all()?;
+    Ok(count as u64)
+}
+
+/// What the sink knows about an input channel, see [`find_earliest_timestamp`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChannelState {
+    /// Nothing is received yet, so the channel could still send records of any timestamp
+    NeverSeen,
+    /// The timestamp of the last received record
+    Seen(Timestamp),
+    /// The channel is closed with [`Control::CloseInput`], along with the timestamp of the last
+    /// record received before, which is only reported, see [`LagInfo`]
+    Closed { last: Option<Timestamp> },
+}
+
+impl ChannelState {
+    fn last(&self) -> Option<Timestamp> {
+        match *self {
+            Self::NeverSeen => None,
+            Self::Seen(ts) => Some(ts),
+            Self::Closed { last } => last,
+        }
+    }
+
+    fn record(&mut self, ts: Timestamp) {
+        *self = Self::Seen(ts);
+    }
+
+    fn close(&mut self) {
+        *self = Self::Closed { last: self.last() };
+    }
+}
+
+/// The watermark of the channels: the earliest of their last timestamps.
+///
+/// Closed channels are skipped, while the channels that haven't sent anything yet hold the
+/// watermark back. If all the channels are closed, everything is safe to dump, i.e. the watermark
+/// is [`u128::MAX`].
+fn find_earliest_timestamp(mut items: impl Iterator<Item = ChannelState>) -> Option<Timestamp> {
+    items.try_fold(Timestamp(u128::MAX), |acc, item| match item {
+        ChannelState::NeverSeen => None,
+        ChannelState::Seen(ts) => Some(acc.min(ts)),
+        ChannelState::Closed { .. } => Some(acc),
+    })
+}
+
+fn channel_data_as_record<T: Into<Record>>(
+    rx: mpsc::Receiver<T>,
+    tx: mpsc::SyncSender<Event>,
+    gate: &Backpressure,
+    idx: usize,
+    normalize: Normalizer,
+) {
+    loop {
+        if !gate.wait_unblocked(idx) {
+            // closed explicitly, the watermark is not affected
+            return;
+        }
+        // records received after the channel is closed are discarded by the sink
+        let Ok(data) = rx.recv() else {
+            break;
+        };
+        let mut record = data.into();
+        let ts = record.timestamp_mut();
+        *ts = normalize(*ts);
+        let tagged = SourceTagged {
+            source: idx as SourceId,
+            record,
+        };
+        if tx.send(Event::Record(tagged)).is_err() {
             break;
         }
     }
+    let _ = tx.send(Event::Ended(Some(idx)));
 }
 
+/// Reads the records of each [`NewRecordsAvailable`], checking their order, until the sender of
+/// notifications is dropped.
 pub struct SortedOutputListenLoop<'r> {
     pub reader: &'r mut output::Reader,
     pub notify_new_records: mpsc::Receiver<NewRecordsAvailable>,
+    /// Timestamp of the last record checked already, e.g. returned by the previous run of a
+    /// restarted consumer, so that the next records are checked against it. [`None`] to start
+    /// from scratch.
+    pub last_verified: Option<Timestamp>,
 }
 
 impl<'r> SortedOutputListenLoop<'r> {
-    pub fn run(self) {
-        while let Ok(NewRecordsAvailable(count)) = self.notify_new_records.recv() {
-            println!("reading next {count} records, ensuring their proper order");
-            let mut prev = self
-                .reader
-                .read()
-                .expect("must be available, count is non-zero")
-                .timestamp();
-            for _ in 1..count.get() {
-                let record = self.reader.read().expect("must be available");
+    /// Read the records of each notification, checking that they are in order, across the
+    /// notifications as well.
+    ///
+    /// Returns the timestamp of the last record checked, to resume from, see
+    /// [`SortedOutputListenLoop::last_verified`]. Panics if the records are out of order, see
+    /// [`SortedOutputListenLoop::run_with`].
+    pub fn run(self) -> Option<Timestamp> {
+        self.run_with(|_| ControlFlow::Continue(()))
+            .expect("notified records must be available and in order")
+    }
 
+    /// Read the records of each notification, passing them to the `handler` one by one.
+    ///
+    /// Stops once the `handler` breaks, leaving the reader right after the record it broke on,
+    /// or once the sender of notifications is dropped. Returns the timestamp of the last record
+    /// handled (or [`SortedOutputListenLoop::last_verified`] if there were none).
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidData`] if a record is earlier than the one before
+    /// it, or the records don't match the range of their notification, before the record is
+    /// handled.
+    pub fn run_with(
+        self,
+        mut handler: impl FnMut(&Record) -> ControlFlow<()>,
+    ) -> std::io::Result<Option<Timestamp>> {
+        let mut last = self.last_verified;
+        while let Ok(NewRecordsAvailable {
+            count,
+            min_ts,
+            max_ts,
+        }) = self.notify_new_records.recv()
+        {
+            #[cfg(feature = "tracing")]
+            tracing::info!(count, ?min_ts, ?max_ts, "reading new records");
+            let out_of_order =
+                |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+            let mut first = true;
+            for record in self.reader.by_ref_iter().take(count.get()) {
+                let record = record?;
                 let ts = record.timestamp();
-                assert!(ts >= prev);
-                prev = ts;
+                if first && ts != min_ts {
+                    return Err(out_of_order(format!(
+                        "notified records start at {ts:?} instead of {min_ts:?}"
+                    )));
+                }
+                if let Some(last) = last.filter(|last| ts < *last) {
+                    return Err(out_of_order(format!("{ts:?} is earlier than {last:?}")));
+                }
+                first = false;
+                last = Some(ts);
+                if handler(&record).is_break() {
+                    return Ok(last);
+                }
+            }
+            if last != Some(max_ts) {
+                return Err(out_of_order(format!(
+                    "notified records end at {last:?} instead of {max_ts:?}"
+                )));
             }
-            println!("checked all written records!");
+            #[cfg(feature = "tracing")]
+            tracing::info!("handled all written records");
         }
+        Ok(last)
     }
 }
 
+/// Run the sink on another thread and the consumer on this one, returning once both of them
+/// have ended, see the [shutdown sequence](crate#shutdown).
+///
+/// Since the consumer has read every notified record by then, the output file and the buffer
+/// directory could be removed right after it returns. Panics of either loop are propagated, and
+/// so are the errors of the sink, see [`UnsortedDataSinkLoop::run`]: the sender of notifications
+/// is dropped on an error too, so the consumer ends with the records notified so far.
+pub fn run_to_completion<P: AsRef<Path> + Send>(
+    sink: UnsortedDataSinkLoop<'_, P>,
+    consumer: SortedOutputListenLoop<'_>,
+) -> std::io::Result<(RunReport, Option<Timestamp>)> {
+    std::thread::scope(|scope| {
+        let sink = scope.spawn(move || sink.run());
+        let last_verified = consumer.run();
+        match sink.join() {
+            Ok(report) => Ok((report?, last_verified)),
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{self, channels, record, send_each_kind, sink};
+    use assert_matches::assert_matches;
+    use std::time::Duration;
+
+    #[test]
+    fn backpressure_does_not_deadlock_producers() -> std::io::Result<()> {
+        const RECORDS: u128 = 200;
+
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (tx_a, rx_a) = mpsc::sync_channel(0);
+        let (tx_b, rx_b) = mpsc::sync_channel(0);
+        let (tx_c, rx_c) = mpsc::sync_channel(0);
+        let (tx_d, rx_d) = mpsc::sync_channel(0);
+        let (tx_e, rx_e) = mpsc::sync_channel(0);
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for ts in 0..RECORDS {
+                    let timestamp = Timestamp(ts);
+                    let _ = tx_a.send(DataA {
+                        timestamp,
+                        foo: "".into(),
+                    });
+                }
+            });
+            scope.spawn(move || {
+                for ts in 0..RECORDS {
+                    let timestamp = Timestamp(ts);
+                    let _ = tx_b.send(DataB {
+                        timestamp,
+                        bar: true,
+                    });
+                }
+            });
+            scope.spawn(move || {
+                for ts in 0..RECORDS {
+                    let timestamp = Timestamp(ts);
+                    let _ = tx_c.send(DataC {
+                        timestamp,
+                        baz: (0, 0),
+                    });
+                }
+            });
+            scope.spawn(move || {
+                for ts in 0..RECORDS {
+                    let _ = tx_d.send(DataD {
+                        timestamp: Timestamp(ts),
+                        abc: (),
+                    });
+                }
+            });
+            scope.spawn(move || {
+                for ts in 0..RECORDS {
+                    let _ = tx_e.send(DataE {
+                        timestamp: Timestamp(ts),
+                        def: vec![],
+                    });
+                }
+            });
+
+            UnsortedDataSinkLoop {
+                buffer_config: BufferConfig {
+                    file_read_buf_capacity: 1_024,
+                    max_buffered_records: Some(8),
+                    ..test_support::config(16)
+                },
+                ..sink(
+                    (rx_a, rx_b, rx_c, rx_d, rx_e),
+                    &mut writer,
+                    notify_tx,
+                    dir.path(),
+                )
+            }
+            .run()
+            .unwrap();
+        });
+
+        let notified: usize = notify_rx.iter().map(|x| x.count.get()).sum();
+        assert!(notified > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn all_inputs_disconnected_at_startup() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (done_tx, done_rx) = mpsc::channel();
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let receivers = (
+            mpsc::channel().1,
+            mpsc::channel().1,
+            mpsc::channel().1,
+            mpsc::channel().1,
+            mpsc::channel().1,
+        );
+        let buffer_dir = dir.path().to_path_buf();
+
+        std::thread::spawn(move || {
+            let mut writer = output::Writer::open(buffer_dir.join("output")).unwrap();
+            sink(receivers, &mut writer, notify_tx, buffer_dir)
+                .run()
+                .unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        done_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the loop should end");
+        assert!(notify_rx.recv().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_start_without_buffer_dir() -> std::io::Result<()> {
+        let (_dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (_producer, receivers) = channels();
+
+        // the output file is in the way
+        let sink = sink(receivers, &mut writer, notify_tx, output.join("buffer")).start();
+        assert!(sink.is_err());
+        // so the consumer ends right away
+        assert!(notify_rx.recv().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn closed_input_does_not_hold_back_watermark() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (control_tx, control_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+
+        send_each_kind(&producer, 0..1);
+        for ts in 1..10 {
+            for kind in [RecordKind::B, RecordKind::C, RecordKind::D, RecordKind::E] {
+                producer.send(test_support::record_of(kind, ts)).unwrap();
+            }
+        }
+        // only the producer of A is alive
+        let (tx_a, ..) = producer.into_senders();
+        let watermark = Watermark::default();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                UnsortedDataSinkLoop {
+                    buffer_config: BufferConfig {
+                        file_read_buf_capacity: 1_024,
+                        ..test_support::config(16)
+                    },
+                    control: Some(control_rx),
+                    watermark: Some(watermark.clone()),
+                    ..sink(receivers, &mut writer, notify_tx, dir.path())
+                }
+                .run()
+            });
+
+            // nothing is dumped before the record of A is received, so it isn't lost on closing
+            let mut notified = notify_rx.recv().expect("sink is running").count.get();
+            assert_eq!(watermark.get(), Some(Timestamp(0)));
+            control_tx.send(Control::CloseInput(RecordKind::A)).unwrap();
+            drop(control_tx);
+
+            // the producer of A is still alive, but everything up to the last timestamp of the
+            // others is dumped
+            while notified < 41 {
+                let new = notify_rx.recv().expect("sink is running");
+                assert!(new.max_ts <= Timestamp(9));
+                notified += new.count.get();
+            }
+            assert_eq!(notified, 41);
+            assert!(watermark.is_safe(Timestamp(9)));
+            assert!(!watermark.is_safe(Timestamp(10)));
+            drop(tx_a);
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn closed_input_is_not_waited_for() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let (done_tx, done_rx) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let mut writer = output::Writer::open(output).unwrap();
+            let (notify_tx, _notify_rx) = mpsc::channel();
+            let (control_tx, control_rx) = mpsc::channel();
+            let (producer, receivers) = channels();
+            let mut sink = UnsortedDataSinkLoop {
+                control: Some(control_rx),
+                ..sink(receivers, &mut writer, notify_tx, dir.path())
+            }
+            .start()
+            .unwrap();
+            control_tx.send(Control::CloseInput(RecordKind::A)).unwrap();
+            drop(control_tx);
+            while sink.channels[RecordKind::A as usize] == ChannelState::NeverSeen {
+                assert!(sink.process_until_idle().unwrap());
+            }
+
+            // discarded by the sink
+            producer
+                .send(test_support::record_of(RecordKind::A, 0))
+                .unwrap();
+            for ts in 0..3 {
+                for kind in [RecordKind::B, RecordKind::C, RecordKind::D, RecordKind::E] {
+                    producer.send(test_support::record_of(kind, ts)).unwrap();
+                }
+            }
+            // the producer of A is kept alive
+            let (tx_a, ..) = producer.into_senders();
+            let report = sink.finish().unwrap();
+            done_tx.send(report).unwrap();
+            // the receiver is dropped once the next record is received after the sink is finished
+            let a = || DataA {
+                timestamp: Timestamp(0),
+                foo: "".into(),
+            };
+            while tx_a.send(a()).is_ok() {
+                std::thread::yield_now();
+            }
+        });
+
+        let report = done_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the sink should end while the closed producer is alive");
+        assert_eq!(report.records_written, 12);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pause_and_resume_dumping() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (control_tx, control_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        let watermark = Watermark::default();
+
+        let mut sink = UnsortedDataSinkLoop {
+            control: Some(control_rx),
+            watermark: Some(watermark.clone()),
+            ..sink(receivers, &mut writer, notify_tx, dir.path())
+        }
+        .start()?;
+
+        control_tx.send(Control::Pause).unwrap();
+        // the control message is forwarded by its own thread, so it could come after records
+        while !sink.paused {
+            assert!(sink.process_until_idle()?);
+        }
+        send_each_kind(&producer, 0..5);
+        while !watermark.is_safe(Timestamp(4)) {
+            assert!(sink.process_until_idle()?);
+        }
+        assert!(notify_rx.try_recv().is_err());
+
+        control_tx.send(Control::Resume).unwrap();
+        while sink.paused {
+            assert!(sink.process_until_idle()?);
+        }
+        let new = notify_rx.try_recv().expect("dumped once resumed");
+        assert_eq!(new.count.get(), 25);
+        assert_eq!(new.max_ts, Timestamp(4));
+        drop(control_tx);
+        drop(producer);
+        sink.finish()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn notify_in_limited_batches() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (control_tx, control_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        let watermark = Watermark::default();
+
+        let mut sink = UnsortedDataSinkLoop {
+            buffer_config: BufferConfig {
+                max_notify_batch: NonZero::new(10),
+                ..test_support::config(4)
+            },
+            control: Some(control_rx),
+            watermark: Some(watermark.clone()),
+            ..sink(receivers, &mut writer, notify_tx, dir.path())
+        }
+        .start()?;
+
+        // pile up records to dump them at once
+        control_tx.send(Control::Pause).unwrap();
+        while !sink.paused {
+            assert!(sink.process_until_idle()?);
+        }
+        send_each_kind(&producer, 0..5);
+        while !watermark.is_safe(Timestamp(4)) {
+            assert!(sink.process_until_idle()?);
+        }
+        control_tx.send(Control::Resume).unwrap();
+        drop(control_tx);
+        drop(producer);
+        sink.finish()?;
+
+        let mut reader = output::Reader::open(&output)?;
+        let mut counts = vec![];
+        for new in notify_rx {
+            let batch = reader
+                .by_ref_iter()
+                .take(new.count.get())
+                .collect::<std::io::Result<Vec<_>>>()?;
+            assert_eq!(batch.first().map(Record::timestamp), Some(new.min_ts));
+            assert_eq!(batch.last().map(Record::timestamp), Some(new.max_ts));
+            counts.push(new.count.get());
+        }
+        assert_eq!(counts, [10, 10, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_timestamps_of_channels() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        for ts in 0..10 {
+            for kind in RecordKind::ALL {
+                // in microseconds, unlike the others
+                let ts = if kind == RecordKind::A {
+                    ts * 1_000
+                } else {
+                    ts
+                };
+                producer.send(test_support::record_of(kind, ts)).unwrap();
+            }
+        }
+        drop(producer);
+
+        UnsortedDataSinkLoop {
+            normalizers: Some([
+                |ts| Timestamp(ts.0 / 1_000),
+                |ts| ts,
+                |ts| ts,
+                |ts| ts,
+                |ts| ts,
+            ]),
+            ..sink(receivers, &mut writer, notify_tx, dir.path())
+        }
+        .run()?;
+
+        let notified: usize = notify_rx.iter().map(|x| x.count.get()).sum();
+        assert_eq!(notified, 50);
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 0..10 {
+            for _ in RecordKind::ALL {
+                assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_too_large_records() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, _notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        send_each_kind(&producer, 0..3);
+        producer
+            .send(DataE {
+                timestamp: Timestamp(1),
+                def: vec![0; 100],
+            })
+            .unwrap();
+        drop(producer);
+
+        let report = UnsortedDataSinkLoop {
+            buffer_config: BufferConfig {
+                serialization: SerializationConfig {
+                    max_record_size: Some(64),
+                },
+                ..Default::default()
+            },
+            ..sink(receivers, &mut writer, notify_tx, dir.path())
+        }
+        .run()?;
+        assert_eq!(report.records_written, 15);
+        assert_eq!(report.records_rejected, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_latest_timestamp_at_the_end() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        send_each_kind(&producer, [1, u128::MAX]);
+        drop(producer);
+
+        let report = UnsortedDataSinkLoop {
+            buffer_config: BufferConfig {
+                exclusive_watermark: true,
+                lateness_slack: Timestamp(1),
+                max_notify_batch: NonZero::new(4),
+                ..Default::default()
+            },
+            ..sink(receivers, &mut writer, notify_tx, dir.path())
+        }
+        .run()?;
+        assert_eq!(report.records_written, 10);
+        let notified = notify_rx.iter().collect::<Vec<_>>();
+        assert!(notified.iter().all(|x| x.count.get() <= 4));
+        assert_eq!(
+            notified.last().map(|x| x.max_ts),
+            Some(Timestamp(u128::MAX))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fail_once_never_spilling_buffer_is_full() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, _notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        // nothing is safe to dump until the others send anything
+        for ts in 0..100 {
+            producer.send(record(ts)).unwrap();
+        }
+        drop(producer);
+
+        let err = UnsortedDataSinkLoop {
+            buffer_config: BufferConfig {
+                spill: SpillPolicy::NeverSpill,
+                ..test_support::config(16)
+            },
+            ..sink(receivers, &mut writer, notify_tx, dir.path())
+        }
+        .run()
+        .unwrap_err();
+        assert_matches!(
+            BufferError::downcast(&err),
+            Some(BufferError::CapacityExceeded { .. })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn swap_output_between_bursts() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut first = output::Writer::open(dir.path().join("first"))?;
+        let mut second = output::Writer::open(dir.path().join("second"))?;
+        let (notify_tx, _notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        let watermark = Watermark::default();
+
+        let mut sink = UnsortedDataSinkLoop {
+            watermark: Some(watermark.clone()),
+            ..sink(receivers, &mut first, notify_tx, dir.path())
+        }
+        .start()?;
+        send_each_kind(&producer, 0..5);
+        while watermark.get() != Some(Timestamp(4)) {
+            assert!(sink.process_until_idle().unwrap());
+        }
+        let first = sink.swap_output(&mut second);
+        assert_eq!(first.records_written(), Some(25));
+
+        send_each_kind(&producer, 5..10);
+        drop(producer);
+        let report = sink.finish()?;
+        assert_eq!(report.records_written, 50);
+        assert_eq!(second.records_written(), Some(25));
+        let mut reader = output::Reader::open(dir.path().join("second"))?;
+        assert_eq!(reader.read()?.timestamp(), Timestamp(5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tag_records_with_channel_index() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut untagged = output::Writer::open(dir.path().join("untagged"))?;
+        let mut tagged = output::Writer::open(&output)?.with_records();
+        let (notify_tx, _notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        send_each_kind(&producer, 0..4);
+        drop(producer);
+
+        let report = UnsortedDataSinkLoop {
+            tagged_writer: Some(&mut tagged),
+            ..sink(receivers, &mut untagged, notify_tx, dir.path())
+        }
+        .run()?;
+        assert_eq!(report.records_written, 20);
+        assert_eq!(untagged.records_written(), Some(0));
+        drop(tagged);
+
+        let sources = output::Reader::open(&output)?
+            .with_records::<SourceTagged<Record>>()
+            .try_iter()
+            .map(|x| x.map(|x| (x.record.kind() as SourceId, x.source)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(sources.len(), 20);
+        assert!(sources.iter().all(|(kind, source)| kind == source));
+        Ok(())
+    }
+
+    #[test]
+    fn dump_the_rest_at_the_end_of_inputs() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        for ts in 0..10 {
+            for kind in RecordKind::ALL {
+                if kind != RecordKind::A || ts < 3 {
+                    producer.send(test_support::record_of(kind, ts)).unwrap();
+                }
+            }
+        }
+        drop(producer);
+
+        let report = sink(receivers, &mut writer, notify_tx, dir.path()).run()?;
+        assert_eq!(report.records_written, 43);
+        assert_eq!(report.final_watermark, Some(Timestamp(2)));
+        assert!(report.total_spills > 0);
+
+        // the sender is dropped already, so the listener ends after reading everything
+        let mut reader = output::Reader::open(&output)?;
+        SortedOutputListenLoop {
+            reader: &mut reader,
+            notify_new_records: notify_rx,
+            last_verified: None,
+        }
+        .run();
+        assert_eq!(writer.records_written(), Some(43));
+        assert_eq!(reader.records_remaining()?, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_both_loops_to_completion() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let mut reader = output::Reader::open(&output)?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        let producer = std::thread::spawn(move || {
+            for ts in (0..100).rev() {
+                producer.send(record(ts)).unwrap();
+            }
+        });
+
+        let (report, last_verified) = run_to_completion(
+            sink(receivers, &mut writer, notify_tx, dir.path()),
+            SortedOutputListenLoop {
+                reader: &mut reader,
+                notify_new_records: notify_rx,
+                last_verified: None,
+            },
+        )?;
+        producer.join().unwrap();
+        assert_eq!(report.records_written, 100);
+        assert_eq!(last_verified, Some(Timestamp(99)));
+        // the consumer has read everything already
+        assert_eq!(reader.records_remaining()?, Some(0));
+        drop((writer, reader));
+        dir.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn publish_lag_of_channels() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let mut writer = output::Writer::open(&output)?;
+        let (notify_tx, _notify_rx) = mpsc::channel();
+        let (lag_tx, lag_rx) = mpsc::channel();
+        let (producer, receivers) = channels();
+        for ts in 0..10 {
+            producer
+                .send(test_support::record_of(RecordKind::A, ts))
+                .unwrap();
+        }
+        producer
+            .send(test_support::record_of(RecordKind::B, 2))
+            .unwrap();
+        drop(producer);
+
+        UnsortedDataSinkLoop {
+            lag_metrics: Some(lag_tx),
+            ..sink(receivers, &mut writer, notify_tx, dir.path())
+        }
+        .run()?;
+
+        let lags = lag_rx.iter().last().expect("published at the end at least");
+        assert_eq!(
+            lags[RecordKind::A as usize],
+            LagInfo {
+                last_timestamp: Some(Timestamp(9)),
+                lag: Some(Timestamp(0)),
+                closed: false,
+            }
+        );
+        assert_eq!(lags[RecordKind::B as usize].lag, Some(Timestamp(7)));
+        assert_eq!(lags[RecordKind::C as usize], LagInfo::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn listen_with_handler() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = output::Writer::open(file.path())?;
+        for ts in 0..10 {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+        let (notify_tx, notify_rx) = mpsc::channel();
+        for (count, min_ts, max_ts) in [(4, 0, 3), (6, 4, 9)] {
+            notify_tx
+                .send(NewRecordsAvailable {
+                    count: NonZero::new(count).unwrap(),
+                    min_ts: Timestamp(min_ts),
+                    max_ts: Timestamp(max_ts),
+                })
+                .unwrap();
+        }
+        drop(notify_tx);
+
+        let mut reader = output::Reader::open(file.path())?;
+        let mut handled = vec![];
+        let last = SortedOutputListenLoop {
+            reader: &mut reader,
+            notify_new_records: notify_rx,
+            last_verified: None,
+        }
+        .run_with(|record| {
+            handled.push(record.timestamp().0);
+            if record.timestamp() == Timestamp(6) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+        assert_eq!(handled, [0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(last, Some(Timestamp(6)));
+        assert_eq!(reader.read()?.timestamp(), Timestamp(7));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_order_across_notifications() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = output::Writer::open(file.path())?;
+        // each batch is sorted, but the second one starts before the first one ends
+        for ts in [3, 4, 2, 5] {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+        let listen = |last_verified, batches: &[(usize, u128, u128)]| {
+            let (notify_tx, notify_rx) = mpsc::channel();
+            for &(count, min_ts, max_ts) in batches {
+                notify_tx
+                    .send(NewRecordsAvailable {
+                        count: NonZero::new(count).unwrap(),
+                        min_ts: Timestamp(min_ts),
+                        max_ts: Timestamp(max_ts),
+                    })
+                    .unwrap();
+            }
+            drop(notify_tx);
+            let mut reader = output::Reader::open(file.path()).unwrap();
+            std::panic::catch_unwind(move || {
+                SortedOutputListenLoop {
+                    reader: &mut reader,
+                    notify_new_records: notify_rx,
+                    last_verified,
+                }
+                .run()
+            })
+        };
+
+        assert!(listen(None, &[(2, 3, 4), (2, 2, 5)]).is_err());
+        assert_eq!(listen(None, &[(2, 3, 4)]).unwrap(), Some(Timestamp(4)));
+        // resumed after a restart
+        assert!(listen(Some(Timestamp(4)), &[(1, 3, 3)]).is_err());
+        assert_eq!(listen(Some(Timestamp(3)), &[]).unwrap(), Some(Timestamp(3)));
+
+        // reported to the handler's caller rather than panicking, in release builds too
+        let (notify_tx, notify_rx) = mpsc::channel();
+        notify_tx
+            .send(NewRecordsAvailable {
+                count: NonZero::new(4).unwrap(),
+                min_ts: Timestamp(3),
+                max_ts: Timestamp(5),
+            })
+            .unwrap();
+        let mut reader = output::Reader::open(file.path())?;
+        let mut handled = vec![];
+        let err = SortedOutputListenLoop {
+            reader: &mut reader,
+            notify_new_records: notify_rx,
+            last_verified: None,
+        }
+        .run_with(|record| {
+            handled.push(record.timestamp().0);
+            ControlFlow::Continue(())
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(handled, [3, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn produce_into_channels_by_kind() {
+        let (producer, (rx_a, rx_b, rx_c, rx_d, rx_e)) = channels();
+
+        let timestamp = Timestamp(1);
+        producer.send(DataD { timestamp, abc: () }).unwrap();
+        producer
+            .send(Record::B(DataB {
+                timestamp,
+                bar: true,
+            }))
+            .unwrap();
+        assert_eq!(rx_d.try_recv(), Ok(DataD { timestamp, abc: () }));
+        assert_eq!(
+            rx_b.try_recv(),
+            Ok(DataB {
+                timestamp,
+                bar: true
+            })
+        );
+        assert!(rx_a.try_recv().is_err());
+        assert!(rx_c.try_recv().is_err());
+
+        drop(rx_e);
+        let record = || DataE {
+            timestamp,
+            def: vec![1],
+        };
+        assert_eq!(
+            producer.send(record()),
+            Err(mpsc::SendError(Record::E(record())))
+        );
+    }
+
+    #[test]
+    fn sort_records_of_iterator() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let buffer_dir = dir.path().join("buffer");
+        let records = [5, 3, 8, 1, 4, 2, 7, 6].map(record);
+
+        let count = external_sort(records, &output, &buffer_dir, test_support::config(3))?;
+        assert_eq!(count, 8);
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 1..=8 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+        assert!(buffer_dir.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sort_single_stream() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let (tx, rx) = mpsc::channel();
+        for ts in [5, 3, 8, 1, 4, 2, 7, 6] {
+            tx.send(record(ts)).unwrap();
+        }
+        drop(tx);
+
+        let count = sort_stream(
+            rx,
+            &output,
+            BufferConfig {
+                max_in_memory: 3,
+                file_read_buf_capacity: 1_024,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(count, 8);
+
+        let mut reader = output::Reader::open(&output)?;
+        for ts in 1..=8 {
+            assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+        }
+        assert!(!dir.path().join("output.buffer").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_existing_buffer_dir() -> std::io::Result<()> {
+        let (dir, output) = test_support::temp_output()?;
+        let existing = dir.path().join("output.buffer");
+        std::fs::create_dir(&existing)?;
+        std::fs::write(existing.join("data"), "of the caller")?;
+        let (tx, rx) = mpsc::channel();
+        for ts in [3, 1, 2] {
+            tx.send(record(ts)).unwrap();
+        }
+        drop(tx);
+
+        let count = sort_stream(rx, &output, test_support::config(1))?;
+        assert_eq!(count, 3);
+        assert_eq!(std::fs::read(existing.join("data"))?, b"of the caller");
+        assert!(!dir.path().join("output.buffer-1").exists());
+
+        // removed on errors too, e.g. if the output is a directory
+        let (_, rx) = mpsc::channel();
+        assert!(sort_stream(rx, dir.path(), test_support::config(1)).is_err());
+        let mut buffer_dir = dir.path().as_os_str().to_owned();
+        buffer_dir.push(".buffer");
+        assert!(!Path::new(&buffer_dir).exists());
+
+        Ok(())
+    }
 
     #[test]
     fn find_min_timestamp() {
-        let items = [None, Some(Timestamp(45))];
+        use ChannelState::*;
+
+        let items = [NeverSeen, Seen(Timestamp(45))];
         assert_eq!(find_earliest_timestamp(items.into_iter()), None);
 
-        let items = [Some(Timestamp(0)), None, Some(Timestamp(45))];
+        let items = [Seen(Timestamp(0)), NeverSeen, Seen(Timestamp(45))];
         assert_eq!(find_earliest_timestamp(items.into_iter()), None);
 
         let items = [
-            Some(Timestamp(100)),
-            Some(Timestamp(5)),
-            Some(Timestamp(45)),
+            Seen(Timestamp(100)),
+            Seen(Timestamp(5)),
+            Seen(Timestamp(45)),
         ];
         assert_eq!(
             find_earliest_timestamp(items.into_iter()),
             Some(Timestamp(5))
         );
+
+        // closed channels don't hold the watermark back, whatever they sent
+        let items = [
+            Seen(Timestamp(100)),
+            Closed { last: None },
+            Closed {
+                last: Some(Timestamp(5)),
+            },
+        ];
+        assert_eq!(
+            find_earliest_timestamp(items.into_iter()),
+            Some(Timestamp(100))
+        );
+        let items = [
+            Closed {
+                last: Some(Timestamp(5)),
+            },
+            NeverSeen,
+        ];
+        assert_eq!(find_earliest_timestamp(items.into_iter()), None);
+
+        // everything is safe to dump once all the channels are closed
+        let items = [Closed { last: None }, Closed { last: None }];
+        assert_eq!(
+            find_earliest_timestamp(items.into_iter()),
+            Some(Timestamp(u128::MAX))
+        );
     }
 }
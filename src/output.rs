@@ -1,101 +1,2616 @@
-use crate::data::Record;
+use crate::buffer::Config as BufferConfig;
+use crate::codec::{
+    self, read_up_to, Format, IntEncoding, SerErrorPolicy, SerializationConfig, FORMAT_HEADER_LEN,
+    FORMAT_VERSION,
+};
+use crate::data::{Record, RecordKind, Timestamp};
+use crate::{Producer, SendersTuple};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Each record in the output file is prefixed with its length, encoded as `u32` little-endian.
+///
+/// It allows detecting records that were only partially written (e.g. on a crash). Records of
+/// [`Format::Ndjson`] are not prefixed, as they end with a newline instead.
+const FRAME_PREFIX_LEN: usize = size_of::<u32>();
+
+/// The output file starts with a header: the format header (magic bytes and version) followed by
+/// the number of records in the file (`u64` little-endian).
+const HEADER_LEN: usize = FORMAT_HEADER_LEN + size_of::<u64>();
+
+/// Oldest version of the format whose output files are read, see [`upgrade_record`]
+const OLDEST_READABLE_VERSION: u16 = 1;
+
+/// Capacity of the read and write buffers, same as the default of [`BufReader`]/[`BufWriter`]
+const DEFAULT_BUF_CAPACITY: usize = 8 * 1024;
+
+/// The records count in the header of files written by [`Writer::from_write`], which could not
+/// update the header.
+const UNKNOWN_COUNT: u64 = u64::MAX;
+
+/// The checksum trailing the records, see [`Writer::with_checksum`], is a `u64` little-endian.
+const CHECKSUM_LEN: usize = size_of::<u64>();
+
+/// Number of records between the offsets in the index file, see [`Writer::with_index`]
+const INDEX_INTERVAL: u64 = 1024;
+
+/// Errors specific to the output file format.
+///
+/// They are returned wrapped into [`std::io::Error`], see [`Error::downcast`].
+#[derive(Debug)]
+pub enum Error {
+    /// The file ended cleanly, right before a record.
+    Eof,
+    /// The file ended in the middle of a record.
+    TruncatedRecord {
+        /// The number of bytes of the record (including its length prefix) that were read
+        read: usize,
+    },
+    /// The file doesn't start with the header, e.g. it was written by an older version.
+    MissingHeader,
+    /// The file ended in the middle of the header.
+    TruncatedHeader,
+    /// The file is written in a different version of the format.
+    IncompatibleFormat { found: u16, expected: u16 },
+    /// The offset to resume reading from is not at a record boundary of the file, see
+    /// [`Reader::resume_from`].
+    InvalidOffset { offset: u64 },
+    /// A record could not be serialised or deserialised, see [`SerErrorPolicy`].
+    Serialization { message: String },
+    /// The record is larger than the limit, see
+    /// [`SerializationConfig::effective_max_record_size`].
+    RecordTooLarge {
+        /// Size of the serialised record, in bytes
+        size: u64,
+        limit: usize,
+    },
+}
+
+impl Error {
+    /// Get the error wrapped into [`std::io::Error`], if it is there.
+    pub fn downcast(err: &std::io::Error) -> Option<&Self> {
+        err.get_ref().and_then(|x| x.downcast_ref())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eof => write!(f, "file ended at a record boundary"),
+            Self::TruncatedRecord { read } => {
+                write!(
+                    f,
+                    "file ended in the middle of a record, after {read} bytes"
+                )
+            }
+            Self::MissingHeader => write!(f, "file doesn't start with the format header"),
+            Self::TruncatedHeader => write!(f, "file ended in the middle of the format header"),
+            Self::IncompatibleFormat { found, expected } => write!(
+                f,
+                "file format version is {found}, but only {expected} is supported"
+            ),
+            Self::InvalidOffset { offset } => {
+                write!(f, "offset {offset} is not at a record boundary of the file")
+            }
+            Self::Serialization { message } => {
+                write!(f, "failed to (de)serialise a record: {message}")
+            }
+            Self::RecordTooLarge { size, limit } => {
+                write!(
+                    f,
+                    "record of {size} bytes is larger than the limit of {limit}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        let kind = match value {
+            Error::Eof | Error::TruncatedRecord { .. } | Error::TruncatedHeader => {
+                std::io::ErrorKind::UnexpectedEof
+            }
+            Error::MissingHeader
+            | Error::IncompatibleFormat { .. }
+            | Error::Serialization { .. } => std::io::ErrorKind::InvalidData,
+            Error::InvalidOffset { .. } | Error::RecordTooLarge { .. } => {
+                std::io::ErrorKind::InvalidInput
+            }
+        };
+        std::io::Error::new(kind, value)
+    }
+}
 
 /// Write records into the output file.
+///
+/// The file format (the header, the framing of the records) doesn't depend on the records, so the
+/// writer could write any serialisable values, e.g. for other sorted files. The buffer only writes
+/// [`Record`]s, which is the default.
 #[derive(Debug)]
-pub struct Writer {
-    buf_writer: BufWriter<File>,
+pub struct Writer<T = Record> {
+    buf_writer: BufWriter<Sink>,
+    format: Format,
+    ser_error_policy: SerErrorPolicy,
+    frame: Vec<u8>,
+    /// See [`Writer::with_serialization`]
+    serialization: SerializationConfig,
+    /// [`None`] if the count is unknown, see [`Writer::records_written`]
+    records_written: Option<u64>,
+    records_skipped: u64,
+    /// See [`Writer::with_per_kind_files`]
+    per_kind: Option<PerKind<T>>,
+    /// See [`Writer::open_atomic`], taken once the file is renamed
+    atomic: Option<AtomicRename>,
+    /// See [`Writer::with_checksum`]
+    checksum: Option<Checksum>,
+    /// See [`Writer::with_index`]
+    index: Option<IndexWriter>,
+    records: PhantomData<fn(&T)>,
 }
 
-impl Writer {
+/// The temporary file of [`Writer::open_atomic`] and the path it is renamed to
+#[derive(Debug)]
+struct AtomicRename {
+    temp: PathBuf,
+    target: PathBuf,
+}
+
+impl Writer<Record> {
     /// Open the writer.
+    ///
+    /// The file header is written immediately, so that readers could open the file right away.
     pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
-        Ok(Self {
-            buf_writer: BufWriter::new(
-                OpenOptions::new()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .open(path)?,
-            ),
-        })
+        Self::open_with_capacity(path, DEFAULT_BUF_CAPACITY)
+    }
+
+    /// Open the writer with the specified capacity of the write buffer.
+    pub fn open_with_capacity(path: impl AsRef<Path>, capacity: usize) -> std::io::Result<Self> {
+        Self::create(path, capacity)
+    }
+
+    /// Create the writer over an arbitrary sink, e.g. a pipe or a socket.
+    ///
+    /// Since the sink might be not seekable, the records count in the header is never updated,
+    /// and readers consider it unknown.
+    pub fn from_write<W: Write + Send + 'static>(mut sink: W) -> std::io::Result<Self> {
+        write_header(&mut sink, UNKNOWN_COUNT)?;
+        Ok(Self::new(
+            Sink::Stream(Box::new(sink)),
+            Some(0),
+            DEFAULT_BUF_CAPACITY,
+        ))
+    }
+
+    /// Open the writer, appending records to the existing file (if there is).
+    ///
+    /// Appending only preserves the global order of the file if the caller guarantees that the new
+    /// records are not earlier than the last one already in the file.
+    ///
+    /// The count of records continues from the one in the file header. If the file was written
+    /// with [`Writer::from_write`], the count is unknown, see [`Writer::records_written`].
+    pub fn open_append(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        let records_written = if file.metadata()?.len() == 0 {
+            write_header(&mut file, 0)?;
+            Some(0)
+        } else {
+            Some(read_header(&mut file)?).filter(|count| *count != UNKNOWN_COUNT)
+        };
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self::new(
+            Sink::File(file),
+            records_written,
+            DEFAULT_BUF_CAPACITY,
+        ))
+    }
+
+    /// Open the writer over a temporary file next to `path` (the same name with `.tmp` appended),
+    /// which is only renamed to `path` by [`Writer::finalize`].
+    ///
+    /// The rename is atomic, so readers (e.g. a job looking for completed files in the directory)
+    /// never see a partially written file at `path`. If the writer is dropped without being
+    /// finalized, including by [`Writer::into_reader`], the temporary file is removed. Per-kind
+    /// files are written in place, see [`Writer::with_per_kind_files`].
+    pub fn open_atomic(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let target = path.as_ref().to_path_buf();
+        let Some(name) = target.file_name() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a file path", target.display()),
+            ));
+        };
+        let mut temp = name.to_os_string();
+        temp.push(".tmp");
+        let temp = target.with_file_name(temp);
+        let mut writer = Self::create(&temp, DEFAULT_BUF_CAPACITY)?;
+        writer.atomic = Some(AtomicRename { temp, target });
+        Ok(writer)
+    }
+
+    /// Write other values than [`Record`]s, e.g. for other sorted files. They are read back with
+    /// [`Reader::with_records`].
+    ///
+    /// Other values have no [`RecordKind`], so the per-kind files (see
+    /// [`Writer::with_per_kind_files`]) are flushed and closed, and only the records written so
+    /// far are in them.
+    pub fn with_records<U>(mut self) -> Writer<U> {
+        // the dummy sink is flushed on drop instead
+        let buf_writer = std::mem::replace(
+            &mut self.buf_writer,
+            BufWriter::new(Sink::Stream(Box::new(std::io::sink()))),
+        );
+        Writer {
+            buf_writer,
+            format: self.format,
+            ser_error_policy: self.ser_error_policy,
+            frame: Vec::new(),
+            serialization: self.serialization,
+            records_written: self.records_written,
+            records_skipped: self.records_skipped,
+            // flushed on drop
+            per_kind: None,
+            atomic: self.atomic.take(),
+            checksum: self.checksum.take(),
+            index: self.index.take(),
+            records: PhantomData,
+        }
+    }
+
+    /// Also write records of each kind into a separate file next to `path`, see [`kind_path`].
+    ///
+    /// It allows consumers to read only the kinds they need. Each file is a regular output file
+    /// with the same format, opened lazily on the first record of its kind, and flushed along
+    /// with this writer. Since records are written in order, they are sorted in those files too.
+    pub fn with_per_kind_files(mut self, path: impl AsRef<Path>) -> Self {
+        self.per_kind = Some(PerKind {
+            path: path.as_ref().to_path_buf(),
+            kind: Record::kind,
+            writers: RecordKind::ALL.iter().map(|_| None).collect(),
+        });
+        self
+    }
+}
+
+impl<T> Writer<T> {
+    /// Create the file, truncating it if it exists.
+    fn create(path: impl AsRef<Path>, capacity: usize) -> std::io::Result<Self> {
+        // readable as well, see `Writer::into_reader`
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        write_header(&mut file, 0)?;
+        Ok(Self::new(Sink::File(file), Some(0), capacity))
+    }
+
+    fn new(sink: Sink, records_written: Option<u64>, capacity: usize) -> Self {
+        Self {
+            buf_writer: BufWriter::with_capacity(capacity, sink),
+            format: Format::default(),
+            ser_error_policy: SerErrorPolicy::default(),
+            frame: Vec::new(),
+            serialization: SerializationConfig::default(),
+            records_written,
+            records_skipped: 0,
+            per_kind: None,
+            atomic: None,
+            checksum: None,
+            index: None,
+            records: PhantomData,
+        }
+    }
+
+    /// Total number of records in the file, if known.
+    pub fn records_written(&self) -> Option<u64> {
+        self.records_written
+    }
+
+    /// Set the integer encoding of the written records, same as [`Writer::with_format`] with
+    /// [`Format::Bincode`].
+    ///
+    /// The file must be read with the same encoding, see [`Reader::with_int_encoding`].
+    pub fn with_int_encoding(self, encoding: IntEncoding) -> Self {
+        self.with_format(encoding.into())
+    }
+
+    /// Set the format of the written records, e.g. [`Format::Ndjson`] for debugging.
+    ///
+    /// The file must be read with the same format, see [`Reader::with_format`].
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set what to do with records that could not be serialised.
+    ///
+    /// Records are serialised before writing anything, so skipping them doesn't corrupt the file.
+    pub fn with_ser_error_policy(mut self, policy: SerErrorPolicy) -> Self {
+        self.ser_error_policy = policy;
+        self
+    }
+
+    /// Set the limits of the written records. Records over them fail with
+    /// [`Error::RecordTooLarge`], writing nothing of them.
+    ///
+    /// The file should be read with the same config, see [`Reader::with_serialization`].
+    pub fn with_serialization(mut self, config: SerializationConfig) -> Self {
+        self.serialization = config;
+        self
+    }
+
+    /// See [`Writer::with_serialization`]
+    pub fn serialization(&self) -> &SerializationConfig {
+        &self.serialization
+    }
+
+    /// Number of records skipped, see [`SerErrorPolicy::SkipRecord`]
+    pub fn records_skipped(&self) -> u64 {
+        self.records_skipped
+    }
+
+    /// Append a checksum of the written records to the file on [`Writer::finalize`], to check the
+    /// integrity of the whole file with [`verify_checksum`].
+    ///
+    /// It must be set before writing any records, and the file must not be appended to (see
+    /// [`Writer::open_append`]) afterwards. Readers stop at the records count in the header, so
+    /// they never read the checksum as a record.
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = Some(Checksum::new());
+        self
+    }
+
+    /// Also write an index of the record offsets into the file at `path` (see [`index_path`]),
+    /// for [`Reader::scan_range`] to binary-search through.
+    ///
+    /// The index has the offset of every 1024th record. It must be set before writing any
+    /// records, and the file must not be appended to (see [`Writer::open_append`]) afterwards.
+    /// The index is flushed along with this writer, right after the records.
+    pub fn with_index(mut self, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(index_path(path))?);
+        file.write_all(&INDEX_INTERVAL.to_le_bytes())?;
+        self.index = Some(IndexWriter {
+            file,
+            position: HEADER_LEN as u64,
+            records: 0,
+        });
+        Ok(self)
+    }
+
+    /// Flush buffered data, and update the records count in the file header.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.buf_writer.flush()?;
+        if let (Some(count), Sink::File(file)) = (self.records_written, self.buf_writer.get_mut()) {
+            file.seek(SeekFrom::Start(FORMAT_HEADER_LEN as u64))?;
+            file.write_all(&count.to_le_bytes())?;
+            file.seek(SeekFrom::End(0))?;
+        }
+        // after the records, so that the index never has offsets past them
+        if let Some(index) = self.index.as_mut() {
+            index.file.flush()?;
+        }
+        for writer in self.per_kind_writers() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush the writer and read the file from the start, with the same format.
+    ///
+    /// Unlike opening the file with [`Reader::open`] once more, it reads exactly the file that was
+    /// written. Fails with [`std::io::ErrorKind::Unsupported`] for the writers created with
+    /// [`Writer::from_write`].
+    pub fn into_reader(mut self) -> std::io::Result<Reader<T>> {
+        self.flush()?;
+        let format = self.format;
+        // the dummy sink is flushed on drop instead
+        let buf_writer = std::mem::replace(
+            &mut self.buf_writer,
+            BufWriter::new(Sink::Stream(Box::new(std::io::sink()))),
+        );
+        let Sink::File(mut file) = buf_writer.into_inner().map_err(|err| err.into_error())? else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only a file could be read back",
+            ));
+        };
+        file.rewind()?;
+        Ok(Reader::from_file(file, DEFAULT_BUF_CAPACITY)?.with_format(format))
     }
 
+    /// Same as [`Writer::flush`], but also makes sure the written data reaches the disk.
+    ///
+    /// Sinks passed to [`Writer::from_write`] are only flushed.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        if let Sink::File(file) = self.buf_writer.get_mut() {
+            file.sync_data()?;
+        }
+        for writer in self.per_kind_writers() {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Finish writing: append the checksum (see [`Writer::with_checksum`]), make sure everything
+    /// reaches the disk, same as [`Writer::sync`], and rename the file to its final path (see
+    /// [`Writer::open_atomic`]).
+    pub fn finalize(mut self) -> std::io::Result<()> {
+        if let Some(checksum) = self.checksum.take() {
+            // past the records, and thus past the count in the header
+            self.flush()?;
+            self.buf_writer.write_all(&checksum.0.to_le_bytes())?;
+        }
+        self.sync()?;
+        if let Some(AtomicRename { temp, target }) = self.atomic.take() {
+            std::fs::rename(temp, target)?;
+        }
+        Ok(())
+    }
+
+    fn per_kind_writers(&mut self) -> impl Iterator<Item = &mut Writer<T>> {
+        self.per_kind
+            .iter_mut()
+            .flat_map(|x| x.writers.iter_mut().flatten())
+    }
+}
+
+impl<T: Serialize> Writer<T> {
     /// Write a record into the file, without caring about ordering.
-    pub fn write(&mut self, record: &Record) -> std::io::Result<()> {
-        if let Err(err) = bincode::serialize_into(&mut self.buf_writer, record) {
-            match *err {
-                bincode::ErrorKind::Io(err) => return Err(err),
-                other => panic!("intentionally not covering serialisation errors: {other}"),
+    pub fn write(&mut self, record: &T) -> std::io::Result<()> {
+        self.frame.clear();
+        if !self.encode(record)? {
+            return Ok(());
+        }
+        self.buf_writer.write_all(&self.frame)?;
+        if let Some(checksum) = self.checksum.as_mut() {
+            checksum.update(&self.frame);
+        }
+        self.written(record, self.frame.len())
+    }
+
+    /// Write a batch of records, same as [`Writer::write`] does for each of them.
+    ///
+    /// The whole batch is serialised first and then written at once, so that large batches go
+    /// past the write buffer in a single syscall. The serialised batch is kept in memory, so
+    /// its size should be bounded by the caller.
+    pub fn write_all(&mut self, records: &[T]) -> std::io::Result<()> {
+        self.frame.clear();
+        // the lengths of the encoded records, for the index
+        let encoded = records
+            .iter()
+            .map(|record| {
+                let start = self.frame.len();
+                let encoded = self.encode(record)?;
+                Ok(encoded.then(|| self.frame.len() - start))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        self.buf_writer.write_all(&self.frame)?;
+        if let Some(checksum) = self.checksum.as_mut() {
+            checksum.update(&self.frame);
+        }
+        for (record, len) in records.iter().zip(encoded) {
+            if let Some(len) = len {
+                self.written(record, len)?;
             }
         }
         Ok(())
     }
 
-    /// Flush buffered data.
+    /// Append the record to the frame, prefixed with its length for [`Format::Bincode`].
+    ///
+    /// Returns `false` if the record is skipped, see [`SerErrorPolicy::SkipRecord`].
+    fn encode(&mut self, record: &T) -> std::io::Result<bool> {
+        let start = self.frame.len();
+        let prefixed = matches!(self.format, Format::Bincode(_));
+        if prefixed {
+            self.frame.extend_from_slice(&[0; FRAME_PREFIX_LEN]);
+        }
+        let result = self.format.serialize_into(&mut self.frame, record);
+        if let skipped @ (Ok(None) | Err(_)) = self.ser_error_policy.handle(result) {
+            // whatever was serialised before the error
+            self.frame.truncate(start);
+            skipped?;
+            self.records_skipped += 1;
+            return Ok(false);
+        }
+        // not counting the length prefix, or the trailing newline of `Format::Ndjson`
+        let size = self.frame.len() - start - if prefixed { FRAME_PREFIX_LEN } else { 1 };
+        if let Err(err) = self
+            .serialization
+            .check_record_size(self.format, size as u64)
+        {
+            self.frame.truncate(start);
+            return Err(err.into());
+        }
+        if prefixed {
+            // fits, as the effective limit of the format is checked above
+            let len = size as u32;
+            self.frame[start..start + FRAME_PREFIX_LEN].copy_from_slice(&len.to_le_bytes());
+        }
+        Ok(true)
+    }
+
+    /// Account for the record of `len` bytes written into the file
+    fn written(&mut self, record: &T, len: usize) -> std::io::Result<()> {
+        if let Some(count) = self.records_written.as_mut() {
+            *count += 1;
+        }
+        if let Some(index) = self.index.as_mut() {
+            index.written(len)?;
+        }
+        if let Some(per_kind) = self.per_kind.as_mut() {
+            let kind = (per_kind.kind)(record);
+            let writer = &mut per_kind.writers[kind as usize];
+            let writer = match writer {
+                Some(writer) => writer,
+                None => writer.insert(
+                    Writer::create(kind_path(&per_kind.path, kind), DEFAULT_BUF_CAPACITY)?
+                        .with_format(self.format)
+                        .with_ser_error_policy(self.ser_error_policy),
+                ),
+            };
+            writer.write(record)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for Writer<T> {
+    fn drop(&mut self) {
+        let Some(atomic) = self.atomic.take() else {
+            let _ = self.flush();
+            return;
+        };
+        // not finalized, so the partial file is never renamed, and there is nothing to flush
+        let buf_writer = std::mem::replace(
+            &mut self.buf_writer,
+            BufWriter::new(Sink::Stream(Box::new(std::io::sink()))),
+        );
+        drop(buf_writer.into_parts());
+        let _ = std::fs::remove_file(atomic.temp);
+    }
+}
+
+/// FNV-1a hash of the bytes of the records, see [`Writer::with_checksum`]
+#[derive(Debug, Clone, Copy)]
+struct Checksum(u64);
+
+impl Checksum {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Check the checksum at the end of the file, written by [`Writer::with_checksum`].
+///
+/// Returns `false` if the records don't match it, including if the file has no checksum. Fails
+/// if the file doesn't start with a valid header.
+pub fn verify_checksum(path: impl AsRef<Path>) -> std::io::Result<bool> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    read_header(&mut reader)?;
+    let Some(records_len) = len.checked_sub((HEADER_LEN + CHECKSUM_LEN) as u64) else {
+        return Ok(false);
+    };
+
+    let mut checksum = Checksum::new();
+    let mut records = (&mut reader).take(records_len);
+    loop {
+        let buf = records.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        checksum.update(buf);
+        let consumed = buf.len();
+        records.consume(consumed);
+    }
+    let mut expected = [0; CHECKSUM_LEN];
+    reader.read_exact(&mut expected)?;
+    Ok(checksum.0 == u64::from_le_bytes(expected))
+}
+
+/// Files with records of each kind, see [`Writer::with_per_kind_files`]
+#[derive(Debug)]
+struct PerKind<T> {
+    path: PathBuf,
+    /// Kind of the record, i.e. [`Record::kind`]
+    kind: fn(&T) -> RecordKind,
+    /// Indexed by the kind
+    writers: Vec<Option<Writer<T>>>,
+}
+
+/// Index of the record offsets in the output file, see [`Writer::with_index`].
+///
+/// The file is the interval between the indexed records, followed by the offsets of the records
+/// at each interval starting from the first one, all `u64` little-endian.
+#[derive(Debug)]
+struct IndexWriter {
+    file: BufWriter<File>,
+    /// Byte offset of the next record in the output file
+    position: u64,
+    /// Number of records written into the output file
+    records: u64,
+}
+
+impl IndexWriter {
+    /// Account for the record of `len` bytes written into the output file
+    fn written(&mut self, len: usize) -> std::io::Result<()> {
+        if self.records.is_multiple_of(INDEX_INTERVAL) {
+            self.file.write_all(&self.position.to_le_bytes())?;
+        }
+        self.records += 1;
+        self.position += len as u64;
+        Ok(())
+    }
+}
+
+/// Offsets of the records in the output file, read from its index, see [`Writer::with_index`]
+struct Index {
+    /// Number of records between the offsets
+    interval: u64,
+    offsets: Vec<u64>,
+}
+
+impl Index {
+    /// Read the index of the output file at `path`, if there is one.
+    ///
+    /// An offset that is written only partially is left out.
+    fn read(path: impl AsRef<Path>) -> std::io::Result<Option<Self>> {
+        let bytes = match std::fs::read(index_path(path)) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            result => result?,
+        };
+        let mut words = bytes
+            .chunks_exact(size_of::<u64>())
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()));
+        Ok(words
+            .next()
+            .filter(|interval| *interval > 0)
+            .map(|interval| Self {
+                interval,
+                offsets: words.collect(),
+            }))
+    }
+}
+
+/// Path of the index of the output file at `path`, e.g. `output.idx` for `output`. See
+/// [`Writer::with_index`].
+pub fn index_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".idx");
+    path.into()
+}
+
+/// Path of the file with records of the `kind` only, written along with the output file at
+/// `path`, e.g. `output.A` for `output`. See [`Writer::with_per_kind_files`].
+pub fn kind_path(path: impl AsRef<Path>, kind: RecordKind) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(format!(".{kind:?}"));
+    path.into()
+}
+
+/// Read the output file at `path` and send each record into the sender of its kind, e.g. to feed a
+/// recorded run back through [`crate::UnsortedDataSinkLoop`] to reproduce a bug.
+///
+/// The senders are dropped at the end, disconnecting the channels. Returns the number of records
+/// sent. Fails with [`std::io::ErrorKind::BrokenPipe`] if the receiver of a record is dropped.
+pub fn replay(path: impl AsRef<Path>, senders: SendersTuple) -> std::io::Result<u64> {
+    let producer = Producer::new(senders);
+    let mut reader = Reader::open(path)?;
+    let mut count = 0;
+    for record in reader.try_iter() {
+        producer.send(record?).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "receiver of replayed records is dropped",
+            )
+        })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Sort the records of the output file at `input`, e.g. written in the order they arrived, into
+/// the output file at `output`.
+///
+/// Records are sorted by [`crate::external_sort`], spilling into a temporary directory next to
+/// the output file, same as by [`crate::sort_stream`], which is removed afterwards, even if
+/// sorting fails. The input could be the output file itself: the records are sorted into
+/// `<output>.sorted` then, which only replaces the input once it is written whole, and is removed
+/// otherwise.
+///
+/// Returns the number of records written.
+pub fn sort_file(input: &Path, output: &Path, config: BufferConfig) -> std::io::Result<u64> {
+    let mut reader = Reader::open(input)?;
+    let in_place =
+        output.exists() && std::fs::canonicalize(input)? == std::fs::canonicalize(output)?;
+    let sorted = if in_place {
+        let mut sorted = output.as_os_str().to_owned();
+        sorted.push(".sorted");
+        PathBuf::from(sorted)
+    } else {
+        output.to_path_buf()
+    };
+    let buffer_dir = crate::TempBufferDir::next_to(output)?;
+
+    let mut read_error = None;
+    let records = reader
+        .try_iter()
+        .map_while(|record| record.map_err(|err| read_error = Some(err)).ok());
+    let count = crate::external_sort(records, &sorted, buffer_dir.path(), config);
+    let result = read_error.map_or(count, Err).and_then(|count| {
+        buffer_dir.remove()?;
+        if in_place {
+            std::fs::rename(&sorted, output)?;
+        }
+        Ok(count)
+    });
+    if result.is_err() && in_place {
+        // the input is left as it was
+        let _ = std::fs::remove_file(&sorted);
+    }
+    result
+}
+
+/// Identifier of a shard, see [`Shards`]
+pub type ShardId = u64;
+
+/// Output split into multiple files (shards) by the timestamps of records, e.g. one per hour.
+///
+/// Each shard file is written by its own [`Writer`], opened lazily on its first record. As long
+/// as records are written in the global order, each shard is sorted as well.
+#[derive(Debug)]
+pub struct Shards {
+    dir: PathBuf,
+    partition: fn(Timestamp) -> ShardId,
+    writers: BTreeMap<ShardId, Writer>,
+}
+
+impl Shards {
+    /// Shard files are created in `dir`, see [`Shards::path`].
+    pub fn new(dir: impl AsRef<Path>, partition: fn(Timestamp) -> ShardId) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            partition,
+            writers: BTreeMap::new(),
+        }
+    }
+
+    /// Path of the shard file
+    pub fn path(&self, shard: ShardId) -> PathBuf {
+        shard_path(&self.dir, shard)
+    }
+
+    /// Shards that have been written into so far, in ascending order
+    pub fn shards(&self) -> impl Iterator<Item = ShardId> + '_ {
+        self.writers.keys().copied()
+    }
+
+    /// Write the record into its shard, opening the shard file if it is not yet.
+    pub fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        let shard = (self.partition)(record.timestamp());
+        let writer = match self.writers.entry(shard) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Writer::open(shard_path(&self.dir, shard))?),
+        };
+        writer.write(record)
+    }
+
+    /// Flush all the opened shards, see [`Writer::flush`].
     pub fn flush(&mut self) -> std::io::Result<()> {
-        self.buf_writer.flush()
+        for writer in self.writers.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn shard_path(dir: &Path, shard: ShardId) -> PathBuf {
+    dir.join(format!("shard-{shard}"))
+}
+
+/// Where the [`Writer`] writes to
+enum Sink {
+    File(File),
+    Stream(Box<dyn Write + Send>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Stream(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Stream(stream) => stream.flush(),
+        }
+    }
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(file) => f.debug_tuple("File").field(file).finish(),
+            Self::Stream(_) => f.debug_tuple("Stream").finish_non_exhaustive(),
+        }
+    }
+}
+
+fn write_header(mut sink: impl Write, count: u64) -> std::io::Result<()> {
+    codec::write_format_header(&mut sink)?;
+    sink.write_all(&count.to_le_bytes())
+}
+
+/// Read and validate the header, returning the records count in it.
+///
+/// Only the current version of the format is accepted, e.g. to append records of the current
+/// shape to the file.
+fn read_header(reader: impl Read) -> std::io::Result<u64> {
+    read_versioned_header(reader, FORMAT_VERSION).map(|(_, count)| count)
+}
+
+/// Same as [`read_header`], but accepts the versions from `oldest`, returning the version too.
+fn read_versioned_header(mut reader: impl Read, oldest: u16) -> std::io::Result<(u16, u64)> {
+    let version = codec::read_format_version(&mut reader, oldest)?;
+    let mut count = [0; size_of::<u64>()];
+    if read_up_to(&mut reader, &mut count)? < count.len() {
+        return Err(Error::TruncatedHeader.into());
+    }
+    Ok((version, u64::from_le_bytes(count)))
+}
+
+/// Deserialise a [`Record`] of a file written in an older `version` of the format, converting
+/// it into the current shape.
+///
+/// Once the layout of the records changes (e.g. a field is added to [`crate::data::DataA`]),
+/// [`FORMAT_VERSION`] is bumped, and the records of the older versions are deserialised here
+/// into copies of the old types kept for that, then converted with defaults for the new fields.
+/// Version 2 only changed the spill files, so the records of version 1 are of the current shape.
+fn upgrade_record(format: Format, version: u16, frame: &[u8]) -> std::io::Result<Record> {
+    match version {
+        1 => format.deserialize_from(frame),
+        _ => unreachable!("version {version} is not older than the current one, or not readable"),
     }
 }
 
+/// See [`upgrade_record`]
+type Upgrade<T> = fn(Format, u16, &[u8]) -> std::io::Result<T>;
+
 /// Read records from the output file.
-pub struct Reader {
+///
+/// Same as [`Writer`], it could read any deserialisable values, [`Record`]s by default. Files
+/// written by older versions of the format are read as well, converting their records into the
+/// current shape. Other values are deserialised as they are.
+///
+/// Readers don't lock the file, and each of them has its own file handle and position, so any
+/// number of them could read the same file at once, e.g. disjoint ranges of it from different
+/// threads, see [`Reader::clone_at`].
+pub struct Reader<T = Record> {
     buf_reader: BufReader<File>,
+    /// [`None`] for the readers of [`Writer::into_reader`]
+    path: Option<PathBuf>,
+    format: Format,
+    frame: Vec<u8>,
+    /// See [`Reader::with_serialization`]
+    serialization: SerializationConfig,
+    /// Version of the format the file is written in
+    version: u16,
+    /// Converts the records of the older versions, [`None`] for other values than [`Record`]s
+    upgrade: Option<Upgrade<T>>,
+    records_read: u64,
+    /// Records count in the header as of the last check, [`None`] if it is unknown
+    records_flushed: Option<u64>,
+    /// Byte offset of the next record
+    position: u64,
+    records: PhantomData<fn() -> T>,
 }
 
-impl Reader {
+impl Reader<Record> {
     /// Open the reader.
     pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::open_with_capacity(path, DEFAULT_BUF_CAPACITY)
+    }
+
+    /// Open the reader with the specified capacity of the read buffer.
+    pub fn open_with_capacity(path: impl AsRef<Path>, capacity: usize) -> std::io::Result<Self> {
+        let mut reader = Self::from_file(OpenOptions::new().read(true).open(&path)?, capacity)?;
+        reader.path = Some(path.as_ref().to_path_buf());
+        reader.upgrade = Some(upgrade_record);
+        Ok(reader)
+    }
+
+    /// Open the reader, resuming from the offset previously obtained with [`Reader::position`].
+    ///
+    /// Fails with [`Error::InvalidOffset`] if the offset is not at a record boundary. Records
+    /// before it are skipped without reading them, but counted, so that
+    /// [`Reader::records_remaining`] stays correct.
+    ///
+    /// The file must be written in [`Format::Bincode`], the format the reader is opened in, see
+    /// [`Reader::clone_at`] for other formats.
+    pub fn resume_from(path: impl AsRef<Path>, offset: u64) -> std::io::Result<Self> {
+        let mut reader = Self::open(path)?;
+        reader.skip_to(offset)?;
+        Ok(reader)
+    }
+
+    /// Read other values than [`Record`]s, written by a [`Writer::with_records`] of the same type.
+    pub fn with_records<U>(self) -> Reader<U> {
+        Reader {
+            buf_reader: self.buf_reader,
+            path: self.path,
+            format: self.format,
+            frame: self.frame,
+            serialization: self.serialization,
+            version: self.version,
+            upgrade: None,
+            records_read: self.records_read,
+            records_flushed: self.records_flushed,
+            position: self.position,
+            records: PhantomData,
+        }
+    }
+}
+
+impl<T> Reader<T> {
+    /// Read the file from its current position, which must be the start.
+    fn from_file(file: File, capacity: usize) -> std::io::Result<Self> {
+        let mut buf_reader = BufReader::with_capacity(capacity, file);
+        let (version, count) = read_versioned_header(&mut buf_reader, OLDEST_READABLE_VERSION)?;
         Ok(Self {
-            buf_reader: BufReader::new(OpenOptions::new().read(true).open(path)?),
+            buf_reader,
+            path: None,
+            format: Format::default(),
+            frame: Vec::new(),
+            serialization: SerializationConfig::default(),
+            version,
+            upgrade: None,
+            records_read: 0,
+            records_flushed: (count != UNKNOWN_COUNT).then_some(count),
+            position: HEADER_LEN as u64,
+            records: PhantomData,
         })
     }
 
-    /// Read a record, assuming that it **must** be available already.
-    pub fn read(&mut self) -> std::io::Result<Record> {
-        match bincode::deserialize_from(&mut self.buf_reader) {
-            Ok(x) => Ok(x),
-            Err(err) => match *err {
-                bincode::ErrorKind::Io(err) => Err(err),
-                other => panic!("intentionally not covering deserialisation errors: {other}"),
-            },
+    /// Skip the records up to the offset, see [`Reader::resume_from`].
+    ///
+    /// Records are skipped by their length prefixes, or by lines for [`Format::Ndjson`].
+    fn skip_to(&mut self, offset: u64) -> std::io::Result<()> {
+        let len = self.buf_reader.get_ref().metadata()?.len();
+        if offset > len {
+            return Err(Error::InvalidOffset { offset }.into());
         }
+        while self.position < offset {
+            match self.format {
+                Format::Bincode(_) => {
+                    let mut prefix = [0; FRAME_PREFIX_LEN];
+                    if read_up_to(&mut self.buf_reader, &mut prefix)? < FRAME_PREFIX_LEN {
+                        return Err(Error::InvalidOffset { offset }.into());
+                    }
+                    let frame_len = u32::from_le_bytes(prefix);
+                    self.buf_reader.seek_relative(frame_len.into())?;
+                    self.position += (FRAME_PREFIX_LEN as u64) + u64::from(frame_len);
+                }
+                Format::Ndjson => {
+                    self.frame.clear();
+                    let read = self.buf_reader.read_until(b'\n', &mut self.frame)?;
+                    if !self.frame.ends_with(b"\n") {
+                        return Err(Error::InvalidOffset { offset }.into());
+                    }
+                    self.position += read as u64;
+                }
+            }
+            self.records_read += 1;
+        }
+        if self.position != offset {
+            return Err(Error::InvalidOffset { offset }.into());
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::data::*;
-    use assert_matches::assert_matches;
+    /// Open another reader of the same file, starting at the offset, same as
+    /// [`Reader::resume_from`]. It gets the format and the read buffer capacity of this one.
+    ///
+    /// The new reader has a file handle of its own, so the two could be used independently, e.g.
+    /// from different threads. Fails with [`std::io::ErrorKind::Unsupported`] for the readers of
+    /// [`Writer::into_reader`], as the file path is unknown.
+    pub fn clone_at(&self, offset: u64) -> std::io::Result<Self> {
+        let Some(path) = &self.path else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only a reader opened by path could be cloned",
+            ));
+        };
+        let file = OpenOptions::new().read(true).open(path)?;
+        let mut reader =
+            Self::from_file(file, self.buf_reader.capacity())?.with_format(self.format);
+        reader.path = Some(path.clone());
+        reader.serialization = self.serialization;
+        reader.upgrade = self.upgrade;
+        reader.skip_to(offset)?;
+        Ok(reader)
+    }
 
-    #[test]
-    fn write_and_read_few_records() -> std::io::Result<()> {
-        let file = tempfile::NamedTempFile::new()?;
+    /// Byte offset of the next record in the file, to resume from with [`Reader::resume_from`].
+    pub fn position(&self) -> u64 {
+        self.position
+    }
 
-        let mut writer = Writer::open(file.path())?;
-        let mut reader = Reader::open(file.path())?;
+    /// Move back to the first record of the file.
+    pub fn rewind(&mut self) -> std::io::Result<()> {
+        self.buf_reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        self.records_read = 0;
+        self.position = HEADER_LEN as u64;
+        Ok(())
+    }
 
-        writer.write(&Record::D(DataD {
-            timestamp: Timestamp(51),
-            abc: (),
-        }))?;
+    /// Number of records in the file that are not read yet.
+    ///
+    /// It is based on the records count in the file header, which is updated by the writer on
+    /// each [`Writer::flush`]. Returns [`None`] if the count is unknown (written with
+    /// [`Writer::from_write`]).
+    pub fn records_remaining(&mut self) -> std::io::Result<Option<u64>> {
+        Ok(self
+            .refresh_records_flushed()?
+            .map(|count| count.saturating_sub(self.records_read)))
+    }
 
-        writer.write(&Record::C(DataC {
-            timestamp: Timestamp(1),
-            baz: (0, 1),
-        }))?;
-        writer.write(&Record::D(DataD {
-            timestamp: Timestamp(100),
-            abc: (),
-        }))?;
-        writer.flush()?;
+    /// Read the records count in the header once again.
+    fn refresh_records_flushed(&mut self) -> std::io::Result<Option<u64>> {
+        let position = self.buf_reader.stream_position()?;
+        self.buf_reader.rewind()?;
+        let (_, count) = read_versioned_header(&mut self.buf_reader, OLDEST_READABLE_VERSION)?;
+        self.buf_reader.seek(SeekFrom::Start(position))?;
+        self.records_flushed = (count != UNKNOWN_COUNT).then_some(count);
+        Ok(self.records_flushed)
+    }
 
-        assert_matches!(reader.read()?, Record::D(x) if x.timestamp == Timestamp(51));
-        assert_matches!(reader.read()?, Record::C(x) if x.timestamp == Timestamp(1));
-        assert_matches!(reader.read()?, Record::D(x) if x.timestamp == Timestamp(100));
+    /// Set the integer encoding of the read records. Must be the same as the file was written
+    /// with, see [`Writer::with_int_encoding`].
+    pub fn with_int_encoding(self, encoding: IntEncoding) -> Self {
+        self.with_format(encoding.into())
+    }
+
+    /// Set the format of the read records. Must be the same as the file was written with, see
+    /// [`Writer::with_format`].
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the limits of the read records, the same as of [`Writer::with_serialization`]. Records
+    /// over them fail with [`Error::RecordTooLarge`], rather than being read into memory.
+    ///
+    /// For [`Format::Bincode`], the record is rejected by its length prefix, before reading it.
+    /// Either way, the reader is left before the record.
+    pub fn with_serialization(mut self, config: SerializationConfig) -> Self {
+        self.serialization = config;
+        self
+    }
+
+    /// See [`Reader::with_serialization`]
+    pub fn serialization(&self) -> &SerializationConfig {
+        &self.serialization
+    }
+}
+
+impl<T: DeserializeOwned> Reader<T> {
+    /// Read a record, assuming that it **must** be available already.
+    ///
+    /// If the file ends right before the record, returns [`Error::Eof`]. If it ends in the middle
+    /// of it, returns [`Error::TruncatedRecord`]. Both are of the
+    /// [`std::io::ErrorKind::UnexpectedEof`] kind.
+    ///
+    /// Records that are not flushed by the writer yet (see [`Writer::flush`]) are not read, as if
+    /// the file ended before them, even if some of their bytes are in the file already.
+    pub fn read(&mut self) -> std::io::Result<T> {
+        self.read_next()?.ok_or_else(|| Error::Eof.into())
+    }
+
+    /// Read a record, recovering from a torn write at the end of the file (e.g. on a crash).
+    ///
+    /// Returns [`None`] at the end of the file, including when it ends in the middle of a record,
+    /// which is skipped then. Since records are length-prefixed, a partial record could only be
+    /// the last one, and other errors are returned as usual.
+    pub fn read_recoverable(&mut self) -> std::io::Result<Option<T>> {
+        match self.read_next() {
+            Err(err) if matches!(Error::downcast(&err), Some(Error::TruncatedRecord { .. })) => {
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// Iterate over the records until the end of the file.
+    ///
+    /// Unlike [`Reader::read`], the end of the file right before a record ends the iteration, while
+    /// errors (including [`Error::TruncatedRecord`]) are yielded once, ending it as well.
+    pub fn try_iter(&mut self) -> TryIter<'_, T> {
+        TryIter {
+            reader: self,
+            done: false,
+        }
+    }
+
+    /// Iterate over records that **must** be available already, e.g. the `count` of a
+    /// [`crate::NewRecordsAvailable`] notification with [`Iterator::take`].
+    ///
+    /// Each item is the same as of [`Reader::read`], so the end of the file is an error too. The
+    /// first error ends the iteration. Once the iterator is dropped, the reader is positioned right
+    /// after the last record it yielded, and could be used for the next batch.
+    pub fn by_ref_iter(&mut self) -> impl Iterator<Item = std::io::Result<T>> + '_ {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = self.read();
+            done = next.is_err();
+            Some(next)
+        })
+    }
+
+    /// Follow the file as it is being written, like `tail -f`.
+    ///
+    /// At the end of the file, the iterator waits for a message from `notify` (e.g. the
+    /// [`crate::NewRecordsAvailable`] notifications), but no longer than `poll_interval`, and tries
+    /// again. A record that is only partially written yet is read again once it is complete. When
+    /// `notify` is disconnected, the writing is considered finished, and the iterator ends after
+    /// reading the rest of the file.
+    pub fn follow<'a, N>(
+        &'a mut self,
+        notify: &'a mpsc::Receiver<N>,
+        poll_interval: Duration,
+    ) -> Follow<'a, N, T> {
+        Follow {
+            reader: self,
+            notify,
+            poll_interval,
+            finished: false,
+            done: false,
+        }
+    }
+
+    /// Returns [`None`] if the file ends right before the record.
+    ///
+    /// Records beyond the count in the header are not flushed by the writer yet, and might be
+    /// written only partially, so the file is considered to end there.
+    fn read_next(&mut self) -> std::io::Result<Option<T>> {
+        if self
+            .records_flushed
+            .is_some_and(|count| self.records_read >= count)
+            && self
+                .refresh_records_flushed()?
+                .is_some_and(|count| self.records_read >= count)
+        {
+            return Ok(None);
+        }
+
+        let record_len = match self.format {
+            Format::Bincode(_) => {
+                let mut prefix = [0; FRAME_PREFIX_LEN];
+                match read_up_to(&mut self.buf_reader, &mut prefix)? {
+                    0 => return Ok(None),
+                    FRAME_PREFIX_LEN => {}
+                    read => return Err(Error::TruncatedRecord { read }.into()),
+                }
+
+                let len = u32::from_le_bytes(prefix) as usize;
+                if let Err(err) = self
+                    .serialization
+                    .check_record_size(self.format, len as u64)
+                {
+                    self.buf_reader.seek_relative(-(FRAME_PREFIX_LEN as i64))?;
+                    return Err(err.into());
+                }
+                // the frame grows as the bytes are read, rather than up to whatever length a
+                // corrupted prefix claims
+                self.frame.clear();
+                let read =
+                    Read::take(&mut self.buf_reader, len as u64).read_to_end(&mut self.frame)?;
+                if read < len {
+                    return Err(Error::TruncatedRecord {
+                        read: FRAME_PREFIX_LEN + read,
+                    }
+                    .into());
+                }
+                FRAME_PREFIX_LEN + len
+            }
+            Format::Ndjson => {
+                self.frame.clear();
+                match self.buf_reader.read_until(b'\n', &mut self.frame)? {
+                    0 => return Ok(None),
+                    read if !self.frame.ends_with(b"\n") => {
+                        return Err(Error::TruncatedRecord { read }.into())
+                    }
+                    read => {
+                        let size = read - 1;
+                        if let Err(err) = self
+                            .serialization
+                            .check_record_size(self.format, size as u64)
+                        {
+                            self.buf_reader.seek_relative(-(read as i64))?;
+                            return Err(err.into());
+                        }
+                        read
+                    }
+                }
+            }
+        };
+        self.position += record_len as u64;
+
+        let record = match self.upgrade {
+            Some(upgrade) if self.version < FORMAT_VERSION => {
+                upgrade(self.format, self.version, &self.frame)?
+            }
+            _ => self.format.deserialize_from(self.frame.as_slice())?,
+        };
+        self.records_read += 1;
+        Ok(Some(record))
+    }
+}
+
+impl Reader<Record> {
+    /// Read the records up to the `limit` timestamp (inclusive), e.g. to process the file in time
+    /// windows at the consumer's own pace, rather than on each notification.
+    ///
+    /// Reading stops at the end of the flushed records, or at the first record past the limit,
+    /// which is left unread, so that the next call starts from it. Fails as [`Reader::read`]
+    /// does, leaving the reader where it was before the call.
+    pub fn read_until(&mut self, limit: Timestamp) -> std::io::Result<Vec<Record>> {
+        let (start, records_read) = (self.position, self.records_read);
+        let mut records = Vec::new();
+        loop {
+            let before = self.position;
+            match self.read_next() {
+                Ok(Some(record)) if record.timestamp() <= limit => records.push(record),
+                Ok(Some(_)) => {
+                    self.unread(before)?;
+                    return Ok(records);
+                }
+                Ok(None) => return Ok(records),
+                Err(err) => {
+                    self.buf_reader.seek(SeekFrom::Start(start))?;
+                    self.position = start;
+                    self.records_read = records_read;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Step back to the start of the record just read, which started at the `before` position
+    fn unread(&mut self, before: u64) -> std::io::Result<()> {
+        // the record is still in the read buffer, unless it is larger than the buffer
+        self.buf_reader
+            .seek_relative(-((self.position - before) as i64))?;
+        self.position = before;
+        self.records_read -= 1;
+        Ok(())
+    }
+
+    /// Iterate over the records with timestamps in the `range`, starting from the current position.
+    ///
+    /// Records earlier than the range are skipped. If the file has an index (see
+    /// [`Writer::with_index`]), they are skipped by a binary search through it, taking `O(log n)`
+    /// seeks, and only the records after the last indexed one before the range are scanned.
+    /// Otherwise, they are scanned one by one. Since the file is sorted, the iteration ends at
+    /// the first record past the range, which is left to be read next, same as by
+    /// [`Reader::read_until`]. Errors are yielded once, ending the iteration, same as of
+    /// [`Reader::try_iter`].
+    pub fn scan_range<'a>(
+        &'a mut self,
+        range: impl RangeBounds<Timestamp> + 'a,
+    ) -> impl Iterator<Item = std::io::Result<Record>> + 'a {
+        let mut searched = false;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if !std::mem::replace(&mut searched, true) {
+                if let Err(err) = self.seek_by_index(range.start_bound()) {
+                    done = true;
+                    return Some(Err(err));
+                }
+            }
+            while !done {
+                let before = self.position;
+                let record = match self.read_next() {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(err) => {
+                        done = true;
+                        return Some(Err(err));
+                    }
+                };
+                let ts = record.timestamp();
+                if range.contains(&ts) {
+                    return Some(Ok(record));
+                }
+                if !is_before(range.start_bound(), ts) {
+                    done = true;
+                    if let Err(err) = self.unread(before) {
+                        return Some(Err(err));
+                    }
+                }
+            }
+            done = true;
+            None
+        })
+    }
+
+    /// Move to the last indexed record before the `start`, if it is past the current position,
+    /// see [`Reader::scan_range`].
+    ///
+    /// Only the offsets of the flushed records are searched through. Stays where it was if the
+    /// file has no index, or on error.
+    fn seek_by_index(&mut self, start: Bound<&Timestamp>) -> std::io::Result<()> {
+        let index = match (&self.path, start) {
+            (_, Bound::Unbounded) | (None, _) => None,
+            (Some(path), _) => Index::read(path)?,
+        };
+        let Some(Index { interval, offsets }) = index else {
+            return Ok(());
+        };
+        let (position, records_read) = (self.position, self.records_read);
+        let flushed = self.refresh_records_flushed()?.unwrap_or(u64::MAX);
+        let first = offsets.partition_point(|offset| *offset < position);
+        let end = offsets.len().min(flushed.div_ceil(interval) as usize);
+        let (mut low, mut high) = (first, end.max(first));
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.read_at(offsets[mid], mid as u64 * interval) {
+                Ok(Some(record)) if is_before(start, record.timestamp()) => low = mid + 1,
+                Ok(_) => high = mid,
+                Err(err) => {
+                    self.seek_to(position, records_read)?;
+                    return Err(err);
+                }
+            }
+        }
+        match low.checked_sub(1).filter(|last| *last >= first) {
+            Some(last) => self.seek_to(offsets[last], last as u64 * interval),
+            None => self.seek_to(position, records_read),
+        }
+    }
+
+    /// Read the record at the offset, which is `records_read` records into the file
+    fn read_at(&mut self, offset: u64, records_read: u64) -> std::io::Result<Option<Record>> {
+        self.seek_to(offset, records_read)?;
+        self.read_next()
+    }
+
+    /// Move to the offset, which is `records_read` records into the file
+    fn seek_to(&mut self, offset: u64, records_read: u64) -> std::io::Result<()> {
+        self.buf_reader.seek(SeekFrom::Start(offset))?;
+        self.position = offset;
+        self.records_read = records_read;
+        Ok(())
+    }
+}
+
+/// Whether the timestamp is before the `start` bound of a range
+fn is_before(start: Bound<&Timestamp>, ts: Timestamp) -> bool {
+    match start {
+        Bound::Included(start) => ts < *start,
+        Bound::Excluded(start) => ts <= *start,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Iterator following the file of a [`Reader`], see [`Reader::follow`]
+pub struct Follow<'a, N, T = Record> {
+    reader: &'a mut Reader<T>,
+    notify: &'a mpsc::Receiver<N>,
+    poll_interval: Duration,
+    /// The writing is finished, so the end of the file is final
+    finished: bool,
+    done: bool,
+}
+
+impl<N, T: DeserializeOwned> Iterator for Follow<'_, N, T> {
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.reader.read_next() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {}
+                Err(err) => match Error::downcast(&err) {
+                    // the writer hasn't written the whole record yet
+                    Some(Error::TruncatedRecord { read }) if !self.finished => {
+                        if let Err(err) = self.reader.buf_reader.seek_relative(-(*read as i64)) {
+                            self.done = true;
+                            return Some(Err(err));
+                        }
+                    }
+                    _ => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                },
+            }
+
+            if self.finished {
+                self.done = true;
+            } else if let Err(mpsc::RecvTimeoutError::Disconnected) =
+                self.notify.recv_timeout(self.poll_interval)
+            {
+                // read whatever was written before that once more
+                self.finished = true;
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the records of a [`Reader`], see [`Reader::try_iter`]
+pub struct TryIter<'r, T = Record> {
+    reader: &'r mut Reader<T>,
+    done: bool,
+}
+
+impl<T: DeserializeOwned> Iterator for TryIter<'_, T> {
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.reader.read_next().transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::*;
+    use crate::test_support::{self, record, record_of};
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn write_and_read_few_records() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+
+        let mut writer = Writer::open(file.path())?;
+        let mut reader = Reader::open(file.path())?;
+
+        writer.write(&record(51))?;
+
+        writer.write(&Record::C(DataC {
+            timestamp: Timestamp(1),
+            baz: (0, 1),
+        }))?;
+        writer.write(&record(100))?;
+        writer.flush()?;
+
+        assert_matches!(reader.read()?, Record::D(x) if x.timestamp == Timestamp(51));
+        assert_matches!(reader.read()?, Record::C(x) if x.timestamp == Timestamp(1));
+        assert_matches!(reader.read()?, Record::D(x) if x.timestamp == Timestamp(100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_per_kind_files() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("output");
+        let mut writer = Writer::open(&path)?
+            .with_int_encoding(IntEncoding::Varint)
+            .with_per_kind_files(&path);
+        for ts in 0..6 {
+            writer.write(&if ts % 3 == 0 {
+                Record::A(DataA {
+                    timestamp: Timestamp(ts),
+                    foo: "foo".into(),
+                })
+            } else {
+                record(ts)
+            })?;
+        }
+        writer.flush()?;
+
+        let read_all = |path| {
+            Reader::open(path)?
+                .with_int_encoding(IntEncoding::Varint)
+                .try_iter()
+                .map(|x| x.map(|record| (record.kind(), record.timestamp().0)))
+                .collect::<std::io::Result<Vec<_>>>()
+        };
+        assert_eq!(read_all(path.clone())?.len(), 6);
+        assert_eq!(
+            read_all(kind_path(&path, RecordKind::A))?,
+            [(RecordKind::A, 0), (RecordKind::A, 3)]
+        );
+        assert_eq!(
+            read_all(dir.path().join("output.D"))?,
+            [1, 2, 4, 5].map(|ts| (RecordKind::D, ts))
+        );
+        assert!(!kind_path(&path, RecordKind::C).exists());
+
+        // the per-kind files are flushed once other values are written instead
+        writer.write(&Record::A(DataA {
+            timestamp: Timestamp(6),
+            foo: "foo".into(),
+        }))?;
+        let mut writer = writer.with_records::<u64>();
+        assert_eq!(read_all(kind_path(&path, RecordKind::A))?.len(), 3);
+        writer.write(&7)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_other_values() -> std::io::Result<()> {
+        #[derive(Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct Event {
+            at: u64,
+            name: String,
+        }
+        let event = |at| Event {
+            at,
+            name: format!("event-{at}"),
+        };
+
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?
+            .with_int_encoding(IntEncoding::Varint)
+            .with_records::<Event>();
+        writer.write(&event(1))?;
+        writer.write_all(&[event(2), event(3)])?;
+        assert_eq!(writer.records_written(), Some(3));
+
+        let mut reader = writer.into_reader()?;
+        assert_eq!(reader.records_remaining()?, Some(3));
+        let events = reader.try_iter().collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(events, [event(1), event(2), event(3)]);
+
+        let mut reader = Reader::open(file.path())?
+            .with_int_encoding(IntEncoding::Varint)
+            .with_records::<Event>();
+        assert_eq!(reader.read()?, event(1));
+        let mut reader = Reader::resume_from(file.path(), reader.position())?
+            .with_int_encoding(IntEncoding::Varint)
+            .with_records::<Event>();
+        assert_eq!(reader.read()?, event(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn append_to_existing_file() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+
+        let mut writer = Writer::open(file.path())?;
+        writer.write(&record(1))?;
+        writer.write(&record(2))?;
+        writer.flush()?;
+        let mut writer = Writer::open_append(file.path())?;
+        writer.write(&record(3))?;
+        writer.flush()?;
+
+        let mut reader = Reader::open(file.path())?;
+        for ts in 1..=3 {
+            assert_eq!(reader.read()?, record(ts));
+        }
+        reader.read().unwrap_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn rename_atomically_on_finalize() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("output");
+        let temp = dir.path().join("output.tmp");
+
+        let mut writer = Writer::open_atomic(&path)?.with_checksum();
+        writer.write(&record(1))?;
+        writer.write_all(&[record(2), record(3)])?;
+        writer.flush()?;
+        assert!(temp.exists());
+        assert!(!path.exists());
+        writer.finalize()?;
+        assert!(!temp.exists());
+
+        let mut reader = Reader::open(&path)?;
+        for ts in 1..=3 {
+            assert_eq!(reader.read()?, record(ts));
+        }
+        // the checksum isn't read as a record
+        assert_matches!(
+            Error::downcast(&reader.read().unwrap_err()),
+            Some(Error::Eof)
+        );
+        assert!(verify_checksum(&path)?);
+
+        // corrupted
+        let mut bytes = std::fs::read(&path)?;
+        bytes[HEADER_LEN + FRAME_PREFIX_LEN] ^= 1;
+        std::fs::write(&path, bytes)?;
+        assert!(!verify_checksum(&path)?);
+
+        // not finalized
+        let other = dir.path().join("other");
+        let mut writer = Writer::open_atomic(&other)?;
+        writer.write(&record(1))?;
+        drop(writer);
+        assert!(!dir.path().join("other.tmp").exists());
+        assert!(!other.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_records_larger_than_limit() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        // 4 bytes of the variant, 16 of the timestamp, 8 of the length and 2 per element
+        let record = |len| {
+            Record::E(DataE {
+                timestamp: Timestamp(1),
+                def: vec![0; len],
+            })
+        };
+
+        let limited = SerializationConfig {
+            max_record_size: Some(40),
+        };
+        let mut writer = Writer::open(file.path())?.with_serialization(limited);
+        writer.write(&record(6))?;
+        let err = writer.write(&record(7)).unwrap_err();
+        assert_matches!(
+            Error::downcast(&err),
+            Some(Error::RecordTooLarge {
+                size: 42,
+                limit: 40
+            })
+        );
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(writer.records_written(), Some(1));
+        // written without the limit
+        writer.serialization = SerializationConfig::default();
+        writer.write(&record(7))?;
+        writer.flush()?;
+
+        let mut reader = Reader::open(file.path())?.with_serialization(limited);
+        assert_eq!(reader.read()?, record(6));
+        let position = reader.position();
+        assert_matches!(
+            Error::downcast(&reader.read().unwrap_err()),
+            Some(Error::RecordTooLarge {
+                size: 42,
+                limit: 40
+            })
+        );
+        // left before the record
+        assert_eq!(reader.position(), position);
+        reader.serialization = SerializationConfig::default();
+        assert_eq!(reader.read()?, record(7));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_records_up_to_limit() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let timestamps =
+            |records: Vec<Record>| records.iter().map(|x| x.timestamp().0).collect::<Vec<_>>();
+
+        let mut writer = Writer::open(file.path())?;
+        writer.write_all(&[record(1), record(2), record(3), record(5), record(8)])?;
+        writer.flush()?;
+        let mut reader = Reader::open(file.path())?;
+
+        assert_eq!(timestamps(reader.read_until(Timestamp(3))?), [1, 2, 3]);
+        let position = reader.position();
+        assert_eq!(timestamps(reader.read_until(Timestamp(4))?), []);
+        assert_eq!(reader.position(), position);
+        assert_eq!(reader.records_remaining()?, Some(2));
+        assert_eq!(timestamps(reader.read_until(Timestamp(10))?), [5, 8]);
+
+        // not flushed yet
+        writer.write(&record(9))?;
+        assert_eq!(timestamps(reader.read_until(Timestamp(10))?), []);
+        writer.flush()?;
+        assert_eq!(timestamps(reader.read_until(Timestamp(10))?), [9]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_back_finished_writer() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+
+        let mut writer = Writer::open(file.path())?.with_int_encoding(IntEncoding::Varint);
+        writer.write(&record(1))?;
+        writer.write(&record(2))?;
+        let mut reader = writer.into_reader()?;
+        assert_eq!(reader.records_remaining()?, Some(2));
+        assert_eq!(reader.read()?, record(1));
+        assert_eq!(reader.read()?, record(2));
+        reader.read().unwrap_err();
+
+        let writer = Writer::from_write(Vec::new())?;
+        let err = writer.into_reader().err().expect("stream isn't readable");
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+        Ok(())
+    }
+
+    #[test]
+    fn count_remaining_records() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let record = record(0);
+
+        let mut writer = Writer::open(file.path())?;
+        let mut reader = Reader::open(file.path())?;
+        assert_eq!(reader.records_remaining()?, Some(0));
+        writer.write(&record)?;
+        writer.write(&record)?;
+        writer.sync()?;
+        assert_eq!(writer.records_written(), Some(2));
+        assert_eq!(reader.records_remaining()?, Some(2));
+        reader.read()?;
+        assert_eq!(reader.records_remaining()?, Some(1));
+        reader.read()?;
+        assert_eq!(reader.records_remaining()?, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_format_header() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        writer.write(&record(0))?;
+        writer.flush()?;
+        let valid = std::fs::read(file.path())?;
+
+        // the file without a header, written by older versions
+        std::fs::write(file.path(), &valid[HEADER_LEN..])?;
+        let err = Reader::open(file.path()).err().unwrap();
+        assert_matches!(Error::downcast(&err), Some(Error::MissingHeader));
+        let err = Writer::open_append(file.path()).unwrap_err();
+        assert_matches!(Error::downcast(&err), Some(Error::MissingHeader));
+
+        let mut other_version = valid.clone();
+        other_version[FORMAT_HEADER_LEN - 2..FORMAT_HEADER_LEN]
+            .copy_from_slice(&42u16.to_le_bytes());
+        std::fs::write(file.path(), &other_version)?;
+        let err = Reader::open(file.path()).err().unwrap();
+        assert_matches!(
+            Error::downcast(&err),
+            Some(Error::IncompatibleFormat { found: 42, expected })
+                if *expected == codec::FORMAT_VERSION
+        );
+
+        std::fs::write(file.path(), &valid[..HEADER_LEN - 1])?;
+        let err = Reader::open(file.path()).err().unwrap();
+        assert_matches!(Error::downcast(&err), Some(Error::TruncatedHeader));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_records_of_older_format_version() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        // written by version 1: the header with the count, and a `Record::B` with fixint encoding
+        let mut blob = b"tsk\xff".to_vec();
+        blob.extend(1u16.to_le_bytes());
+        blob.extend(1u64.to_le_bytes());
+        blob.extend(21u32.to_le_bytes());
+        blob.extend(1u32.to_le_bytes());
+        blob.extend(7u128.to_le_bytes());
+        blob.push(1);
+        std::fs::write(file.path(), &blob)?;
+
+        let mut reader = Reader::open(file.path())?;
+        assert_eq!(
+            reader.read()?,
+            Record::B(DataB {
+                timestamp: Timestamp(7),
+                bar: true,
+            })
+        );
+        assert_eq!(reader.records_remaining()?, Some(0));
+
+        // records of the current shape are not appended to it
+        let err = Writer::open_append(file.path()).unwrap_err();
+        assert_matches!(
+            Error::downcast(&err),
+            Some(Error::IncompatibleFormat { found: 1, .. })
+        );
+
+        blob[FORMAT_HEADER_LEN - 2..FORMAT_HEADER_LEN].copy_from_slice(&0u16.to_le_bytes());
+        std::fs::write(file.path(), &blob)?;
+        let err = Reader::open(file.path()).err().unwrap();
+        assert_matches!(
+            Error::downcast(&err),
+            Some(Error::IncompatibleFormat { found: 0, .. })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn many_records_with_custom_capacities() -> std::io::Result<()> {
+        const RECORDS: u128 = 10_000;
+
+        let file = tempfile::NamedTempFile::new()?;
+        let record = |ts| {
+            Record::E(DataE {
+                timestamp: Timestamp(ts),
+                def: vec![ts as u16; 10],
+            })
+        };
+
+        let mut writer = Writer::open_with_capacity(file.path(), 1 << 20)?;
+        for ts in 0..RECORDS {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+
+        let mut reader = Reader::open_with_capacity(file.path(), 7)?;
+        for ts in 0..RECORDS {
+            assert_eq!(reader.read()?, record(ts));
+        }
+        reader.read().unwrap_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_again_after_rewind() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..10 {
+            writer.write(&Record::C(DataC {
+                timestamp: Timestamp(ts),
+                baz: (ts as u32, 0),
+            }))?;
+        }
+        writer.flush()?;
+
+        let mut reader = Reader::open(file.path())?;
+        let first_pass = (0..10)
+            .map(|_| reader.read())
+            .collect::<Result<Vec<_>, _>>()?;
+        reader.read().unwrap_err();
+        reader.rewind()?;
+        assert_eq!(reader.records_remaining()?, Some(10));
+        let second_pass = (0..10)
+            .map(|_| reader.read())
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(first_pass, second_pass);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_into_non_seekable_sink() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        /// Not seekable, sends written bytes over a channel
+        struct Pipe(std::sync::mpsc::Sender<Vec<u8>>);
+        impl Write for Pipe {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.send(buf.to_vec()).expect("receiver is alive");
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let record = Record::B(DataB {
+            timestamp: Timestamp(42),
+            bar: true,
+        });
+        let mut writer = Writer::from_write(Pipe(tx))?;
+        writer.write(&record)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::write(file.path(), rx.iter().flatten().collect::<Vec<_>>())?;
+
+        let mut reader = Reader::open(file.path())?;
+        assert_eq!(reader.records_remaining()?, None);
+        assert_eq!(reader.read()?, record);
+        reader.read().unwrap_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_record_is_reported() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..3 {
+            writer.write(&Record::E(DataE {
+                timestamp: Timestamp(ts),
+                def: vec![1, 2, 3],
+            }))?;
+        }
+        writer.flush()?;
+        // length prefix (4), variant (4), timestamp (16), vec length (8) and items (3 * 2)
+        const RECORD_LEN: u64 = 38;
+        let len = file.as_file().metadata()?.len();
+        file.as_file().set_len(len - 3)?;
+
+        let mut reader = Reader::open(file.path())?;
+        assert_eq!(reader.read()?.timestamp(), Timestamp(0));
+        assert_eq!(reader.read()?.timestamp(), Timestamp(1));
+        let err = reader.read().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_matches!(Error::downcast(&err), Some(Error::TruncatedRecord { .. }));
+
+        file.as_file().set_len(len - RECORD_LEN)?;
+        let mut reader = Reader::open(file.path())?;
+        reader.read()?;
+        reader.read()?;
+        let err = reader.read().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_matches!(Error::downcast(&err), Some(Error::Eof));
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_length_prefix_is_reported() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        writer.write(&record(0))?;
+        writer.flush()?;
+        let position = Reader::open(file.path())?.position();
+        // the length prefix of the record claims 4 GiB
+        let mut corrupted = file.reopen()?;
+        corrupted.seek(SeekFrom::Start(position))?;
+        corrupted.write_all(&u32::MAX.to_le_bytes())?;
+
+        let mut reader = Reader::open(file.path())?;
+        let err = reader.read().unwrap_err();
+        assert_matches!(Error::downcast(&err), Some(Error::TruncatedRecord { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn unflushed_records_are_not_read() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+
+        // the records reach the file right away, but the header is only updated on flush
+        let mut writer = Writer::open_with_capacity(file.path(), 1)?;
+        writer.write(&record(0))?;
+        writer.flush()?;
+        writer.write(&record(1))?;
+
+        let mut reader = Reader::open(file.path())?;
+        assert_eq!(reader.read()?, record(0));
+        let err = reader.read().unwrap_err();
+        assert_matches!(Error::downcast(&err), Some(Error::Eof));
+
+        writer.flush()?;
+        assert_eq!(reader.read()?, record(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterate_until_end_or_error() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..3 {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+
+        let mut reader = Reader::open(file.path())?;
+        let timestamps = reader
+            .try_iter()
+            .map(|x| x.map(|record| record.timestamp().0))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(timestamps, [0, 1, 2]);
+
+        let len = file.as_file().metadata()?.len();
+        file.as_file().set_len(len - 1)?;
+        let mut reader = Reader::open(file.path())?;
+        let mut iter = reader.try_iter();
+        iter.next().unwrap()?;
+        iter.next().unwrap()?;
+        let err = iter.next().unwrap().unwrap_err();
+        assert_matches!(Error::downcast(&err), Some(Error::TruncatedRecord { .. }));
+        assert!(iter.next().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn take_batches_by_ref() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        let mut reader = Reader::open(file.path())?;
+        let mut write_batch = |timestamps: std::ops::Range<u128>| {
+            for ts in timestamps {
+                writer.write(&record(ts))?;
+            }
+            writer.flush()
+        };
+        let mut read_batch = |count| {
+            reader
+                .by_ref_iter()
+                .take(count)
+                .map(|x| x.map(|record| record.timestamp().0))
+                .collect::<std::io::Result<Vec<_>>>()
+        };
+
+        write_batch(0..5)?;
+        assert_eq!(read_batch(2)?, [0, 1]);
+        assert_eq!(read_batch(0)?, []);
+        assert_eq!(read_batch(1)?, [2]);
+        write_batch(5..7)?;
+        assert_eq!(read_batch(4)?, [3, 4, 5, 6]);
+
+        let err = read_batch(1).unwrap_err();
+        assert_matches!(Error::downcast(&err), Some(Error::Eof));
+        write_batch(7..8)?;
+        assert_eq!(read_batch(1)?, [7]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn follow_file_being_written() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let record = |ts| {
+            Record::E(DataE {
+                timestamp: Timestamp(ts),
+                def: vec![1, 2, 3],
+            })
+        };
+        // records beyond the count in the header are not read, so the count must be unknown
+        let mut writer = Writer::from_write(file.reopen()?)?;
+        writer.write(&record(0))?;
+        writer.flush()?;
+
+        // bytes of the second record, to be appended in parts
+        let other = tempfile::NamedTempFile::new()?;
+        let mut other_writer = Writer::open(other.path())?;
+        other_writer.write(&record(1))?;
+        other_writer.flush()?;
+        let frame = std::fs::read(other.path())?.split_off(HEADER_LEN);
+
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let mut reader = Reader::open(file.path())?;
+        std::thread::scope(|scope| {
+            let reader = &mut reader;
+            let follower = scope.spawn(move || {
+                reader
+                    .follow(&notify_rx, Duration::from_millis(10))
+                    .collect::<std::io::Result<Vec<_>>>()
+            });
+
+            let mut append = OpenOptions::new().append(true).open(file.path())?;
+            for part in frame.chunks(frame.len() / 2 + 1) {
+                append.write_all(part)?;
+                notify_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(30));
+            }
+            drop(notify_tx);
+
+            assert_eq!(follower.join().unwrap()?, [record(0), record(1)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn recover_records_before_torn_write() -> std::io::Result<()> {
+        const RECORDS: u128 = 5;
+
+        let file = tempfile::NamedTempFile::new()?;
+        let record = |ts| {
+            Record::E(DataE {
+                timestamp: Timestamp(ts),
+                def: vec![7; 4],
+            })
+        };
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..=RECORDS {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+        let len = file.as_file().metadata()?.len();
+        file.as_file().set_len(len - 5)?;
+
+        let mut reader = Reader::open(file.path())?;
+        let mut recovered = vec![];
+        while let Some(record) = reader.read_recoverable()? {
+            recovered.push(record);
+        }
+        assert_eq!(recovered, (0..RECORDS).map(record).collect::<Vec<_>>());
+        assert!(reader.read_recoverable()?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_all_at_once() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let records = (0..5)
+            .map(|ts| {
+                Record::E(DataE {
+                    timestamp: Timestamp(ts),
+                    def: vec![0; ts as usize],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut writer = Writer::open(file.path())?;
+        writer.write_all(&records[..2])?;
+        writer.write_all(&[])?;
+        writer.write_all(&records[2..])?;
+        writer.flush()?;
+        assert_eq!(writer.records_written(), Some(5));
+
+        let mut reader = Reader::open(file.path())?;
+        for record in &records {
+            assert_eq!(&reader.read()?, record);
+        }
+        assert_eq!(reader.records_remaining()?, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_timestamp_range() -> std::io::Result<()> {
+        const RECORDS: u128 = 10_000;
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..RECORDS {
+            // every timestamp twice
+            for _ in 0..2 {
+                writer.write(&record(ts))?;
+            }
+        }
+        writer.flush()?;
+
+        let mut reader = Reader::open(file.path())?;
+        let mut scan = |range: (Bound<u128>, Bound<u128>)| -> std::io::Result<Vec<u128>> {
+            reader.rewind()?;
+            reader
+                .scan_range((range.0.map(Timestamp), range.1.map(Timestamp)))
+                .map(|x| x.map(|x| x.timestamp().0))
+                .collect()
+        };
+        use Bound::*;
+        assert_eq!(
+            scan((Included(5_000), Excluded(5_002)))?,
+            [5_000, 5_000, 5_001, 5_001]
+        );
+        assert_eq!(scan((Excluded(5_000), Included(5_001)))?, [5_001, 5_001]);
+        assert_eq!(scan((Included(RECORDS - 1), Unbounded))?.len(), 2);
+        assert_eq!(scan((Unbounded, Excluded(1)))?, [0, 0]);
+        assert!(scan((Included(RECORDS), Unbounded))?.is_empty());
+        assert!(scan((Included(7), Excluded(7)))?.is_empty());
+
+        // the record past the range is left to be read, and the range could be borrowed
+        reader.rewind()?;
+        let end = Timestamp(1);
+        let range = (Bound::Unbounded, Bound::Excluded(&end));
+        assert_eq!(reader.scan_range(range).count(), 2);
+        assert_eq!(reader.read()?.timestamp(), Timestamp(1));
+        assert_eq!(reader.read()?.timestamp(), Timestamp(1));
+        assert_eq!(reader.read()?.timestamp(), Timestamp(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_range_through_index() -> std::io::Result<()> {
+        const RECORDS: u64 = 100 * INDEX_INTERVAL + 10;
+        let (_dir, path) = test_support::temp_output()?;
+        let mut writer = Writer::open(&path)?.with_index(&path)?;
+        // each timestamp thrice, so that some of them straddle the indexed records, and records
+        // of all kinds, so that the offsets are irregular
+        let records = (0..RECORDS)
+            .map(|i| record_of(RecordKind::ALL[i as usize % 5], u128::from(i / 3)))
+            .collect::<Vec<_>>();
+        for batch in records.chunks(1000) {
+            writer.write_all(batch)?;
+        }
+        writer.flush()?;
+
+        let mut reader = Reader::open(&path)?;
+        let mut scan = |start: u128, end: u128| -> std::io::Result<Vec<Record>> {
+            reader.rewind()?;
+            reader
+                .scan_range(Timestamp(start)..Timestamp(end))
+                .collect()
+        };
+        let expected = |start: u128, end: u128| {
+            (records.iter())
+                .filter(|x| (Timestamp(start)..Timestamp(end)).contains(&x.timestamp()))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let last = u128::from((RECORDS - 1) / 3);
+        let indexed = (0..RECORDS)
+            .step_by(INDEX_INTERVAL as usize)
+            .map(|i| u128::from(i / 3));
+        for ts in indexed.chain([last]) {
+            for start in [ts.saturating_sub(1), ts, ts + 1] {
+                assert_eq!(
+                    scan(start, start + 2)?,
+                    expected(start, start + 2),
+                    "{start}"
+                );
+            }
+        }
+        assert_eq!(scan(0, 1)?, expected(0, 1));
+        assert!(scan(last + 1, last + 2)?.is_empty());
+
+        // starting from the current position, even if an indexed record before the range is
+        // behind it
+        reader.rewind()?;
+        let _ = reader.scan_range(Timestamp(1_000)..).next().transpose()?;
+        assert_eq!(reader.read()?.timestamp(), Timestamp(1_000));
+        let scanned = reader.scan_range(Timestamp(900)..Timestamp(1_001)).count();
+        assert_eq!(scanned, 1);
+
+        // the records before the last indexed one before the range are not read at all
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        file.write_all(&u32::MAX.to_le_bytes())?;
+        let mut reader = Reader::open(&path)?;
+        assert_eq!(
+            reader
+                .scan_range(Timestamp(last)..)
+                .collect::<std::io::Result<Vec<_>>>()?,
+            expected(last, last + 1)
+        );
+        // scanned linearly without the index
+        std::fs::remove_file(index_path(&path))?;
+        reader.rewind()?;
+        let err = reader
+            .scan_range(Timestamp(last)..)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        // the corrupted length claims the rest of the file
+        assert_matches!(Error::downcast(&err), Some(Error::TruncatedRecord { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sort_unsorted_file() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input");
+        let mut writer = Writer::open(&input)?;
+        for ts in [5, 3, 8, 1, 4, 2, 7, 6] {
+            writer.write(&record(ts))?;
+        }
+        drop(writer);
+        let config = || BufferConfig {
+            max_in_memory: 3,
+            file_read_buf_capacity: 1_024,
+            ..Default::default()
+        };
+        let check_sorted = |path: &Path| -> std::io::Result<()> {
+            let mut reader = Reader::open(path)?;
+            assert_eq!(reader.records_remaining()?, Some(8));
+            for ts in 1..=8 {
+                assert_eq!(reader.read()?.timestamp(), Timestamp(ts));
+            }
+            Ok(())
+        };
+
+        let output = dir.path().join("output");
+        assert_eq!(sort_file(&input, &output, config())?, 8);
+        check_sorted(&output)?;
+        assert!(!dir.path().join("output.buffer").exists());
+
+        // in place
+        assert_eq!(sort_file(&input, &input, config())?, 8);
+        check_sorted(&input)?;
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())?
+            .map(|x| x.map(|x| x.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        files.sort();
+        assert_eq!(files, ["input", "output"]);
+
+        // a directory that is in the way is kept
+        std::fs::create_dir(dir.path().join("output.buffer"))?;
+        assert_eq!(sort_file(&input, &output, config())?, 8);
+        assert!(dir.path().join("output.buffer").is_dir());
+        assert!(!dir.path().join("output.buffer-1").exists());
+        std::fs::remove_dir(dir.path().join("output.buffer"))?;
+
+        // nothing is left behind on failure
+        let len = std::fs::metadata(&input)?.len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&input)?
+            .set_len(len - 5)?;
+        let err = sort_file(&input, &input, config()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(std::fs::metadata(&input)?.len(), len - 5);
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())?
+            .map(|x| x.map(|x| x.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        files.sort();
+        assert_eq!(files, ["input", "output"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn replay_into_channels() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..3 {
+            let timestamp = Timestamp(ts);
+            writer.write(&Record::B(DataB {
+                timestamp,
+                bar: true,
+            }))?;
+            writer.write(&Record::D(DataD { timestamp, abc: () }))?;
+        }
+        writer.flush()?;
+
+        let (tx_a, rx_a) = mpsc::channel();
+        let (tx_b, rx_b) = mpsc::channel();
+        let (tx_c, rx_c) = mpsc::channel();
+        let (tx_d, rx_d) = mpsc::channel();
+        let (tx_e, rx_e) = mpsc::channel();
+        assert_eq!(replay(file.path(), (tx_a, tx_b, tx_c, tx_d, tx_e))?, 6);
+        assert_eq!(rx_a.iter().count(), 0);
+        assert_eq!(
+            rx_b.iter().map(|x| x.timestamp.0).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(rx_c.iter().count(), 0);
+        assert_eq!(
+            rx_d.iter().map(|x| x.timestamp.0).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(rx_e.iter().count(), 0);
+
+        let (tx_a, _rx_a) = mpsc::channel();
+        let (tx_b, rx_b) = mpsc::channel();
+        let (tx_c, _rx_c) = mpsc::channel();
+        let (tx_d, _rx_d) = mpsc::channel();
+        let (tx_e, _rx_e) = mpsc::channel();
+        drop(rx_b);
+        let err = replay(file.path(), (tx_a, tx_b, tx_c, tx_d, tx_e)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        Ok(())
+    }
+
+    #[test]
+    fn position_advances_by_record_size() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let record = |ts| {
+            Record::E(DataE {
+                timestamp: Timestamp(ts),
+                def: vec![0; ts as usize],
+            })
+        };
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..5 {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+
+        let mut reader = Reader::open(file.path())?;
+        assert_eq!(reader.position(), HEADER_LEN as u64);
+        let mut serialized = Vec::new();
+        for ts in 0..5 {
+            let position = reader.position();
+            reader.read()?;
+            serialized.clear();
+            IntEncoding::default().serialize_into(&mut serialized, &record(ts))?;
+            assert_eq!(
+                reader.position(),
+                position + (FRAME_PREFIX_LEN + serialized.len()) as u64
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn resume_from_saved_position() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let record = |ts| {
+            Record::E(DataE {
+                timestamp: Timestamp(ts),
+                def: vec![0; ts as usize],
+            })
+        };
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..5 {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+
+        let mut reader = Reader::open(file.path())?;
+        reader.read()?;
+        reader.read()?;
+        let position = reader.position();
+        drop(reader);
+
+        let mut reader = Reader::resume_from(file.path(), position)?;
+        assert_eq!(reader.position(), position);
+        assert_eq!(reader.records_remaining()?, Some(3));
+        for ts in 2..5 {
+            assert_eq!(reader.read()?, record(ts));
+        }
+        let end = reader.position();
+        assert_eq!(end, file.as_file().metadata()?.len());
+        assert_eq!(
+            Reader::resume_from(file.path(), end)?.records_remaining()?,
+            Some(0)
+        );
+
+        for offset in [0, position - 1, position + 1, end + 1] {
+            let err = Reader::resume_from(file.path(), offset).err().unwrap();
+            assert_matches!(
+                Error::downcast(&err),
+                Some(Error::InvalidOffset { offset: x }) if *x == offset
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_halves_of_file_concurrently() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let record = |ts| {
+            Record::C(DataC {
+                timestamp: Timestamp(ts),
+                baz: (0, 0),
+            })
+        };
+        let mut writer = Writer::open(file.path())?;
+        for ts in 0..10 {
+            writer.write(&record(ts))?;
+        }
+        writer.flush()?;
+
+        let mut first = Reader::open(file.path())?;
+        let start = first.position();
+        for _ in 0..5 {
+            first.read()?;
+        }
+        let mut second = first.clone_at(first.position())?;
+        assert_eq!(second.records_remaining()?, Some(5));
+        first.rewind()?;
+        assert_eq!(first.position(), start);
+
+        let read = |reader: &mut Reader, count| {
+            (0..count)
+                .map(|_| reader.read().map(|x| x.timestamp().0))
+                .collect::<std::io::Result<Vec<_>>>()
+        };
+        let (first, second) = std::thread::scope(|scope| {
+            let first = scope.spawn(|| read(&mut first, 5));
+            let second = scope.spawn(|| read(&mut second, 5));
+            (first.join().unwrap(), second.join().unwrap())
+        });
+        assert_eq!(first?, [0, 1, 2, 3, 4]);
+        assert_eq!(second?, [5, 6, 7, 8, 9]);
+
+        let err = Writer::open(file.path())?
+            .into_reader()?
+            .clone_at(start)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+        Ok(())
+    }
+
+    #[test]
+    fn varint_encoding_is_more_compact() -> std::io::Result<()> {
+        let fixint = tempfile::NamedTempFile::new()?;
+        let varint = tempfile::NamedTempFile::new()?;
+        let record = Record::C(DataC {
+            timestamp: Timestamp(1_700_000_000_000),
+            baz: (1, 2),
+        });
+
+        let mut writer = Writer::open(fixint.path())?;
+        writer.write(&record)?;
+        writer.flush()?;
+        let mut writer = Writer::open(varint.path())?.with_int_encoding(IntEncoding::Varint);
+        writer.write(&record)?;
+        writer.flush()?;
+
+        assert!(varint.as_file().metadata()?.len() < fixint.as_file().metadata()?.len());
+        let mut reader = Reader::open(varint.path())?.with_int_encoding(IntEncoding::Varint);
+        assert_eq!(reader.read()?, record);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_ndjson() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let records = [
+            Record::A(DataA {
+                timestamp: Timestamp(1),
+                foo: "foo\nbar".into(),
+            }),
+            Record::E(DataE {
+                timestamp: Timestamp(2),
+                def: vec![3, 4],
+            }),
+        ];
+
+        let mut writer = Writer::open(file.path())?.with_format(Format::Ndjson);
+        for record in &records {
+            writer.write(record)?;
+        }
+        writer.flush()?;
+
+        let written = std::fs::read(file.path())?;
+        assert_eq!(
+            std::str::from_utf8(&written[HEADER_LEN..]).expect("JSON is UTF-8"),
+            "{\"A\":{\"timestamp\":1,\"foo\":\"foo\\nbar\"}}\n\
+             {\"E\":{\"timestamp\":2,\"def\":[3,4]}}\n"
+        );
+
+        let mut reader = Reader::open(file.path())?.with_format(Format::Ndjson);
+        assert_eq!(reader.read()?, records[0]);
+        let second = reader.position();
+        assert_eq!(reader.read()?, records[1]);
+        assert_eq!(reader.records_remaining()?, Some(0));
+
+        // skipped by lines, even though the first one has an escaped newline in it
+        let mut clone = reader.clone_at(second)?;
+        assert_eq!(clone.read()?, records[1]);
+        assert_matches!(
+            Error::downcast(&reader.clone_at(second - 1).err().unwrap()),
+            Some(Error::InvalidOffset { .. })
+        );
+
+        // a line without the newline is cut off
+        file.as_file().set_len(written.len() as u64 - 1)?;
+        let mut reader = Reader::open(file.path())?.with_format(Format::Ndjson);
+        assert_eq!(reader.read()?, records[0]);
+        assert_matches!(reader.read_recoverable(), Ok(None));
 
         Ok(())
     }
@@ -0,0 +1,115 @@
+use crate::data::*;
+use crate::{output, BufferConfig, Producer, ReceiversTuple, UnsortedDataSinkLoop};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+/// Environment variable to seed [`rng`] with, e.g. to reproduce a failed run.
+const SEED_VAR: &str = "TSK1183_SEED";
+
+/// Random generator for tests, seeded from [`SEED_VAR`] if it is set, and randomly otherwise.
+///
+/// The seed is printed, so that it shows up in the output of a failed test. Since records with
+/// equal timestamps are dumped in the push order (see [`crate::Sequenced`]), the same seed
+/// produces exactly the same output.
+pub fn rng() -> StdRng {
+    let seed = match std::env::var(SEED_VAR) {
+        Ok(seed) => seed
+            .parse()
+            .unwrap_or_else(|_| panic!("{SEED_VAR} must be a u64, got {seed:?}")),
+        Err(_) => rand::random(),
+    };
+    println!("{SEED_VAR}={seed}");
+    StdRng::seed_from_u64(seed)
+}
+
+/// The smallest record, for tests where only the timestamp matters.
+pub fn record(ts: u128) -> Record {
+    record_of(RecordKind::D, ts)
+}
+
+/// A record of the kind with the timestamp, and the rest of the data empty.
+pub fn record_of(kind: RecordKind, ts: u128) -> Record {
+    let timestamp = Timestamp(ts);
+    match kind {
+        RecordKind::A => Record::A(DataA {
+            timestamp,
+            foo: "".into(),
+        }),
+        RecordKind::B => Record::B(DataB {
+            timestamp,
+            bar: true,
+        }),
+        RecordKind::C => Record::C(DataC {
+            timestamp,
+            baz: (0, 0),
+        }),
+        RecordKind::D => Record::D(DataD { timestamp, abc: () }),
+        RecordKind::E => Record::E(DataE {
+            timestamp,
+            def: vec![],
+        }),
+    }
+}
+
+/// Temporary directory of a test, along with the path of the output file in it (not created).
+///
+/// The directory is removed once the returned guard is dropped.
+pub fn temp_output() -> std::io::Result<(tempfile::TempDir, PathBuf)> {
+    let dir = tempfile::tempdir()?;
+    let output = dir.path().join("output");
+    Ok((dir, output))
+}
+
+/// Buffer config with at most `max_in_memory` records in memory, and the defaults otherwise.
+pub fn config(max_in_memory: usize) -> BufferConfig {
+    BufferConfig {
+        max_in_memory,
+        ..Default::default()
+    }
+}
+
+/// The five input channels of [`UnsortedDataSinkLoop`], with a producer into all of them.
+pub fn channels() -> (Producer, ReceiversTuple) {
+    let (tx_a, rx_a) = mpsc::channel();
+    let (tx_b, rx_b) = mpsc::channel();
+    let (tx_c, rx_c) = mpsc::channel();
+    let (tx_d, rx_d) = mpsc::channel();
+    let (tx_e, rx_e) = mpsc::channel();
+    let producer = Producer::new((tx_a, tx_b, tx_c, tx_d, tx_e));
+    (producer, (rx_a, rx_b, rx_c, rx_d, rx_e))
+}
+
+/// Send a record of each kind (see [`record_of`]) for each of the timestamps.
+pub fn send_each_kind(producer: &Producer, timestamps: impl IntoIterator<Item = u128>) {
+    for ts in timestamps {
+        for kind in RecordKind::ALL {
+            producer
+                .send(record_of(kind, ts))
+                .expect("the sink is listening");
+        }
+    }
+}
+
+/// Sink with the default config and none of the optional settings, to override with the
+/// struct update syntax.
+pub fn sink<'w, P>(
+    receivers: ReceiversTuple,
+    writer: &'w mut output::Writer,
+    notify_new_records: mpsc::Sender<crate::NewRecordsAvailable>,
+    buffer_dir: P,
+) -> UnsortedDataSinkLoop<'w, P> {
+    UnsortedDataSinkLoop {
+        receivers,
+        writer,
+        notify_new_records,
+        buffer_dir,
+        buffer_config: BufferConfig::default(),
+        control: None,
+        watermark: None,
+        normalizers: None,
+        lag_metrics: None,
+        tagged_writer: None,
+    }
+}